@@ -1,73 +1,484 @@
 #![warn(clippy::all)]
 
 mod state_handler;
+mod trust;
 mod tui;
 
-use anyhow::Result;
-use futures_util::{SinkExt, StreamExt};
+use anyhow::{anyhow, Result};
+use clap::Parser;
+use std::fmt;
+use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
-use tokio::{
-    io,
-    net::TcpStream,
-    sync::broadcast::{self},
-};
-use tokio_tungstenite::WebSocketStream;
+use tokio::sync::broadcast::{self};
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
 
-use crate::state_handler::{Action, ClientState, ConnectionStatus, StateHandler};
+use crate::state_handler::{
+    Action, BufferId, ClientState, ConnectionStatus, Identity, StateHandler,
+};
+use common::codec::{Codec, Frame};
+use common::connection::{self, Transport, TransportKind};
+use common::handshake::{Capability, Hello, HelloReply};
 use common::message::{Message, MessageType};
+use common::validation::name_key;
+use trust::Trust;
 use tui::{
     app_router::AppRouter,
     components::component::{Component, ComponentRender},
     Event, TextType, Tui,
 };
 
+#[derive(Parser, Debug)]
+struct ClientConfig {
+    // Encoding used on the wire: postcard or json
+    #[arg(short, long, default_value = "postcard")]
+    codec: Codec,
+
+    // PEM file with the authority signing the certificates of servers
+    // reached over TLS. Without one, a server's certificate is trusted
+    // the first time and has to stay the same afterwards
+    #[arg(long)]
+    ca: Option<PathBuf>,
+
+    // File remembering the certificates trusted on first use,
+    // ~/.config/chatclient/known_servers by default
+    #[arg(long)]
+    known_servers: Option<PathBuf>,
+
+    // Seconds between pings sent to the server
+    #[arg(long, default_value_t = 15)]
+    ping_interval: u64,
+
+    // Seconds without hearing from the server before the connection is
+    // considered lost. Matches the server's default timeout, as the
+    // session is best resumed once the server has let go of the old
+    // connection as well
+    #[arg(long, default_value_t = 90)]
+    ping_timeout: u64,
+}
+
+// How often the server is pinged, and how long it may stay silent
+#[derive(Clone, Copy)]
+struct Heartbeat {
+    interval: Duration,
+    timeout: Duration,
+}
+
+impl Heartbeat {
+    fn new(config: &ClientConfig) -> Result<Self> {
+        if config.ping_interval == 0 {
+            return Err(anyhow!("The ping interval has to be at least 1 second"));
+        }
+
+        if config.ping_timeout <= config.ping_interval {
+            return Err(anyhow!(
+                "The ping timeout ({0}s) has to be longer than the ping interval ({1}s)",
+                config.ping_timeout,
+                config.ping_interval
+            ));
+        }
+
+        Ok(Heartbeat {
+            interval: Duration::from_secs(config.ping_interval),
+            timeout: Duration::from_secs(config.ping_timeout),
+        })
+    }
+}
+
 #[derive(Clone)]
 enum Terminate {
     Exit,
 }
 
-pub async fn establish_connection(server: &str) -> Result<WebSocketStream<TcpStream>> {
-    let stream = TcpStream::connect(server).await?;
-    let (ws_stream, _) = tokio_tungstenite::client_async(format!("ws://{server}"), stream).await?;
-    Ok(ws_stream)
+// Optional protocol features this client implements
+const CLIENT_CAPABILITIES: &[Capability] = &[Capability::History, Capability::Resume];
+
+// How long to wait for the connection to the server to be set up
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+// How long to wait for the server to answer the handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// How long to wait for the server to answer a request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+// How long to wait for the server to take the last message
+// when leaving, before giving up on closing cleanly
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(2);
+
+// Number of older messages asked for by /history
+const HISTORY_PAGE: u32 = 20;
+
+// Delay before the first attempt at reconnecting, doubled after
+// every failed attempt up to the maximum
+const RECONNECT_BASE_DELAY: Duration = Duration::from_secs(1);
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(30);
+
+// The server turned the client away during the handshake, such as
+// for speaking another protocol version. Trying again can't succeed
+#[derive(Debug)]
+struct Rejected {
+    reason: String,
+}
+
+impl fmt::Display for Rejected {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Server rejected client: {}", self.reason)
+    }
+}
+
+impl std::error::Error for Rejected {}
+
+// Servers are addressed as host:port, optionally prefixed with the
+// transport to use (ws:// or tcp://, wss:// or tls:// over TLS).
+// WebSocket without TLS is the default. Also tells whether to use TLS
+pub fn parse_server_address(server: &str) -> (TransportKind, bool, &str) {
+    if let Some(addr) = server.strip_prefix("tcp://") {
+        (TransportKind::Tcp, false, addr)
+    } else if let Some(addr) = server.strip_prefix("tls://") {
+        (TransportKind::Tcp, true, addr)
+    } else if let Some(addr) = server.strip_prefix("ws://") {
+        (TransportKind::WebSocket, false, addr)
+    } else if let Some(addr) = server.strip_prefix("wss://") {
+        (TransportKind::WebSocket, true, addr)
+    } else {
+        (TransportKind::WebSocket, false, server)
+    }
+}
+
+// Also returns the fingerprint of the server's certificate
+// when it is trusted for the first time
+pub async fn establish_connection(
+    server: &str,
+    trust: &Trust,
+) -> Result<(Box<dyn Transport>, Option<String>)> {
+    let (kind, tls, addr) = parse_server_address(server);
+
+    if !tls {
+        let transport = tokio::time::timeout(CONNECT_TIMEOUT, connection::connect(addr, kind))
+            .await
+            .map_err(|_| anyhow!("Timed out connecting to {addr}"))??;
+
+        return Ok((transport, None));
+    }
+
+    let (transport, cert) = tokio::time::timeout(
+        CONNECT_TIMEOUT,
+        connection::connect_tls(addr, kind, trust.connector()),
+    )
+    .await
+    .map_err(|_| anyhow!("Timed out connecting to {addr}"))??;
+    let fingerprint = trust.check(addr, &cert)?;
+
+    Ok((transport, fingerprint))
+}
+
+// Exchanges protocol version and capabilities with the server,
+// returning the capabilities both sides support
+pub async fn handshake(transport: &mut dyn Transport, codec: Codec) -> Result<Vec<Capability>> {
+    let hello = Hello::new(CLIENT_CAPABILITIES);
+    transport.send_frame(Frame::encode(&hello, codec)?).await?;
+
+    let reply = match tokio::time::timeout(HANDSHAKE_TIMEOUT, transport.recv_frame()).await {
+        Ok(Some(Ok(frame))) => frame.decode::<HelloReply>()?,
+        Ok(_) => return Err(anyhow!("Connection closed during handshake")),
+        Err(_) => return Err(anyhow!("Server did not answer handshake")),
+    };
+
+    match reply {
+        HelloReply::Accepted { capabilities, .. } => Ok(capabilities
+            .iter()
+            .filter_map(|name| Capability::from_name(name))
+            .collect()),
+        HelloReply::Rejected { reason, .. } => Err(Rejected { reason }.into()),
+    }
 }
 
 pub async fn registering_on_server(
     server: &str,
+    trust: &Trust,
     state: Arc<Mutex<ClientState>>,
-    connection_handle: &mut Option<WebSocketStream<TcpStream>>,
-) -> Result<()> {
-    let mut ws_stream = establish_connection(server).await?;
+) -> Result<Box<dyn Transport>> {
+    let codec = state.lock().unwrap().codec;
+    let (mut transport, fingerprint) = establish_connection(server, trust).await?;
+    let capabilities = handshake(transport.as_mut(), codec).await?;
 
-    {
+    let resume_token = {
         let mut state = state.lock().unwrap();
+        if state.current_server != server {
+            state.reset_seen_messages();
+            state.reset_buffers();
+            state.resume_token = None;
+            state.joined_rooms.clear();
+        }
         state.current_server = server.to_string();
         state.connection_status = ConnectionStatus::Established;
+        state.reconnect_after = None;
 
         state.push_notification(TextType::Notification {
             text: String::from("[*] Successfully connected"),
         });
 
-        // Once connected, registration message is sent which
-        // provides username to server
+        if let Some(fingerprint) = fingerprint {
+            state.push_notification(TextType::Notification {
+                text: format!("[*] First connection to {server}, trusting its certificate"),
+            });
+            state.push_notification(TextType::Notification {
+                text: format!("[*] Fingerprint {fingerprint}"),
+            });
+        }
+
+        let capability_list = match capabilities.is_empty() {
+            true => String::from("none"),
+            false => capabilities
+                .iter()
+                .map(|capability| capability.name())
+                .collect::<Vec<&str>>()
+                .join(", "),
+        };
+
+        state.push_notification(TextType::Notification {
+            text: format!("[*] Negotiated capabilities: {capability_list}"),
+        });
+        state.capabilities = capabilities;
+
+        match state.capabilities.contains(&Capability::Resume) {
+            true => state.resume_token.take(),
+            false => None,
+        }
+    };
+
+    if let Some(token) = resume_token {
+        if resume_session(transport.as_mut(), codec, token, Arc::clone(&state)).await? {
+            return Ok(transport);
+        }
+    }
+
+    // Once connected, registration message is sent which provides
+    // username to server, followed by joining the rooms the client
+    // was in before losing the connection
+    let messages = {
+        let mut state = state.lock().unwrap();
+
         state.push_notification(TextType::Notification {
             text: String::from("[*] Registering user"),
         });
+
+        let mut messages = vec![state.registration_request()];
+
+        for room in state.joined_rooms.clone() {
+            messages.push(state.request(
+                MessageType::Join { room: room.clone() },
+                format!("join {room}"),
+            ));
+        }
+
+        messages
+    };
+
+    for message in messages {
+        let _ = transport.send(&message, codec).await;
     }
 
-    let (session_id, username) = {
-        let guard = state.lock().unwrap();
-        (guard.session_id.clone(), guard.username.clone())
+    Ok(transport)
+}
+
+// Asks for the session held by the server since the connection was
+// lost. Returns false if it has expired, in which case the client
+// has to register again
+async fn resume_session(
+    transport: &mut dyn Transport,
+    codec: Codec,
+    token: String,
+    state: Arc<Mutex<ClientState>>,
+) -> Result<bool> {
+    let message = Message::build(MessageType::Resume { token }, 0);
+    transport.send(&message, codec).await?;
+
+    // The message of the day may come before the answer
+    let reply = loop {
+        let reply = match tokio::time::timeout(HANDSHAKE_TIMEOUT, transport.recv()).await {
+            Ok(Some(Ok(reply))) => reply,
+            Ok(_) => return Err(anyhow!("Connection closed while resuming session")),
+            Err(_) => return Err(anyhow!("Server did not answer resume request")),
+        };
+
+        match reply.message_type {
+            MessageType::Motd { .. } => state.lock().unwrap().handle_message(reply)?,
+            _ => break reply,
+        }
+    };
+
+    // Members of the rooms may have changed while the connection was
+    // down, so the lists of those still joined are asked for again
+    let messages = {
+        let mut state = state.lock().unwrap();
+
+        match reply.message_type {
+            MessageType::Resumed { .. } => {
+                state.handle_message(reply)?;
+
+                state
+                    .joined_rooms
+                    .clone()
+                    .into_iter()
+                    .map(|room| {
+                        state.request(
+                            MessageType::Members { room: room.clone() },
+                            format!("members {room}"),
+                        )
+                    })
+                    .collect::<Vec<Message>>()
+            }
+            _ => {
+                state.push_notification(TextType::Notification {
+                    text: String::from("[*] Previous session has expired"),
+                });
+
+                return Ok(false);
+            }
+        }
     };
 
-    if let Ok(message) = Message::build(MessageType::Register, session_id, Some(username), None) {
-        let _ = ws_stream.send(message.to_bytes().into()).await;
+    for message in messages {
+        transport.send(&message, codec).await?;
     }
 
-    let _ = connection_handle.insert(ws_stream);
+    Ok(true)
+}
+
+// Tells the server the session is over before closing the connection,
+// so that it doesn't keep the session around for resuming. A server
+// which doesn't answer in time is simply left behind
+async fn quit_server(transport: &mut dyn Transport, codec: Codec) {
+    let message = Message::build(MessageType::Quit, 0);
 
-    Ok(())
+    let _ = tokio::time::timeout(CLOSE_TIMEOUT, async {
+        let _ = transport.send(&message, codec).await;
+        let _ = transport.close().await;
+    })
+    .await;
+}
+
+// Delay before the given attempt at reconnecting. Half of it is
+// random so that clients which lost their connection at the same
+// time don't all come back at once
+fn reconnect_delay(attempt: u32) -> Duration {
+    let backoff = RECONNECT_BASE_DELAY
+        .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+        .min(RECONNECT_MAX_DELAY);
+
+    backoff / 2 + (backoff / 2).mul_f64(rand::random::<f64>())
+}
+
+// Starts counting reconnection attempts, returning when the first is due
+fn schedule_reconnect(state: &mut ClientState) -> Instant {
+    state.connection_status = ConnectionStatus::Reconnecting { attempt: 1 };
+
+    // Servers shutting down tell how long they expect to be away
+    let delay = match state.reconnect_after.take() {
+        Some(wait) => wait + reconnect_delay(1),
+        None => reconnect_delay(1),
+    };
+
+    Instant::now() + delay
+}
+
+// Attempt at connecting to a server, run in the background so that
+// input is still handled while waiting on the server. Dropping it
+// gives up on the attempt
+struct Attempt {
+    task: JoinHandle<Result<Box<dyn Transport>>>,
+    // Failed attempts at reconnecting are retried, unlike /connect
+    reconnecting: bool,
+}
+
+impl Attempt {
+    fn start(
+        server: String,
+        trust: &Trust,
+        state: Arc<Mutex<ClientState>>,
+        reconnecting: bool,
+    ) -> Self {
+        let trust = trust.clone();
+
+        Attempt {
+            task: tokio::spawn(async move { registering_on_server(&server, &trust, state).await }),
+            reconnecting,
+        }
+    }
+}
+
+impl Drop for Attempt {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+// Starts an attempt at reconnecting to the last server
+fn reconnect(trust: &Trust, state: Arc<Mutex<ClientState>>) -> Attempt {
+    let server = state.lock().unwrap().current_server.clone();
+
+    Attempt::start(server, trust, state, true)
+}
+
+// Reports a failed attempt at reconnecting. Returns when to try
+// again if another attempt may succeed
+fn reconnect_failed(state: &mut ClientState, e: anyhow::Error) -> Option<Instant> {
+    let attempt = match state.connection_status {
+        ConnectionStatus::Reconnecting { attempt } => attempt,
+        _ => 1,
+    };
+
+    if e.is::<Rejected>() {
+        state.push_notification(TextType::Error {
+            text: format!("[-] {e}"),
+        });
+        state.push_notification(TextType::Error {
+            text: String::from(
+                "[-] Stopped reconnecting, this client can't be used with the server",
+            ),
+        });
+        state.connection_status = ConnectionStatus::Unitiliazed;
+
+        return None;
+    }
+
+    state.push_notification(TextType::Error {
+        text: format!("[-] Reconnect attempt {attempt} failed: {e}"),
+    });
+    state.connection_status = ConnectionStatus::Reconnecting {
+        attempt: attempt + 1,
+    };
+
+    Some(Instant::now() + reconnect_delay(attempt + 1))
+}
+
+// Hands a message from the server to the state, returning false once
+// a fatal error left the session unusable. A new one is set up the same
+// way as after losing the connection, unless the session never got
+// registered: registering again would only be refused again
+fn handle_server_message(
+    state: &mut ClientState,
+    message: Message,
+    reconnect_at: &mut Option<Instant>,
+) -> bool {
+    let registered = state.registered;
+
+    if state.handle_message(message).is_ok() {
+        return true;
+    }
+
+    if registered {
+        *reconnect_at = Some(schedule_reconnect(state));
+    } else {
+        state.push_notification(TextType::Error {
+            text: String::from("[-] Not reconnecting, set a name or log in and use /connect"),
+        });
+    }
+
+    false
 }
 
 pub fn display_help(state: &mut ClientState) {
@@ -81,10 +492,23 @@ pub fn display_help(state: &mut ClientState) {
         text: String::from("    /changename - Change name used in server"),
     });
     state.push_notification(TextType::Listing {
-        text: String::from("    /connect - Connect to server. ex. 127.0.0.1:6777"),
+        text: String::from("    /login {name} {password} - Log in to an account"),
     });
     state.push_notification(TextType::Listing {
-        text: String::from("    /list {opt} - List out info. Options: users, rooms, allrooms"),
+        text: String::from("    /register {name} {password} - Create an account and log in"),
+    });
+    state.push_notification(TextType::Listing {
+        text: String::from(
+            "    /connect - Connect to server. ex. 127.0.0.1:6777, tcp://127.0.0.1:6777 or wss://chat.example.com:6777",
+        ),
+    });
+    state.push_notification(TextType::Listing {
+        text: String::from(
+            "    /list {opt} - List out info. Options: users, rooms, allrooms, members {room}",
+        ),
+    });
+    state.push_notification(TextType::Listing {
+        text: String::from("    /names [room] - List members of room, the current one by default"),
     });
     state.push_notification(TextType::Listing {
         text: String::from("    /join {room} - Join room in server"),
@@ -95,24 +519,53 @@ pub fn display_help(state: &mut ClientState) {
     state.push_notification(TextType::Listing {
         text: String::from("    /create {room} - Create new room in server"),
     });
+    state.push_notification(TextType::Listing {
+        text: String::from("    /history {room} - Show older messages of joined room"),
+    });
     state.push_notification(TextType::Listing {
         text: String::from("    /sendto {room} {message}  - Send message to joined room"),
     });
     state.push_notification(TextType::Listing {
         text: String::from("    /privmsg {user} {message} - Send message directly to user"),
     });
+    state.push_notification(TextType::Listing {
+        text: String::from("    /query {user} - Open a conversation with user"),
+    });
     state.push_notification(TextType::Listing {
         text: String::from("    /disconnect - Disconnect from server"),
     });
+    state.push_notification(TextType::Listing {
+        text: String::from("    /reconnect - Reconnect to the last server"),
+    });
     state.push_notification(TextType::Listing {
         text: String::from("    /quit - Close chat client"),
     });
+    state.push_notification(TextType::Notification {
+        text: String::from(
+            "Text without a command is sent to the room or user of the current buffer",
+        ),
+    });
+    state.push_notification(TextType::Notification {
+        text: String::from("F2 shows or hides the sidebar with rooms and members"),
+    });
+    state.push_notification(TextType::Notification {
+        text: String::from(
+            "PageUp/PageDown or the mouse wheel scroll, Home/End jump to either end",
+        ),
+    });
+    state.push_notification(TextType::Notification {
+        text: String::from(
+            "Ctrl+N/Ctrl+P switch to the next/previous buffer, Alt+1-9 to a given one",
+        ),
+    });
 }
 
 async fn run(
     shutdown_tx: broadcast::Sender<Terminate>,
     shutdown_rx: &mut broadcast::Receiver<Terminate>,
     master_state: Arc<Mutex<ClientState>>,
+    trust: Trust,
+    heartbeat: Heartbeat,
 ) -> Result<()> {
     // Initialize required strucutres:
     // * Channel for passing state between TUI and state handler
@@ -133,12 +586,18 @@ async fn run(
             state_handler.updated();
 
             let mut ticker = tokio::time::interval(Duration::from_millis(250));
-            let mut connection_handle: Option<WebSocketStream<TcpStream>> = None;
+            let mut ping = tokio::time::interval(heartbeat.interval);
+            let codec = handler_state.lock().unwrap().codec;
+            let mut connection_handle: Option<Box<dyn Transport>> = None;
+            // When to next try getting a lost connection back, if at all
+            let mut reconnect_at: Option<Instant> = None;
+            let mut attempt: Option<Attempt> = None;
 
             loop {
                 if exit {
                     let _ = shutdown_tx.send(Terminate::Exit);
                     connection_handle = None;
+                    attempt = None;
                 }
 
                 if let Some(connection) = connection_handle.as_mut() {
@@ -147,20 +606,41 @@ async fn run(
                     // * Action channel from TUI
                     // * Shutdown channel
                     tokio::select! {
-                        _tick = ticker.tick() => {},
-                        message = connection.next() => {
+                        _tick = ticker.tick() => {
+                            let mut handler_state = handler_state.lock().unwrap();
+
+                            if handler_state.expire_requests(REQUEST_TIMEOUT) {
+                                state_handler.updated();
+                            }
+                        },
+                        _ = ping.tick() => {
+                            // Latency is the round trip of the previous ping
+                            if connection.idle_time() < heartbeat.timeout {
+                                let _ = tokio::time::timeout(heartbeat.interval, connection.send_ping()).await;
+
+                                handler_state.lock().unwrap().latency = connection.round_trip();
+                            } else {
+                                let mut handler_state = handler_state.lock().unwrap();
+
+                                handler_state.push_notification(TextType::Error {
+                                    text: String::from("[-] Server stopped responding"),
+                                });
+
+                                handler_state.terminate_connection();
+                                connection_handle = None;
+                                reconnect_at = Some(schedule_reconnect(&mut handler_state));
+                            }
+
+                            state_handler.updated();
+                        },
+                        message = connection.recv() => {
                                 match message {
-                                    Some(message) if message.is_ok() => {
+                                    Some(Ok(message)) => {
                                         let mut handler_state = handler_state.lock().unwrap();
 
-                                        if let Ok(message) = message {
-                                            // Certain errors need the connection to be closed
-                                            let message = Message::from_bytes(message.into_data().into()).unwrap();
-                                            let _ = handler_state.handle_message(message).map_err(|_| {
-                                                connection_handle = None;
-                                            });
+                                        if !handle_server_message(&mut handler_state, message, &mut reconnect_at) {
+                                            connection_handle = None;
                                         }
-
                                     },
                                     None => {
                                         let mut handler_state = handler_state.lock().unwrap();
@@ -171,6 +651,7 @@ async fn run(
 
                                         handler_state.terminate_connection();
                                         connection_handle = None;
+                                        reconnect_at = Some(schedule_reconnect(&mut handler_state));
                                     },
                                     _ => {},
                                 }
@@ -184,143 +665,265 @@ async fn run(
 
                                     display_help(&mut handler_state);
                                 },
+                                Some(Action::NextBuffer) => {
+                                    handler_state.lock().unwrap().cycle_buffer(1);
+                                },
+                                Some(Action::PrevBuffer) => {
+                                    handler_state.lock().unwrap().cycle_buffer(-1);
+                                },
+                                Some(Action::SelectBuffer { index }) => {
+                                    handler_state.lock().unwrap().select_buffer(index);
+                                },
+                                Some(Action::ToggleSidebar) => {
+                                    let mut handler_state = handler_state.lock().unwrap();
+
+                                    handler_state.show_sidebar = !handler_state.show_sidebar;
+                                },
                                 Some(Action::SetName { name }) => {
-                                    let session_id = {
-                                            let guard = handler_state.lock().unwrap();
-                                            guard.session_id
-                                    };
+                                    // Sessions left unregistered after a failed
+                                    // registration retry it with the new name
+                                    let message = {
+                                        let mut handler_state = handler_state.lock().unwrap();
 
-                                    if let Ok(message) = Message::build(
-                                            MessageType::ChangeName,
-                                            session_id,
-                                            Some(name),
-                                            None
-                                    ) {
-                                        let _ = connection.send(message.to_bytes().into()).await;
-                                    }
+                                        if handler_state.registered {
+                                            handler_state.push_notification(TextType::Notification {
+                                                    text: String::from("[*] Attemping name change")
+                                            });
 
+                                            handler_state.request(
+                                                MessageType::ChangeName { username: name.clone() },
+                                                format!("changename {name}"),
+                                            )
+                                        } else {
+                                            handler_state.username = name.clone();
+                                            handler_state.identity = Identity::Guest;
+                                            handler_state.push_notification(TextType::Notification {
+                                                    text: String::from("[*] Registering user")
+                                            });
 
-                                    let mut handler_state = handler_state.lock().unwrap();
+                                            handler_state.registration_request()
+                                        }
+                                    };
 
-                                    handler_state.push_notification(TextType::Notification {
-                                            text: String::from("[*] Attemping name change")
-                                    });
+                                    let _ = connection.send(&message, codec).await;
+                                },
+                                Some(Action::Login { name, password }) => {
+                                    let message = {
+                                        let mut handler_state = handler_state.lock().unwrap();
+
+                                        handler_state.username = name;
+                                        handler_state.identity = Identity::Account { password, create: false };
+                                        handler_state.push_notification(TextType::Notification {
+                                                text: String::from("[*] Logging in")
+                                        });
 
+                                        handler_state.registration_request()
+                                    };
+
+                                    let _ = connection.send(&message, codec).await;
                                 },
-                                Some(Action::SendTo { room, message }) => {
-                                    let session_id = {
-                                            let guard = handler_state.lock().unwrap();
-                                            guard.session_id
+                                Some(Action::CreateAccount { name, password }) => {
+                                    let message = {
+                                        let mut handler_state = handler_state.lock().unwrap();
+
+                                        handler_state.username = name;
+                                        handler_state.identity = Identity::Account { password, create: true };
+                                        handler_state.push_notification(TextType::Notification {
+                                                text: String::from("[*] Creating account")
+                                        });
+
+                                        handler_state.registration_request()
                                     };
 
-                                    if let Ok(message) = Message::build(
-                                            MessageType::SendTo,
-                                            session_id,
-                                            Some(room),
-                                            Some(message),
-                                        ) {
-                                            let _ = connection.send(message.to_bytes().into()).await;
-                                        }
+                                    let _ = connection.send(&message, codec).await;
+                                },
+                                Some(Action::SendTo { room, message }) => {
+                                    let message = handler_state.lock().unwrap().request(
+                                            MessageType::SendTo { room: room.clone(), text: message },
+                                            format!("sendto {room}"),
+                                        );
+                                    let _ = connection.send(&message, codec).await;
 
                                 },
                                 Some(Action::PrivMsg{ user, message }) => {
-                                    let (session_id, username) = {
+                                    let username = {
                                             let guard = handler_state.lock().unwrap();
-                                            (guard.session_id, guard.username.clone())
+                                            guard.username.clone()
                                     };
 
-                                    if user == username {
+                                    if name_key(&user) == name_key(&username) {
                                         let mut handler_state = handler_state.lock().unwrap();
                                         handler_state.push_notification(TextType::Error {
                                                 text: String::from("[-] Cannot send message to yourself"),
                                         });
                                     }
 
-                                    else if let Ok(message) = Message::build(
-                                                MessageType::PrivMsg,
-                                                session_id,
-                                                Some(user),
-                                                Some(message),
-                                            ) {
-
-                                            let _ = connection.send(message.to_bytes().into()).await;
+                                    else {
+                                        let message = handler_state.lock().unwrap().request(
+                                                MessageType::PrivMsg { to: user.clone(), text: message },
+                                                format!("privmsg {user}"),
+                                            );
+                                        let _ = connection.send(&message, codec).await;
                                     }
                                 },
-                                Some(Action::Join { room }) => {
-                                    let session_id = {
-                                        let guard = handler_state.lock().unwrap();
-                                        guard.session_id
-                                    };
+                                Some(Action::Say { text }) => {
+                                    let message = {
+                                        let mut handler_state = handler_state.lock().unwrap();
+
+                                        match handler_state.active_buffer.clone() {
+                                            BufferId::Room(room) => Some(handler_state.request(
+                                                MessageType::SendTo { room: room.clone(), text },
+                                                format!("sendto {room}"),
+                                            )),
+                                            BufferId::Private(user) => Some(handler_state.request(
+                                                MessageType::PrivMsg { to: user.clone(), text },
+                                                format!("privmsg {user}"),
+                                            )),
+                                            BufferId::Server => {
+                                                handler_state.push_notification(TextType::Error {
+                                                    text: String::from("[-] No room or user to send to, use /join or /query first"),
+                                                });
 
-                                    if let Ok(message) = Message::build(
-                                            MessageType::Join,
-                                            session_id,
-                                            Some(room),
-                                            None,
-                                        ) {
-                                            let _ = connection.send(message.to_bytes().into()).await;
+                                                None
+                                            }
                                         }
+                                    };
 
+                                    if let Some(message) = message {
+                                        let _ = connection.send(&message, codec).await;
+                                    }
                                 },
-                                Some(Action::Leave { room }) => {
-                                    let session_id = {
-                                        let guard = handler_state.lock().unwrap();
-                                        guard.session_id
-                                    };
+                                Some(Action::Query { user }) => {
+                                    let mut handler_state = handler_state.lock().unwrap();
 
-                                    if let Ok(message) = Message::build(
-                                            MessageType::Leave,
-                                            session_id,
-                                            Some(room),
-                                            None,
-                                        ) {
-                                            let _ = connection.send(message.to_bytes().into()).await;
-                                        }
+                                    if name_key(&user) == name_key(&handler_state.username) {
+                                        handler_state.push_notification(TextType::Error {
+                                                text: String::from("[-] Cannot send message to yourself"),
+                                        });
+                                    } else {
+                                        handler_state.focus_buffer(BufferId::Private(user));
+                                    }
+                                },
+                                Some(Action::Join { room }) => {
+                                    let message = handler_state.lock().unwrap().request(
+                                            MessageType::Join { room: room.clone() },
+                                            format!("join {room}"),
+                                        );
+                                    let _ = connection.send(&message, codec).await;
+
+                                },
+                                Some(Action::Leave { room }) => {
+                                    let message = handler_state.lock().unwrap().request(
+                                            MessageType::Leave { room: room.clone() },
+                                            format!("leave {room}"),
+                                        );
+                                    let _ = connection.send(&message, codec).await;
 
                                 },
                                 Some(Action::List { opt }) => {
-                                    let session_id = {
-                                        let guard = handler_state.lock().unwrap();
-                                        guard.session_id
-                                    };
+                                    let message = handler_state.lock().unwrap().request(
+                                            MessageType::List(opt),
+                                            String::from("list"),
+                                        );
+                                    let _ = connection.send(&message, codec).await;
+
+                                },
+                                Some(Action::Names { room }) => {
+                                    let message = {
+                                        let mut handler_state = handler_state.lock().unwrap();
+
+                                        let room = room.or(match &handler_state.active_buffer {
+                                            BufferId::Room(room) => Some(room.clone()),
+                                            _ => None,
+                                        });
+
+                                        match room {
+                                            Some(room) => Some(handler_state.request(
+                                                MessageType::Members { room: room.clone() },
+                                                format!("names {room}"),
+                                            )),
+                                            None => {
+                                                handler_state.push_notification(TextType::Error {
+                                                    text: String::from("[-] No room to list, use /names {room}"),
+                                                });
 
-                                    if let Ok(message) = Message::build(
-                                            MessageType::List,
-                                            session_id,
-                                            Some(opt),
-                                            None,
-                                        ) {
-                                            let _ = connection.send(message.to_bytes().into()).await;
+                                                None
+                                            }
                                         }
+                                    };
 
+                                    if let Some(message) = message {
+                                        let _ = connection.send(&message, codec).await;
+                                    }
                                 },
                                 Some(Action::Create { room }) => {
-                                    let session_id = {
-                                        let guard = handler_state.lock().unwrap();
-                                        guard.session_id
-                                    };
+                                    let message = handler_state.lock().unwrap().request(
+                                            MessageType::Create { room: room.clone() },
+                                            format!("create {room}"),
+                                        );
+                                    let _ = connection.send(&message, codec).await;
+
+                                },
+                                Some(Action::History { room }) => {
+                                    let message = {
+                                        let mut handler_state = handler_state.lock().unwrap();
+
+                                        if handler_state.capabilities.contains(&Capability::History) {
+                                            // Page back from the oldest message already shown
+                                            let before = handler_state.oldest_message(&room);
+
+                                            Some(handler_state.request(
+                                                MessageType::FetchHistory { room: room.clone(), before, limit: HISTORY_PAGE },
+                                                format!("history {room}"),
+                                            ))
+                                        } else {
+                                            handler_state.push_notification(TextType::Error {
+                                                text: String::from("[-] Server does not keep history"),
+                                            });
 
-                                    if let Ok(message) = Message::build(
-                                            MessageType::Create,
-                                            session_id,
-                                            Some(room),
-                                            None,
-                                        ) {
-                                            let _ = connection.send(message.to_bytes().into()).await;
+                                            None
                                         }
+                                    };
 
+                                    if let Some(message) = message {
+                                        let _ = connection.send(&message, codec).await;
+                                    }
                                 },
                                 Some(Action::Disconnect) => {
-                                    let mut handler_state = handler_state.lock().unwrap();
+                                    {
+                                        let mut handler_state = handler_state.lock().unwrap();
 
-                                    handler_state.push_notification(TextType::Notification {
-                                            text: String::from("[-] Closing connection to server"),
-                                    });
+                                        handler_state.push_notification(TextType::Notification {
+                                                text: String::from("[-] Closing connection to server"),
+                                        });
+
+                                        // Leaving on purpose ends the session, which a
+                                        // later /connect would otherwise pick back up
+                                        handler_state.terminate_connection();
+                                        handler_state.resume_token = None;
+                                    }
 
-                                    handler_state.terminate_connection();
+                                    quit_server(connection.as_mut(), codec).await;
                                     connection_handle = None;
                                 },
+                                Some(Action::Reconnect) => {
+                                    {
+                                        let mut handler_state = handler_state.lock().unwrap();
+
+                                        handler_state.push_notification(TextType::Notification {
+                                                text: String::from("[*] Reconnecting to server"),
+                                        });
+
+                                        handler_state.terminate_connection();
+                                        handler_state.connection_status = ConnectionStatus::Reconnecting { attempt: 1 };
+                                    }
+
+                                    connection_handle = None;
+                                    attempt = Some(reconnect(&trust, Arc::clone(&handler_state)));
+                                },
                                 Some(Action::Quit) => {
+                                    quit_server(connection.as_mut(), codec).await;
+
                                     let mut handler_state = handler_state.lock().unwrap();
                                     handler_state.exit();
                                     exit = true;
@@ -342,11 +945,39 @@ async fn run(
                         }
                     }
                 } else {
-                    // Two sources of events:
+                    // Three sources of events:
+                    // * Attempts at connecting
                     // * Action channel from TUI
                     // * Shutdown channel
                     tokio::select! {
                         _tick = ticker.tick() => {},
+                        _ = tokio::time::sleep_until(reconnect_at.unwrap_or_else(Instant::now)), if reconnect_at.is_some() => {
+                            reconnect_at = None;
+                            attempt = Some(reconnect(&trust, Arc::clone(&handler_state)));
+                        },
+                        joined = async { (&mut attempt.as_mut().unwrap().task).await }, if attempt.is_some() => {
+                            let reconnecting = attempt.take().is_some_and(|attempt| attempt.reconnecting);
+
+                            match joined {
+                                Ok(Ok(transport)) => {
+                                    connection_handle = Some(transport);
+                                },
+                                Ok(Err(e)) if reconnecting => {
+                                    reconnect_at = reconnect_failed(&mut handler_state.lock().unwrap(), e);
+                                },
+                                Ok(Err(e)) => {
+                                    let mut handler_state = handler_state.lock().unwrap();
+
+                                    handler_state.push_notification(TextType::Error {
+                                        text: format!("[-] Failed to register on server: {e}"),
+                                    });
+                                },
+                                // Given up on, nothing is left to do
+                                Err(_) => {},
+                            }
+
+                            state_handler.updated();
+                        },
                         action = action_rx.recv() => {
                             match action {
                                 Some(Action::Help) => {
@@ -354,15 +985,52 @@ async fn run(
 
                                     display_help(&mut handler_state);
                                 },
+                                Some(Action::NextBuffer) => {
+                                    handler_state.lock().unwrap().cycle_buffer(1);
+                                },
+                                Some(Action::PrevBuffer) => {
+                                    handler_state.lock().unwrap().cycle_buffer(-1);
+                                },
+                                Some(Action::SelectBuffer { index }) => {
+                                    handler_state.lock().unwrap().select_buffer(index);
+                                },
+                                Some(Action::ToggleSidebar) => {
+                                    let mut handler_state = handler_state.lock().unwrap();
+
+                                    handler_state.show_sidebar = !handler_state.show_sidebar;
+                                },
                                 Some(Action::SetName { name }) => {
                                     let mut handler_state = handler_state.lock().unwrap();
 
+                                    // A new identity can't take over the old session
                                     handler_state.username = name.clone();
+                                    handler_state.identity = Identity::Guest;
+                                    handler_state.resume_token = None;
                                     handler_state.push_notification(TextType::Notification {
                                         text: format!("[*] Name set to [{name}]"),
                                     });
 
                                 },
+                                Some(Action::Login { name, password }) => {
+                                    let mut handler_state = handler_state.lock().unwrap();
+
+                                    handler_state.username = name.clone();
+                                    handler_state.identity = Identity::Account { password, create: false };
+                                    handler_state.resume_token = None;
+                                    handler_state.push_notification(TextType::Notification {
+                                        text: format!("[*] Will log in as [{name}] when connecting"),
+                                    });
+                                },
+                                Some(Action::CreateAccount { name, password }) => {
+                                    let mut handler_state = handler_state.lock().unwrap();
+
+                                    handler_state.username = name.clone();
+                                    handler_state.identity = Identity::Account { password, create: true };
+                                    handler_state.resume_token = None;
+                                    handler_state.push_notification(TextType::Notification {
+                                        text: format!("[*] Will create account [{name}] when connecting"),
+                                    });
+                                },
                                 Some(Action::Connect { addr }) => {
                                     let username = {
                                         let guard = handler_state.lock().unwrap();
@@ -380,19 +1048,55 @@ async fn run(
                                     }
 
                                     else {
-                                        match registering_on_server(&addr, Arc::clone(&handler_state), &mut connection_handle).await {
-                                            Ok(()) => {},
-                                            Err(e) => {
-                                                let mut handler_state = handler_state.lock().unwrap();
+                                        {
+                                            let mut handler_state = handler_state.lock().unwrap();
 
-                                                handler_state.push_notification(TextType::Error {
-                                                    text: format!("[-] Failed to register on server: {e}"),
-                                                });
-                                            },
+                                            handler_state.push_notification(TextType::Notification {
+                                                text: format!("[*] Connecting to {addr}"),
+                                            });
+                                            handler_state.connection_status = ConnectionStatus::Unitiliazed;
                                         }
+
+                                        reconnect_at = None;
+                                        attempt = Some(Attempt::start(addr, &trust, Arc::clone(&handler_state), false));
                                     }
 
                                 },
+                                Some(Action::Disconnect) if reconnect_at.is_some() || attempt.is_some() => {
+                                    let mut handler_state = handler_state.lock().unwrap();
+
+                                    let text = match &attempt {
+                                        Some(attempt) if !attempt.reconnecting => "[-] Stopped connecting to server",
+                                        _ => "[-] Stopped reconnecting to server",
+                                    };
+                                    handler_state.push_notification(TextType::Notification {
+                                        text: String::from(text),
+                                    });
+
+                                    handler_state.connection_status = ConnectionStatus::Unitiliazed;
+                                    reconnect_at = None;
+                                    attempt = None;
+                                },
+                                Some(Action::Reconnect) => {
+                                    let has_server = {
+                                        let mut handler_state = handler_state.lock().unwrap();
+
+                                        if handler_state.current_server.is_empty() {
+                                            handler_state.push_notification(TextType::Error {
+                                                text: String::from("[-] No server to reconnect to"),
+                                            });
+                                        } else if !matches!(handler_state.connection_status, ConnectionStatus::Reconnecting { .. }) {
+                                            handler_state.connection_status = ConnectionStatus::Reconnecting { attempt: 1 };
+                                        }
+
+                                        !handler_state.current_server.is_empty()
+                                    };
+
+                                    if has_server {
+                                        reconnect_at = None;
+                                        attempt = Some(reconnect(&trust, Arc::clone(&handler_state)));
+                                    }
+                                },
                                 Some(Action::Quit) => {
                                     let mut handler_state = handler_state.lock().unwrap();
                                     exit = true;
@@ -450,6 +1154,9 @@ async fn run(
                             Ok(Event::Key(key)) => {
                                 app_router.handle_key_event(key);
                             },
+                            Ok(Event::Mouse(mouse)) => {
+                                app_router.handle_mouse_event(mouse);
+                            },
                             Ok(Event::Tick) => {},
                             Ok(Event::Error) => {},
                             _ => {},
@@ -488,7 +1195,13 @@ async fn main() -> Result<()> {
     let (shutdown_tx, mut shutdown_rx) = broadcast::channel::<Terminate>(1);
     let mut shutdown_main = shutdown_rx.resubscribe();
 
-    let master_state = Arc::new(Mutex::new(ClientState::default()));
+    let config = ClientConfig::parse();
+    let heartbeat = Heartbeat::new(&config)?;
+    let trust = Trust::new(config.ca.as_deref(), config.known_servers)?;
+
+    let mut client_state = ClientState::default();
+    client_state.codec = config.codec;
+    let master_state = Arc::new(Mutex::new(client_state));
 
     tokio::spawn({
         let master_state_clone = Arc::clone(&master_state);
@@ -498,6 +1211,8 @@ async fn main() -> Result<()> {
                 shutdown_tx,
                 &mut shutdown_rx,
                 Arc::clone(&master_state_clone),
+                trust,
+                heartbeat,
             )
             .await;
         }
@@ -512,3 +1227,102 @@ async fn main() -> Result<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::error::ErrorCode;
+
+    fn failed(command: &str, code: ErrorCode) -> Message {
+        Message::build(
+            MessageType::Failed {
+                command: command.to_string(),
+                code,
+            },
+            0,
+        )
+    }
+
+    #[test]
+    fn register_timeout_does_not_reconnect() {
+        let mut state = ClientState::default();
+        state.username = String::from("alice");
+        state.identity = Identity::Account {
+            password: String::from("secret"),
+            create: true,
+        };
+        state.resume_token = Some(String::from("token"));
+        state.connection_status = ConnectionStatus::Established;
+        let mut reconnect_at = None;
+
+        // The name was refused, then the server gave up waiting
+        assert!(handle_server_message(
+            &mut state,
+            failed("createaccount", ErrorCode::NameTaken),
+            &mut reconnect_at
+        ));
+        assert!(!handle_server_message(
+            &mut state,
+            failed("register", ErrorCode::RegisterTimeout),
+            &mut reconnect_at
+        ));
+
+        assert!(reconnect_at.is_none());
+        assert!(state.resume_token.is_none());
+        assert!(matches!(state.identity, Identity::Guest));
+        assert!(matches!(
+            state.connection_status,
+            ConnectionStatus::Unitiliazed
+        ));
+    }
+
+    #[test]
+    fn internal_error_during_registration_does_not_reconnect() {
+        let mut state = ClientState::default();
+        let mut reconnect_at = None;
+
+        assert!(!handle_server_message(
+            &mut state,
+            failed("createaccount", ErrorCode::Internal),
+            &mut reconnect_at
+        ));
+        assert!(reconnect_at.is_none());
+    }
+
+    #[test]
+    fn fatal_error_of_registered_session_reconnects() {
+        let mut state = ClientState::default();
+        state.registered = true;
+        let mut reconnect_at = None;
+
+        assert!(!handle_server_message(
+            &mut state,
+            failed("sendto main", ErrorCode::Internal),
+            &mut reconnect_at
+        ));
+        assert!(reconnect_at.is_some());
+        assert!(matches!(
+            state.connection_status,
+            ConnectionStatus::Reconnecting { attempt: 1 }
+        ));
+    }
+
+    #[test]
+    fn reconnect_delay_grows_up_to_the_limit() {
+        let backoffs = [(1, 1), (2, 2), (3, 4), (4, 8), (5, 16), (6, 30), (20, 30)];
+
+        for (attempt, backoff) in backoffs {
+            let backoff = Duration::from_secs(backoff);
+
+            // Half of the delay is random
+            for _ in 0..100 {
+                let delay = reconnect_delay(attempt);
+
+                assert!(delay >= backoff / 2, "attempt {attempt}: {delay:?}");
+                assert!(delay <= backoff, "attempt {attempt}: {delay:?}");
+            }
+        }
+
+        assert!(reconnect_delay(u32::MAX) <= RECONNECT_MAX_DELAY);
+    }
+}
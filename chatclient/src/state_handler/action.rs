@@ -1,19 +1,47 @@
+use common::message::ListOption;
+
+#[derive(Debug, PartialEq)]
 pub enum Action {
     Help,
     Connect { addr: String },
     SetName { name: String },
+    Login { name: String, password: String },
+    CreateAccount { name: String, password: String },
     Disconnect,
+    Reconnect,
     SendTo { room: String, message: String },
     PrivMsg { user: String, message: String },
+    // Text typed without a command, sent to the current buffer
+    Say { text: String },
+    Query { user: String },
     Join { room: String },
     Leave { room: String },
-    List { opt: String },
+    List { opt: ListOption },
+    // Members of the room, or of the room of the current buffer
+    Names { room: Option<String> },
     Create { room: String },
+    History { room: String },
+    // Switching between buffers, bound to keys rather than commands
+    NextBuffer,
+    PrevBuffer,
+    SelectBuffer { index: usize },
+    ToggleSidebar,
     Quit,
     Invalid,
 }
 
 pub fn parse_command(string: String) -> Option<Action> {
+    // A leading "//" sends text starting with a slash
+    if let Some(text) = string.strip_prefix("//") {
+        return Some(Action::Say {
+            text: format!("/{text}"),
+        });
+    }
+
+    if !string.starts_with('/') {
+        return Some(Action::Say { text: string });
+    }
+
     let mut tokens = string.split_whitespace();
     if let Some(cmd) = tokens.next() {
         if let Some(stripped) = cmd.strip_prefix('/') {
@@ -32,6 +60,20 @@ pub fn parse_command(string: String) -> Option<Action> {
                     };
                     return Some(Action::SetName { name });
                 }
+                "login" | "register" => {
+                    let (name, password) = match (tokens.next(), tokens.next()) {
+                        (Some(name), Some(password)) => (name.to_string(), password.to_string()),
+                        _ => {
+                            return None;
+                        }
+                    };
+
+                    if cmd_name == "login" {
+                        return Some(Action::Login { name, password });
+                    } else {
+                        return Some(Action::CreateAccount { name, password });
+                    }
+                }
                 "connect" => {
                     let addr = match tokens.next() {
                         Some(addr) => addr.to_string(),
@@ -50,7 +92,7 @@ pub fn parse_command(string: String) -> Option<Action> {
                         }
                     };
                     let mut message = String::new();
-                    while let Some(part) = tokens.next() {
+                    for part in tokens {
                         message += part;
                         message += " ";
                     }
@@ -70,7 +112,7 @@ pub fn parse_command(string: String) -> Option<Action> {
                         }
                     };
                     let mut message = String::new();
-                    while let Some(part) = tokens.next() {
+                    for part in tokens {
                         message += part;
                         message += " ";
                     }
@@ -82,16 +124,38 @@ pub fn parse_command(string: String) -> Option<Action> {
 
                     return Some(Action::PrivMsg { user, message });
                 }
+                "query" => {
+                    let user = match tokens.next() {
+                        Some(user) => user.to_string(),
+                        None => {
+                            return None;
+                        }
+                    };
+
+                    return Some(Action::Query { user });
+                }
                 "list" => {
                     let opt = match tokens.next() {
-                        Some(opt) => opt.to_string(),
-                        None => {
+                        Some("users") => ListOption::Users,
+                        Some("rooms") => ListOption::Rooms,
+                        Some("allrooms") => ListOption::AllRooms,
+                        Some("members") => {
+                            let room = tokens.next().map(|room| room.to_string());
+
+                            return Some(Action::Names { room });
+                        }
+                        _ => {
                             return None;
                         }
                     };
 
                     return Some(Action::List { opt });
                 }
+                "names" => {
+                    let room = tokens.next().map(|room| room.to_string());
+
+                    return Some(Action::Names { room });
+                }
                 "join" => {
                     let room = match tokens.next() {
                         Some(room) => room.to_string(),
@@ -122,9 +186,22 @@ pub fn parse_command(string: String) -> Option<Action> {
 
                     return Some(Action::Create { room });
                 }
+                "history" => {
+                    let room = match tokens.next() {
+                        Some(room) => room.to_string(),
+                        None => {
+                            return None;
+                        }
+                    };
+
+                    return Some(Action::History { room });
+                }
                 "disconnect" => {
                     return Some(Action::Disconnect);
                 }
+                "reconnect" => {
+                    return Some(Action::Reconnect);
+                }
                 "quit" => {
                     return Some(Action::Quit);
                 }
@@ -135,3 +212,67 @@ pub fn parse_command(string: String) -> Option<Action> {
 
     None
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn say(text: &str) -> Option<Action> {
+        Some(Action::Say {
+            text: text.to_string(),
+        })
+    }
+
+    #[test]
+    fn parse_command_cases() {
+        let cases = [
+            // Plain text goes to the current buffer as typed
+            ("hello there", say("hello there")),
+            ("  indented", say("  indented")),
+            ("a/b", say("a/b")),
+            // Commands
+            ("/help", Some(Action::Help)),
+            (
+                "/join  lobby",
+                Some(Action::Join {
+                    room: String::from("lobby"),
+                }),
+            ),
+            (
+                "/privmsg bob hi  there",
+                Some(Action::PrivMsg {
+                    user: String::from("bob"),
+                    message: String::from("hi there"),
+                }),
+            ),
+            (
+                "/list rooms",
+                Some(Action::List {
+                    opt: ListOption::Rooms,
+                }),
+            ),
+            ("/names", Some(Action::Names { room: None })),
+            (
+                "/query alice",
+                Some(Action::Query {
+                    user: String::from("alice"),
+                }),
+            ),
+            ("/quit", Some(Action::Quit)),
+            // A doubled slash sends the rest with a single one
+            ("//join lobby", say("/join lobby")),
+            ("//", say("/")),
+            // Unknown commands and missing arguments
+            ("/", None),
+            ("/frobnicate", None),
+            ("/HELP", None),
+            ("/join", None),
+            ("/privmsg bob", None),
+            ("/list everything", None),
+        ];
+
+        for (input, expected) in cases {
+            assert_eq!(parse_command(input.to_string()), expected, "{input:?}");
+        }
+    }
+}
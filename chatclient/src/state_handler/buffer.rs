@@ -0,0 +1,85 @@
+use super::TextType;
+use common::message::Member;
+use common::validation::name_key;
+
+// Conversations kept apart in the TUI, each in its own buffer
+#[derive(Clone, Debug)]
+pub enum BufferId {
+    // Notices from the client and the server, and command output
+    Server,
+    Room(String),
+    // Private messages with the named user
+    Private(String),
+}
+
+// Users are told apart the way the server does, so a conversation
+// stays in one buffer however the name was typed
+impl PartialEq for BufferId {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (BufferId::Server, BufferId::Server) => true,
+            (BufferId::Room(room), BufferId::Room(other)) => room == other,
+            (BufferId::Private(user), BufferId::Private(other)) => {
+                name_key(user) == name_key(other)
+            }
+            _ => false,
+        }
+    }
+}
+
+impl Eq for BufferId {}
+
+impl BufferId {
+    pub fn label(&self) -> String {
+        match self {
+            BufferId::Server => String::from("server"),
+            BufferId::Room(room) => format!("#{room}"),
+            BufferId::Private(user) => format!("@{user}"),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Buffer {
+    pub id: BufferId,
+    pub lines: Vec<TextType>,
+    // Chat messages received while the buffer was not shown
+    pub unread: usize,
+    // Members of the room, sorted by name
+    pub members: Vec<Member>,
+}
+
+impl Buffer {
+    pub fn new(id: BufferId) -> Self {
+        Buffer {
+            id,
+            lines: Vec::new(),
+            unread: 0,
+            members: Vec::new(),
+        }
+    }
+
+    pub fn set_members(&mut self, mut members: Vec<Member>) {
+        members.sort_by_key(|member| name_key(&member.username));
+        self.members = members;
+    }
+
+    // Adds the member, or replaces the one known as `username`. Changes
+    // seen twice, such as the join of a member already listed, leave
+    // a single entry
+    pub fn update_member(&mut self, username: &str, member: Member) {
+        let mut members = std::mem::take(&mut self.members);
+        members.retain(|known| {
+            name_key(&known.username) != name_key(username)
+                && name_key(&known.username) != name_key(&member.username)
+        });
+        members.push(member);
+
+        self.set_members(members);
+    }
+
+    pub fn remove_member(&mut self, username: &str) {
+        self.members
+            .retain(|member| name_key(&member.username) != name_key(username));
+    }
+}
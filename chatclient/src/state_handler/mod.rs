@@ -1,9 +1,11 @@
 mod action;
+mod buffer;
 mod state;
 
 pub use super::tui::TextType;
 pub use action::{parse_command, Action};
-pub use state::{ClientState, ConnectionStatus};
+pub use buffer::{Buffer, BufferId};
+pub use state::{ClientState, ConnectionStatus, Identity};
 
 use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
 
@@ -1,12 +1,53 @@
 use anyhow::{anyhow, Result};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::time::{Duration, Instant};
 
-use super::TextType;
-use common::message::{Message, MessageType};
+use super::{Buffer, BufferId, TextType};
+use common::codec::Codec;
+use common::error::ErrorCode;
+use common::handshake::Capability;
+use common::message::{ChatMessage, Member, Message, MessageType, Presence};
+use common::validation::name_key;
+
+// Announcement for a member who was renamed or changed presence,
+// given the presence they were known with
+fn member_change(username: &str, presence: Option<Presence>, member: &Member) -> Option<String> {
+    if member.username != username {
+        return Some(format!(
+            "[*] {username} is now known as {0}",
+            member.username
+        ));
+    }
+
+    match (presence, member.presence) {
+        (Some(Presence::Online), Presence::Away) => Some(format!("[-] {username} lost connection")),
+        (Some(Presence::Away), Presence::Online) => Some(format!("[+] {username} is back")),
+        _ => None,
+    }
+}
 
 #[derive(Clone)]
 pub enum ConnectionStatus {
     Unitiliazed,
     Established,
+    // Connection was lost, counting attempts at getting it back
+    Reconnecting { attempt: u32 },
+}
+
+// How the client identifies itself when registering on a server
+#[derive(Clone)]
+pub enum Identity {
+    Guest,
+    // `create` is cleared once the account exists, so that
+    // later connections log in to it instead
+    Account { password: String, create: bool },
+}
+
+// Request sent to the server which has not been answered yet
+#[derive(Clone)]
+pub struct PendingRequest {
+    pub command: String,
+    pub sent_at: Instant,
 }
 
 #[derive(Clone)]
@@ -15,7 +56,30 @@ pub struct ClientState {
     pub current_server: String,
     pub username: String,
     pub session_id: u64,
-    pub notifications: Vec<TextType>,
+    pub registered: bool,
+    pub identity: Identity,
+    // Token for getting the session back after losing the
+    // connection, only valid for the current server
+    pub resume_token: Option<String>,
+    // Wait asked for by a server which shut down, before reconnecting
+    pub reconnect_after: Option<Duration>,
+    // Round trip of the last ping answered by the server
+    pub latency: Option<Duration>,
+    // Rooms joined on the current server, kept across
+    // connections so they can be joined again
+    pub joined_rooms: BTreeSet<String>,
+    pub codec: Codec,
+    pub capabilities: Vec<Capability>,
+    // Buffers in the order they are shown, the server buffer first
+    pub buffers: Vec<Buffer>,
+    pub active_buffer: BufferId,
+    pub show_sidebar: bool,
+    pending_requests: HashMap<u64, PendingRequest>,
+    next_request_id: u64,
+    seen_messages: HashSet<u64>,
+    // Id of the oldest message shown for each room,
+    // used as starting point when fetching older history
+    oldest_messages: HashMap<String, u64>,
 }
 
 impl Default for ClientState {
@@ -37,64 +101,386 @@ impl Default for ClientState {
             current_server: String::new(),
             username: String::new(),
             session_id: u64::MAX,
-            notifications: startup_notifications,
+            registered: false,
+            identity: Identity::Guest,
+            resume_token: None,
+            reconnect_after: None,
+            latency: None,
+            joined_rooms: BTreeSet::new(),
+            codec: Codec::Postcard,
+            capabilities: Vec::new(),
+            buffers: vec![Buffer {
+                lines: startup_notifications,
+                ..Buffer::new(BufferId::Server)
+            }],
+            active_buffer: BufferId::Server,
+            show_sidebar: true,
+            pending_requests: HashMap::new(),
+            next_request_id: 1,
+            seen_messages: HashSet::new(),
+            oldest_messages: HashMap::new(),
         }
     }
 }
 
 impl ClientState {
+    // Notices go to the buffer being looked at, next to
+    // the command that caused them
     pub fn push_notification(&mut self, notification: TextType) {
-        self.notifications.push(notification);
+        let id = self.active_buffer.clone();
+
+        self.push_to(&id, notification);
+    }
+
+    // Adds a line to the buffer, opening it if needed. Chat messages
+    // count as unread unless the buffer is shown
+    fn push_to(&mut self, id: &BufferId, line: TextType) {
+        let active = *id == self.active_buffer;
+        let buffer = self.open_buffer(id);
+
+        if !active
+            && matches!(
+                line,
+                TextType::RoomMessage { .. } | TextType::PrivateMessage { .. }
+            )
+        {
+            buffer.unread += 1;
+        }
+
+        buffer.lines.push(line);
+    }
+
+    fn open_buffer(&mut self, id: &BufferId) -> &mut Buffer {
+        let index = match self.buffers.iter().position(|buffer| buffer.id == *id) {
+            Some(index) => index,
+            None => {
+                self.buffers.push(Buffer::new(id.clone()));
+                self.buffers.len() - 1
+            }
+        };
+
+        &mut self.buffers[index]
+    }
+
+    // Opens the buffer if needed and shows it
+    pub fn focus_buffer(&mut self, id: BufferId) {
+        self.open_buffer(&id).unread = 0;
+        self.active_buffer = id;
+    }
+
+    // Room buffers which are not open are left alone
+    fn room_buffer(&mut self, room: &str) -> Option<&mut Buffer> {
+        self.buffers
+            .iter_mut()
+            .find(|buffer| buffer.id == BufferId::Room(room.to_string()))
+    }
+
+    // Tells the room about a change of its members
+    fn announce(&mut self, room: &str, text: String) {
+        let id = BufferId::Room(room.to_string());
+
+        if self.buffers.iter().any(|buffer| buffer.id == id) {
+            self.push_to(&id, TextType::Notification { text });
+        }
+    }
+
+    fn close_buffer(&mut self, id: &BufferId) {
+        self.buffers.retain(|buffer| buffer.id != *id);
+
+        if self.active_buffer == *id {
+            self.active_buffer = BufferId::Server;
+        }
+    }
+
+    pub fn active_lines(&self) -> &[TextType] {
+        self.buffers
+            .iter()
+            .find(|buffer| buffer.id == self.active_buffer)
+            .map(|buffer| buffer.lines.as_slice())
+            .unwrap_or_default()
+    }
+
+    pub fn select_buffer(&mut self, index: usize) {
+        if let Some(buffer) = self.buffers.get_mut(index) {
+            buffer.unread = 0;
+            self.active_buffer = buffer.id.clone();
+        }
+    }
+
+    // Moves `step` buffers to the right, or left if negative,
+    // wrapping around at either end
+    pub fn cycle_buffer(&mut self, step: isize) {
+        let current = self
+            .buffers
+            .iter()
+            .position(|buffer| buffer.id == self.active_buffer)
+            .unwrap_or(0);
+        let index = (current as isize + step).rem_euclid(self.buffers.len() as isize);
+
+        self.select_buffer(index as usize);
+    }
+
+    // Conversations of another server have nothing to do with
+    // the new one, leaving only the server buffer
+    pub fn reset_buffers(&mut self) {
+        self.buffers.retain(|buffer| buffer.id == BufferId::Server);
+        self.active_buffer = BufferId::Server;
     }
 
     pub fn exit(&mut self) {}
 
     pub fn terminate_connection(&mut self) {
         self.connection_status = ConnectionStatus::Unitiliazed;
+        self.registered = false;
+        self.capabilities.clear();
+        self.pending_requests.clear();
+        self.latency = None;
     }
 
-    pub fn handle_message(&mut self, message: Message) -> Result<()> {
-        let header = message.header;
-        let message_type = header.message_type;
-        let body = message.body;
+    // Message ids are only unique per server
+    pub fn reset_seen_messages(&mut self) {
+        self.seen_messages.clear();
+        self.oldest_messages.clear();
+    }
+
+    pub fn oldest_message(&self, room: &str) -> Option<u64> {
+        self.oldest_messages.get(room).copied()
+    }
+
+    // Shows a room message unless it was already displayed
+    fn push_room_message(&mut self, room: String, message: ChatMessage) {
+        if !self.seen_messages.insert(message.id) {
+            return;
+        }
+
+        let oldest = self
+            .oldest_messages
+            .entry(room.clone())
+            .or_insert(message.id);
+        *oldest = (*oldest).min(message.id);
+
+        self.push_to(&BufferId::Room(room), TextType::RoomMessage { message });
+    }
+
+    fn push_private_message(&mut self, peer: String, outgoing: bool, message: ChatMessage) {
+        if !self.seen_messages.insert(message.id) {
+            return;
+        }
 
-        match message_type {
-            MessageType::Failed => {
-                let failed_cmd = body.arg.unwrap();
-                let error = body.content.unwrap();
+        let id = BufferId::Private(peer);
+        self.push_to(&id, TextType::PrivateMessage { outgoing, message });
 
+        // Buffers opened with /query take on the name as the server
+        // spells it, which messages always carry
+        if let Some(buffer) = self.buffers.iter_mut().find(|buffer| buffer.id == id) {
+            buffer.id = id.clone();
+        }
+        if self.active_buffer == id {
+            self.active_buffer = id;
+        }
+    }
+
+    // Builds a message for the server tagged with a fresh request id,
+    // remembering the command so that the reply can be matched to it
+    pub fn request(&mut self, message_type: MessageType, command: String) -> Message {
+        let request_id = self.next_request_id;
+        self.next_request_id += 1;
+
+        self.pending_requests.insert(
+            request_id,
+            PendingRequest {
+                command,
+                sent_at: Instant::now(),
+            },
+        );
+
+        Message::build(message_type, self.session_id).with_request_id(Some(request_id))
+    }
+
+    // Registers under the current name, logging in to or
+    // creating an account if one was set up
+    pub fn registration_request(&mut self) -> Message {
+        let username = self.username.clone();
+
+        match self.identity.clone() {
+            Identity::Guest => self.request(
+                MessageType::Register {
+                    username: username.clone(),
+                },
+                format!("register {username}"),
+            ),
+            Identity::Account {
+                password,
+                create: false,
+            } => self.request(
+                MessageType::Login {
+                    username: username.clone(),
+                    password,
+                },
+                format!("login {username}"),
+            ),
+            Identity::Account {
+                password,
+                create: true,
+            } => self.request(
+                MessageType::CreateAccount {
+                    username: username.clone(),
+                    password,
+                },
+                format!("createaccount {username}"),
+            ),
+        }
+    }
+
+    // Drops requests which have not been answered within the timeout.
+    // Returns whether any request expired
+    pub fn expire_requests(&mut self, timeout: Duration) -> bool {
+        let expired = self
+            .pending_requests
+            .iter()
+            .filter(|(_, request)| request.sent_at.elapsed() >= timeout)
+            .map(|(id, _)| *id)
+            .collect::<Vec<u64>>();
+
+        for id in &expired {
+            if let Some(request) = self.pending_requests.remove(id) {
                 self.push_notification(TextType::Error {
-                    text: format!("[-] {failed_cmd} failed: {error}"),
+                    text: format!("[-] {0} timed out (request #{id})", request.command),
                 });
+            }
+        }
 
-                match failed_cmd.as_ref() {
-                    "register" => {
-                        self.terminate_connection();
+        !expired.is_empty()
+    }
 
-                        self.push_notification(TextType::Error {
-                            text: String::from("[-] Connection to server closed"),
-                        });
+    fn push_listing(&mut self, title: &str, entries: Vec<String>) {
+        self.push_notification(TextType::Notification {
+            text: format!("[+] {title}"),
+        });
+
+        for entry in entries {
+            self.push_notification(TextType::Listing {
+                text: format!("[{entry}]"),
+            });
+        }
+
+        self.push_notification(TextType::Notification {
+            text: String::from("[-] End of list"),
+        });
+    }
+
+    pub fn handle_message(&mut self, message: Message) -> Result<()> {
+        let request = message.header.request_id.and_then(|id| {
+            self.pending_requests
+                .remove(&id)
+                .map(|request| (id, request))
+        });
+
+        match message.message_type {
+            MessageType::Failed { command, code } => {
+                let text = match &request {
+                    Some((id, request)) => {
+                        format!("[-] {0} failed (request #{id}): {code}", request.command)
+                    }
+                    None => format!("[-] {command} failed: {code}"),
+                };
+
+                self.push_notification(TextType::Error { text });
 
-                        return Err(anyhow!("Failed registration"));
+                // Rooms which are gone by the time they are rejoined
+                // after reconnecting are no longer remembered
+                if code == ErrorCode::NoSuchRoom {
+                    if let Some(room) = request
+                        .as_ref()
+                        .and_then(|(_, request)| request.command.strip_prefix("join "))
+                    {
+                        self.joined_rooms.remove(room);
+                    }
+                }
+
+                // Refused registrations would be refused again the same
+                // way, so the client waits for the user to pick another
+                // name or identity instead of reusing the old ones
+                if !self.registered {
+                    self.resume_token = None;
+                    self.identity = Identity::Guest;
+                }
+
+                if code.is_fatal() {
+                    self.terminate_connection();
+
+                    self.push_notification(TextType::Error {
+                        text: String::from("[-] Connection to server closed"),
+                    });
+
+                    return Err(anyhow!(code));
+                }
+
+                // Still connected but without a name on the server
+                if !self.registered {
+                    let hint = match code {
+                        ErrorCode::NameTaken => {
+                            Some("[*] Pick another name with /name to register")
+                        }
+                        ErrorCode::NameReserved => {
+                            Some("[*] Log in with /login {name} {password} to use this name")
+                        }
+                        ErrorCode::GuestsDisabled => {
+                            Some("[*] Log in with /login or create an account with /register")
+                        }
+                        _ => None,
+                    };
+
+                    if let Some(hint) = hint {
+                        self.push_notification(TextType::Notification {
+                            text: String::from(hint),
+                        });
                     }
-                    _ => {}
                 }
             }
-            MessageType::Registered => {
-                let given_id = body.arg.unwrap();
-                let username = body.content.unwrap();
+            MessageType::Registered { id, username } => {
+                self.session_id = id;
+                self.registered = true;
 
-                self.session_id = given_id.parse::<u64>().unwrap();
+                if let Identity::Account { create, .. } = &mut self.identity {
+                    *create = false;
+                }
                 self.username = username.clone();
 
                 self.push_notification(TextType::Notification {
                     text: format!("[+] Registered as {username}"),
                 });
             }
-            MessageType::ChangedName => {
-                let new_username = body.arg.unwrap();
-                let old_username = body.content.unwrap();
+            MessageType::SessionToken { token } => {
+                self.resume_token = Some(token);
+            }
+            MessageType::Resumed {
+                id,
+                username,
+                rooms,
+            } => {
+                self.session_id = id;
+                self.registered = true;
+                self.username = username.clone();
+
+                self.push_notification(TextType::Notification {
+                    text: format!("[+] Resumed session as {username}"),
+                });
+
+                self.joined_rooms = rooms.iter().cloned().collect();
+
+                for room in &rooms {
+                    self.open_buffer(&BufferId::Room(room.clone()));
+                }
 
+                if !rooms.is_empty() {
+                    self.push_listing("Rooms still joined", rooms);
+                }
+            }
+            MessageType::ChangedName {
+                new_username,
+                old_username,
+            } => {
                 self.username = new_username.clone();
 
                 self.push_notification(TextType::Notification {
@@ -103,110 +489,227 @@ impl ClientState {
                     ),
                 });
             }
-            MessageType::UserRooms => {
-                let content = body.content.unwrap();
-                let mut rooms = Vec::new();
-                if content.contains(",") {
-                    rooms = content.split(",").map(|s| s.to_string()).collect();
-                } else {
-                    rooms.push(content);
+            MessageType::UserRooms(rooms) => {
+                self.push_listing("List of joined rooms", rooms);
+            }
+            MessageType::AllRooms(rooms) => {
+                self.push_listing("List of all rooms", rooms);
+            }
+            MessageType::Users(users) => {
+                self.push_listing("List users", users);
+            }
+            MessageType::Joined {
+                room,
+                history,
+                members,
+            } => {
+                self.joined_rooms.insert(room.clone());
+
+                // Rooms joined again after reconnecting keep their buffer
+                // and don't take the focus away from the current one
+                let id = BufferId::Room(room.clone());
+                if !self.buffers.iter().any(|buffer| buffer.id == id) {
+                    self.focus_buffer(id.clone());
                 }
 
-                self.push_notification(TextType::Notification {
-                    text: String::from("[+] List of joined rooms"),
-                });
+                self.open_buffer(&id).set_members(members);
+                self.push_to(
+                    &id,
+                    TextType::Notification {
+                        text: format!("[+] Joined [{room}] room"),
+                    },
+                );
 
-                for room in rooms {
-                    self.push_notification(TextType::Listing {
-                        text: format!("[{room}]"),
-                    });
+                for message in history {
+                    self.push_room_message(room.clone(), message);
                 }
-
-                self.push_notification(TextType::Notification {
-                    text: String::from("[-] End of list"),
-                });
             }
-            MessageType::AllRooms => {
-                let content = body.content.unwrap();
-                let mut rooms = Vec::new();
-                if content.contains(",") {
-                    rooms = content.split(",").map(|s| s.to_string()).collect();
+            MessageType::History {
+                room,
+                messages,
+                more,
+            } => {
+                let id = BufferId::Room(room.clone());
+
+                if messages.is_empty() {
+                    self.push_to(
+                        &id,
+                        TextType::Notification {
+                            text: format!("[-] No older messages in [{room}]"),
+                        },
+                    );
                 } else {
-                    rooms.push(content);
-                }
+                    self.push_to(
+                        &id,
+                        TextType::Notification {
+                            text: format!("[+] Older messages in [{room}]"),
+                        },
+                    );
 
-                self.push_notification(TextType::Notification {
-                    text: String::from("[+] List of all rooms"),
-                });
+                    for message in messages {
+                        self.push_room_message(room.clone(), message);
+                    }
 
-                for room in rooms {
-                    self.push_notification(TextType::Listing {
-                        text: format!("[{room}]"),
-                    });
+                    if !more {
+                        self.push_to(
+                            &id,
+                            TextType::Notification {
+                                text: format!("[-] Start of [{room}] history"),
+                            },
+                        );
+                    }
                 }
+            }
+            MessageType::LeftRoom { room } => {
+                self.joined_rooms.remove(&room);
+                self.close_buffer(&BufferId::Room(room.clone()));
 
                 self.push_notification(TextType::Notification {
-                    text: String::from("[-] End of list"),
+                    text: format!("[+] Left [{room}] room"),
                 });
             }
-            MessageType::Users => {
-                let content = body.content.unwrap();
-                let mut users = Vec::new();
-                if content.contains(",") {
-                    users = content.split(",").map(|s| s.to_string()).collect();
-                } else {
-                    users.push(content);
-                }
-
+            MessageType::CreatedRoom { room } => {
                 self.push_notification(TextType::Notification {
-                    text: String::from("[+] List users"),
+                    text: format!("[+] Created [{room}] room"),
                 });
+            }
+            MessageType::RoomMessage { room, message } => {
+                self.push_room_message(room, message);
+            }
+            MessageType::MissedMessages {
+                room,
+                count,
+                messages,
+                members,
+            } => {
+                let id = BufferId::Room(room.clone());
 
-                for user in users {
-                    self.push_notification(TextType::Listing {
-                        text: format!("[{user}]"),
-                    });
+                self.push_to(
+                    &id,
+                    TextType::Error {
+                        text: format!("[!] Fell behind and missed {count} messages in [{room}]"),
+                    },
+                );
+
+                if !messages.is_empty() {
+                    self.push_to(
+                        &id,
+                        TextType::Notification {
+                            text: format!(
+                                "[*] Recovered {0} messages from history",
+                                messages.len()
+                            ),
+                        },
+                    );
                 }
 
-                self.push_notification(TextType::Notification {
-                    text: String::from("[-] End of list"),
-                });
+                // Messages which were not actually missed are skipped
+                for message in messages {
+                    self.push_room_message(room.clone(), message);
+                }
+
+                // Changes of the members may have been missed as well
+                if let Some(buffer) = self.room_buffer(&room) {
+                    buffer.set_members(members);
+                }
             }
-            MessageType::Joined => {
-                let room = body.arg.unwrap();
-                self.push_notification(TextType::Notification {
-                    text: format!("[+] Joined [{room}] room"),
-                });
+            MessageType::RoomMembers { room, members } => {
+                // Only listed when asked for with /names, lists
+                // fetched after resuming just update the sidebar
+                let listed = request
+                    .as_ref()
+                    .is_some_and(|(_, request)| request.command.starts_with("names "));
+
+                if listed {
+                    let entries = members
+                        .iter()
+                        .map(|member| match member.presence {
+                            Presence::Online => member.username.clone(),
+                            Presence::Away => format!("{0} (away)", member.username),
+                        })
+                        .collect::<Vec<String>>();
+
+                    self.push_listing(&format!("Members of [{room}]"), entries);
+                }
+
+                if let Some(buffer) = self.room_buffer(&room) {
+                    buffer.set_members(members);
+                }
             }
-            MessageType::LeftRoom => {
-                let room = body.arg.unwrap();
-                self.push_notification(TextType::Notification {
-                    text: format!("[+] Left [{room}] room"),
-                });
+            MessageType::MemberJoined { room, member } => {
+                self.announce(&room, format!("[+] {0} joined", member.username));
+
+                if let Some(buffer) = self.room_buffer(&room) {
+                    buffer.update_member(&member.username.clone(), member);
+                }
             }
-            MessageType::CreatedRoom => {
-                let room = body.arg.unwrap();
-                self.push_notification(TextType::Notification {
-                    text: format!("[+] Created [{room}] room"),
+            MessageType::MemberChanged {
+                room,
+                username,
+                member,
+            } => {
+                let presence = self.room_buffer(&room).and_then(|buffer| {
+                    buffer
+                        .members
+                        .iter()
+                        .find(|known| name_key(&known.username) == name_key(&username))
+                        .map(|known| known.presence)
                 });
+
+                // Our own connection coming and going needs no announcing
+                let renamed = member.username != username;
+                if renamed || member.username != self.username {
+                    if let Some(text) = member_change(&username, presence, &member) {
+                        self.announce(&room, text);
+                    }
+                }
+
+                if let Some(buffer) = self.room_buffer(&room) {
+                    buffer.update_member(&username, member);
+                }
             }
-            MessageType::RoomMessage => {
-                let room = body.arg.unwrap();
-                let content = body.content.unwrap();
-                self.push_notification(TextType::RoomMessage {
-                    text: format!("[{room}] {content}"),
+            MessageType::MemberLeft {
+                room,
+                username,
+                disconnected,
+            } => {
+                let text = match disconnected {
+                    true => format!("[-] {username} disconnected"),
+                    false => format!("[-] {username} left"),
+                };
+                self.announce(&room, text);
+
+                if let Some(buffer) = self.room_buffer(&room) {
+                    buffer.remove_member(&username);
+                }
+            }
+            MessageType::ShuttingDown {
+                reason,
+                reconnect_after,
+            } => {
+                self.push_notification(TextType::Error {
+                    text: format!("[-] {reason}"),
                 });
+
+                // Sessions don't outlive the server
+                self.resume_token = None;
+                self.reconnect_after = Some(Duration::from_secs(reconnect_after));
+            }
+            MessageType::Motd { text } => {
+                for line in text.lines() {
+                    self.push_to(
+                        &BufferId::Server,
+                        TextType::Notification {
+                            text: line.to_string(),
+                        },
+                    );
+                }
             }
-            MessageType::IncomingMsg => {
-                let content = body.content.unwrap();
-                self.push_notification(TextType::PrivateMessage { text: content });
+            MessageType::IncomingMsg(message) => {
+                self.push_private_message(message.author.username.clone(), false, message);
             }
-            MessageType::OutgoingMsg => {
-                let receiver = body.arg.unwrap();
-                let content = body.content.unwrap();
-                self.push_notification(TextType::PrivateMessage {
-                    text: format!("to {receiver}: {content}"),
-                });
+            MessageType::OutgoingMsg { to, message } => {
+                self.push_private_message(to, true, message);
             }
             _ => {}
         }
@@ -214,3 +717,257 @@ impl ClientState {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::message::Author;
+
+    fn chat(id: u64, author: &str, text: &str) -> ChatMessage {
+        let author = Author {
+            id,
+            username: author.to_string(),
+        };
+
+        ChatMessage::new(id, author, text.to_string())
+    }
+
+    fn receive(state: &mut ClientState, message_type: MessageType) {
+        state
+            .handle_message(Message::build(message_type, 0))
+            .unwrap();
+    }
+
+    fn buffer<'a>(state: &'a ClientState, id: &BufferId) -> &'a Buffer {
+        state
+            .buffers
+            .iter()
+            .find(|buffer| buffer.id == *id)
+            .unwrap()
+    }
+
+    #[test]
+    fn private_messages_share_a_buffer_both_ways() {
+        let mut state = ClientState::default();
+        let alice = BufferId::Private(String::from("Alice"));
+
+        receive(&mut state, MessageType::IncomingMsg(chat(1, "Alice", "hi")));
+        receive(
+            &mut state,
+            MessageType::OutgoingMsg {
+                to: String::from("Alice"),
+                message: chat(2, "bob", "hello"),
+            },
+        );
+
+        assert_eq!(state.buffers.len(), 2);
+        assert_eq!(buffer(&state, &alice).lines.len(), 2);
+        // Opening a buffer doesn't take the focus
+        assert_eq!(state.active_buffer, BufferId::Server);
+    }
+
+    #[test]
+    fn unread_counts_chat_messages_of_hidden_buffers() {
+        let mut state = ClientState::default();
+        let main = BufferId::Room(String::from("main"));
+        let alice = BufferId::Private(String::from("Alice"));
+
+        receive(
+            &mut state,
+            MessageType::RoomMessage {
+                room: String::from("main"),
+                message: chat(1, "bob", "one"),
+            },
+        );
+        receive(
+            &mut state,
+            MessageType::IncomingMsg(chat(2, "Alice", "two")),
+        );
+        receive(
+            &mut state,
+            MessageType::IncomingMsg(chat(3, "Alice", "three")),
+        );
+        // Delivered again after a resync, not counted twice
+        receive(
+            &mut state,
+            MessageType::IncomingMsg(chat(3, "Alice", "three")),
+        );
+
+        assert_eq!(buffer(&state, &main).unread, 1);
+        assert_eq!(buffer(&state, &alice).unread, 2);
+
+        // Notices are not chat messages
+        state.focus_buffer(alice.clone());
+        state.push_to(
+            &main,
+            TextType::Notification {
+                text: String::from("[+] carol joined"),
+            },
+        );
+        assert_eq!(buffer(&state, &alice).unread, 0);
+        assert_eq!(buffer(&state, &main).unread, 1);
+
+        // Nor is anything shown in the buffer being looked at
+        receive(
+            &mut state,
+            MessageType::IncomingMsg(chat(4, "Alice", "four")),
+        );
+        assert_eq!(buffer(&state, &alice).unread, 0);
+
+        state.select_buffer(1);
+        assert_eq!(state.active_buffer, main);
+        assert_eq!(buffer(&state, &main).unread, 0);
+    }
+
+    #[test]
+    fn queried_users_keep_one_buffer_however_spelled() {
+        let mut state = ClientState::default();
+
+        // As opened by /query alice
+        state.focus_buffer(BufferId::Private(String::from("alice")));
+        receive(&mut state, MessageType::IncomingMsg(chat(1, "Alice", "hi")));
+        receive(
+            &mut state,
+            MessageType::OutgoingMsg {
+                to: String::from("Alice"),
+                message: chat(2, "bob", "hello"),
+            },
+        );
+
+        assert_eq!(state.buffers.len(), 2);
+        assert_eq!(state.active_lines().len(), 2);
+        assert_eq!(state.buffers[1].unread, 0);
+        // The buffer is named the way the server spells the user
+        assert_eq!(state.buffers[1].id.label(), "@Alice");
+        assert_eq!(state.active_buffer.label(), "@Alice");
+    }
+
+    fn member(username: &str, presence: Presence) -> Member {
+        Member {
+            username: username.to_string(),
+            presence,
+        }
+    }
+
+    fn members(state: &ClientState, room: &str) -> Vec<(String, Presence)> {
+        buffer(state, &BufferId::Room(room.to_string()))
+            .members
+            .iter()
+            .map(|member| (member.username.clone(), member.presence))
+            .collect()
+    }
+
+    fn online(names: &[&str]) -> Vec<(String, Presence)> {
+        names
+            .iter()
+            .map(|name| (name.to_string(), Presence::Online))
+            .collect()
+    }
+
+    fn joined(state: &mut ClientState, names: &[&str]) {
+        receive(
+            state,
+            MessageType::Joined {
+                room: String::from("main"),
+                history: Vec::new(),
+                members: names
+                    .iter()
+                    .map(|name| member(name, Presence::Online))
+                    .collect(),
+            },
+        );
+    }
+
+    #[test]
+    fn members_follow_joins_leaves_and_renames() {
+        let mut state = ClientState {
+            username: String::from("alice"),
+            ..Default::default()
+        };
+        joined(&mut state, &["bob", "alice"]);
+        assert_eq!(members(&state, "main"), online(&["alice", "bob"]));
+
+        let carol_joined = || MessageType::MemberJoined {
+            room: String::from("main"),
+            member: member("carol", Presence::Online),
+        };
+        receive(&mut state, carol_joined());
+        receive(&mut state, carol_joined());
+        assert_eq!(members(&state, "main"), online(&["alice", "bob", "carol"]));
+
+        let bob_renamed = || MessageType::MemberChanged {
+            room: String::from("main"),
+            username: String::from("bob"),
+            member: member("Bobby", Presence::Online),
+        };
+        receive(&mut state, bob_renamed());
+        receive(&mut state, bob_renamed());
+        assert_eq!(
+            members(&state, "main"),
+            online(&["alice", "Bobby", "carol"])
+        );
+
+        receive(
+            &mut state,
+            MessageType::MemberChanged {
+                room: String::from("main"),
+                username: String::from("carol"),
+                member: member("carol", Presence::Away),
+            },
+        );
+        assert_eq!(
+            members(&state, "main")[2],
+            (String::from("carol"), Presence::Away)
+        );
+
+        let carol_left = || MessageType::MemberLeft {
+            room: String::from("main"),
+            username: String::from("carol"),
+            disconnected: true,
+        };
+        receive(&mut state, carol_left());
+        receive(&mut state, carol_left());
+        assert_eq!(members(&state, "main"), online(&["alice", "Bobby"]));
+
+        // Changes are announced once each in the room
+        let announcements = state
+            .active_lines()
+            .iter()
+            .filter_map(|line| match line {
+                TextType::Notification { text } => Some(text.as_str()),
+                _ => None,
+            })
+            .collect::<Vec<&str>>();
+        assert!(announcements.contains(&"[+] carol joined"));
+        assert!(announcements.contains(&"[*] bob is now known as Bobby"));
+        assert!(announcements.contains(&"[-] carol lost connection"));
+        assert!(announcements.contains(&"[-] carol disconnected"));
+    }
+
+    #[test]
+    fn resync_replaces_the_members() {
+        let mut state = ClientState::default();
+        joined(&mut state, &["alice", "bob"]);
+
+        receive(
+            &mut state,
+            MessageType::MissedMessages {
+                room: String::from("main"),
+                count: 5,
+                messages: Vec::new(),
+                members: vec![
+                    member("alice", Presence::Online),
+                    member("dave", Presence::Away),
+                ],
+            },
+        );
+
+        assert_eq!(
+            members(&state, "main"),
+            vec![
+                (String::from("alice"), Presence::Online),
+                (String::from("dave"), Presence::Away),
+            ]
+        );
+    }
+}
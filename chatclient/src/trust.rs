@@ -0,0 +1,99 @@
+use anyhow::{anyhow, Context, Result};
+use common::tls::{self, CertificateDer, TlsConnector};
+use std::fs::OpenOptions;
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+// How certificates of servers reached over TLS are trusted. Either they
+// have to be signed by a given authority, or they are trusted on first
+// use and have to stay the same on later connections
+#[derive(Clone)]
+pub struct Trust {
+    connector: TlsConnector,
+    // File keeping the fingerprint seen for every server, unused
+    // when trusting an authority
+    known_servers: Option<PathBuf>,
+}
+
+impl Trust {
+    pub fn new(ca: Option<&Path>, known_servers: Option<PathBuf>) -> Result<Self> {
+        match ca {
+            Some(ca) => Ok(Trust {
+                connector: tls::connector(ca)?,
+                known_servers: None,
+            }),
+            None => Ok(Trust {
+                connector: tls::unverified_connector()?,
+                known_servers: Some(known_servers.unwrap_or_else(default_known_servers)),
+            }),
+        }
+    }
+
+    pub fn connector(&self) -> &TlsConnector {
+        &self.connector
+    }
+
+    // Compares the certificate with the one seen before for the server,
+    // remembering it if there is none. Returns its fingerprint when it
+    // is seen for the first time
+    pub fn check(&self, server: &str, cert: &CertificateDer) -> Result<Option<String>> {
+        let Some(path) = &self.known_servers else {
+            return Ok(None);
+        };
+
+        let fingerprint = tls::fingerprint(cert);
+
+        match known_fingerprint(path, server)? {
+            Some(known) if known == fingerprint => Ok(None),
+            Some(known) => Err(anyhow!(
+                "Certificate of {server} has changed, someone may be impersonating it! \
+                 Expected {known} but got {fingerprint}. If the change is expected, \
+                 remove {server} from {0}",
+                path.display()
+            )),
+            None => {
+                remember(path, server, &fingerprint)?;
+
+                Ok(Some(fingerprint))
+            }
+        }
+    }
+}
+
+// Under the user's configuration directory
+fn default_known_servers() -> PathBuf {
+    let config_dir = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+        .unwrap_or_default();
+
+    config_dir.join("chatclient").join("known_servers")
+}
+
+// The file holds a line per server with its address and fingerprint
+fn known_fingerprint(path: &Path, server: &str) -> Result<Option<String>> {
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).context(format!("Failed to read {}", path.display())),
+    };
+
+    Ok(contents.lines().find_map(|line| {
+        let (address, fingerprint) = line.split_once(' ')?;
+
+        (address == server).then(|| fingerprint.trim().to_string())
+    }))
+}
+
+fn remember(path: &Path, server: &str, fingerprint: &str) -> Result<()> {
+    let write = || -> std::io::Result<()> {
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir)?;
+        }
+
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        writeln!(file, "{server} {fingerprint}")
+    };
+
+    write().with_context(|| format!("Failed to write {}", path.display()))
+}
@@ -2,7 +2,7 @@ use super::components::component::{Component, ComponentRender};
 use super::components::main_page::MainPage;
 use crate::state_handler::{Action, ClientState};
 
-use crossterm::event::KeyEvent;
+use crossterm::event::{KeyEvent, MouseEvent};
 use ratatui::Frame;
 use tokio::sync::mpsc::UnboundedSender;
 
@@ -32,6 +32,10 @@ impl Component for AppRouter {
     fn handle_key_event(&mut self, key: KeyEvent) {
         self.main_page.handle_key_event(key);
     }
+
+    fn handle_mouse_event(&mut self, mouse: MouseEvent) {
+        self.main_page.handle_mouse_event(mouse);
+    }
 }
 
 impl ComponentRender<()> for AppRouter {
@@ -0,0 +1,72 @@
+use super::component::{Component, ComponentRender, RenderProps};
+use crate::state_handler::{Action, ClientState};
+
+use crossterm::event::KeyEvent;
+use ratatui::{prelude::*, widgets::Tabs, Frame};
+use tokio::sync::mpsc::UnboundedSender;
+
+// One line listing the open buffers, with the number of unread
+// messages next to the ones not shown
+pub struct BufferBar {
+    tabs: Vec<String>,
+    selected: usize,
+}
+
+impl BufferBar {
+    fn from_state(state: &ClientState) -> Self {
+        let tabs = state
+            .buffers
+            .iter()
+            .enumerate()
+            .map(|(index, buffer)| {
+                let label = format!("{0} {1}", index + 1, buffer.id.label());
+
+                match buffer.unread {
+                    0 => label,
+                    unread => format!("{label} ({unread})"),
+                }
+            })
+            .collect::<Vec<String>>();
+
+        let selected = state
+            .buffers
+            .iter()
+            .position(|buffer| buffer.id == state.active_buffer)
+            .unwrap_or(0);
+
+        Self { tabs, selected }
+    }
+}
+
+impl Component for BufferBar {
+    fn new(state: &ClientState, _action_tx: UnboundedSender<Action>) -> Self
+    where
+        Self: Sized,
+    {
+        Self::from_state(state)
+    }
+
+    fn update(self, state: &ClientState) -> Self
+    where
+        Self: Sized,
+    {
+        Self::from_state(state)
+    }
+
+    fn handle_key_event(&mut self, _key: KeyEvent) {}
+}
+
+impl ComponentRender<RenderProps> for BufferBar {
+    fn render(&self, frame: &mut Frame, props: RenderProps) {
+        let tabs = Tabs::new(self.tabs.clone())
+            .select(self.selected)
+            .style(Style::new().fg(props.border_color))
+            .highlight_style(
+                Style::new()
+                    .fg(Color::White)
+                    .add_modifier(Modifier::BOLD | Modifier::REVERSED),
+            );
+
+        frame.render_widget(tabs, props.area);
+    }
+}
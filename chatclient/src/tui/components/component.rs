@@ -1,5 +1,5 @@
 use crate::state_handler::{Action, ClientState};
-use crossterm::event::KeyEvent;
+use crossterm::event::{KeyEvent, MouseEvent};
 use ratatui::{layout::Rect, style::Color, Frame};
 use tokio::sync::mpsc::UnboundedSender;
 
@@ -18,6 +18,8 @@ pub trait Component {
         Self: Sized;
 
     fn handle_key_event(&mut self, key: KeyEvent);
+
+    fn handle_mouse_event(&mut self, _mouse: MouseEvent) {}
 }
 
 pub trait ComponentRender<Props> {
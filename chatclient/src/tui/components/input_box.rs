@@ -6,12 +6,15 @@ use ratatui::{
     Frame,
 };
 use tokio::sync::mpsc::UnboundedSender;
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
 
 use super::component::{Component, ComponentRender, RenderProps};
 use crate::state_handler::{parse_command, Action, ClientState};
 
 pub struct InputBox {
-    char_index: usize,
+    // Byte offset of the cursor, always between two graphemes
+    cursor: usize,
     input: String,
     prompt: String,
     action_tx: UnboundedSender<Action>,
@@ -19,54 +22,51 @@ pub struct InputBox {
 
 impl InputBox {
     pub fn cursor_left(&mut self) {
-        let moved_left = self.char_index.saturating_sub(1);
-        self.char_index = self.clamp_cursor(moved_left);
+        self.cursor = self.input[..self.cursor]
+            .grapheme_indices(true)
+            .next_back()
+            .map(|(index, _)| index)
+            .unwrap_or(0);
     }
 
     pub fn cursor_right(&mut self) {
-        let moved_right = self.char_index.saturating_add(1);
-        self.char_index = self.clamp_cursor(moved_right);
+        if let Some(grapheme) = self.input[self.cursor..].graphemes(true).next() {
+            self.cursor += grapheme.len();
+        }
     }
 
+    // Characters such as combining accents become part of the grapheme
+    // they are typed after, the cursor then moves past all of it
     pub fn enter_char(&mut self, new_char: char) {
-        let index = self.byte_index();
-        self.input.insert(index, new_char);
-        self.cursor_right();
-    }
-
-    fn byte_index(&self) -> usize {
-        self.input
-            .char_indices()
-            .map(|(i, _)| i)
-            .nth(self.char_index)
-            .unwrap_or(self.input.len())
+        self.input.insert(self.cursor, new_char);
+        let inserted = self.cursor + new_char.len_utf8();
+
+        self.cursor = self
+            .input
+            .grapheme_indices(true)
+            .map(|(index, grapheme)| index + grapheme.len())
+            .find(|end| *end >= inserted)
+            .unwrap_or(self.input.len());
     }
 
     pub fn delete_char(&mut self) {
-        let not_leftmost = self.char_index != 0;
-        if not_leftmost {
-            let curr_index = self.char_index;
-            let from_left = curr_index - 1;
+        let end = self.cursor;
+        self.cursor_left();
 
-            let before_del = self.input.chars().take(from_left);
-            let after_del = self.input.chars().skip(curr_index);
-
-            self.input = before_del.chain(after_del).collect();
-            self.cursor_left();
-        }
-    }
-
-    fn clamp_cursor(&self, new_pos: usize) -> usize {
-        new_pos.clamp(0, self.input.chars().count())
+        self.input.replace_range(self.cursor..end, "");
     }
 
     fn reset_cursor(&mut self) {
-        self.char_index = 0;
+        self.cursor = 0;
     }
 
     pub fn submit(&mut self) {
         let input = self.input.trim().to_string();
 
+        if input.is_empty() {
+            return;
+        }
+
         match parse_command(input) {
             Some(action) => {
                 let _ = self.action_tx.send(action);
@@ -84,7 +84,7 @@ impl InputBox {
 impl Component for InputBox {
     fn new(state: &ClientState, action_tx: UnboundedSender<Action>) -> Self {
         Self {
-            char_index: 0,
+            cursor: 0,
             input: String::new(),
             prompt: state.username.clone(),
             action_tx,
@@ -129,7 +129,31 @@ impl Component for InputBox {
 
 impl ComponentRender<RenderProps> for InputBox {
     fn render(&self, frame: &mut Frame, props: RenderProps) {
-        let input = Paragraph::new(self.input.as_str())
+        // Input wider than the box scrolls so the cursor stays in view,
+        // measured in columns as wide characters take up two
+        let width = props.area.width.saturating_sub(2) as usize;
+        let cursor_column = self.input[..self.cursor].width();
+        let offset = (cursor_column + 1).saturating_sub(width);
+
+        let mut skipped = 0;
+        let mut shown = 0;
+        let visible = self
+            .input
+            .graphemes(true)
+            .skip_while(|grapheme| {
+                let skip = skipped < offset;
+                if skip {
+                    skipped += grapheme.width();
+                }
+                skip
+            })
+            .take_while(|grapheme| {
+                shown += grapheme.width();
+                shown <= width
+            })
+            .collect::<String>();
+
+        let input = Paragraph::new(visible)
             .style(Style::default().fg(Color::White))
             .block(
                 Block::default()
@@ -139,9 +163,15 @@ impl ComponentRender<RenderProps> for InputBox {
             );
         frame.render_widget(input, props.area);
 
+        // Panes too small for the input still keep the cursor inside them,
+        // where the last grapheme skipped may end past the cursor
+        let column = (cursor_column.saturating_sub(skipped) + 1)
+            .min(props.area.width.saturating_sub(1) as usize);
+        let row = 1.min(props.area.height.saturating_sub(1));
+
         frame.set_cursor_position(Position::new(
-            props.area.x + self.char_index as u16 + 1,
-            props.area.y + 1,
+            props.area.x + column as u16,
+            props.area.y + row,
         ));
     }
 }
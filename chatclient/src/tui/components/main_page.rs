@@ -1,22 +1,45 @@
+use super::buffer_bar::BufferBar;
 use super::component::{Component, ComponentRender, RenderProps};
 use super::input_box::InputBox;
 use super::primary::Primary;
+use super::sidebar::Sidebar;
 use crate::state_handler::{Action, ClientState};
 
-use crossterm::event::KeyEvent;
+use crossterm::event::{KeyCode, KeyEvent, KeyEventKind, KeyModifiers, MouseEvent};
 use ratatui::{
-    layout::{Constraint, Layout},
+    layout::{Constraint, Direction, Layout},
     style::Color,
     Frame,
 };
 use tokio::sync::mpsc::UnboundedSender;
 
+const SIDEBAR_WIDTH: u16 = 24;
+
 pub struct MainPage {
+    buffer_bar: BufferBar,
     input_box: InputBox,
     primary: Primary,
+    sidebar: Sidebar,
+    show_sidebar: bool,
+    action_tx: UnboundedSender<Action>,
 }
 
-impl MainPage {}
+impl MainPage {
+    // Keys for moving between buffers: Ctrl+N and Ctrl+P for the
+    // next and previous one, Alt+1 to Alt+9 to jump straight to one.
+    // F2 shows or hides the sidebar
+    fn shortcut_action(key: KeyEvent) -> Option<Action> {
+        match (key.modifiers, key.code) {
+            (KeyModifiers::CONTROL, KeyCode::Char('n')) => Some(Action::NextBuffer),
+            (KeyModifiers::CONTROL, KeyCode::Char('p')) => Some(Action::PrevBuffer),
+            (KeyModifiers::ALT, KeyCode::Char(digit @ '1'..='9')) => Some(Action::SelectBuffer {
+                index: digit as usize - '1' as usize,
+            }),
+            (_, KeyCode::F(2)) => Some(Action::ToggleSidebar),
+            _ => None,
+        }
+    }
+}
 
 impl Component for MainPage {
     fn new(state: &ClientState, action_tx: UnboundedSender<Action>) -> Self
@@ -24,8 +47,12 @@ impl Component for MainPage {
         Self: Sized,
     {
         Self {
+            buffer_bar: BufferBar::new(state, action_tx.clone()),
             input_box: InputBox::new(state, action_tx.clone()),
-            primary: Primary::new(state, action_tx),
+            primary: Primary::new(state, action_tx.clone()),
+            sidebar: Sidebar::new(state, action_tx.clone()),
+            show_sidebar: state.show_sidebar,
+            action_tx,
         }
     }
 
@@ -34,36 +61,91 @@ impl Component for MainPage {
         Self: Sized,
     {
         Self {
+            buffer_bar: self.buffer_bar.update(state),
             input_box: self.input_box.update(state),
             primary: self.primary.update(state),
+            sidebar: self.sidebar.update(state),
+            show_sidebar: state.show_sidebar,
+            action_tx: self.action_tx,
         }
     }
 
     fn handle_key_event(&mut self, key: KeyEvent) {
-        self.input_box.handle_key_event(key);
+        if key.kind == KeyEventKind::Press {
+            if let Some(action) = Self::shortcut_action(key) {
+                let _ = self.action_tx.send(action);
+                return;
+            }
+        }
+
+        // Paging through the scrollback of the buffer
+        match key.code {
+            KeyCode::PageUp | KeyCode::PageDown | KeyCode::Home | KeyCode::End => {
+                self.primary.handle_key_event(key);
+            }
+            _ => {
+                self.input_box.handle_key_event(key);
+            }
+        }
+    }
+
+    fn handle_mouse_event(&mut self, mouse: MouseEvent) {
+        self.primary.handle_mouse_event(mouse);
     }
 }
 
 impl ComponentRender<()> for MainPage {
     fn render(&self, frame: &mut Frame, _props: ()) {
-        let constraints = Constraint::from_percentages([90, 10]);
+        let constraints = [
+            Constraint::Length(1),
+            Constraint::Percentage(90),
+            Constraint::Percentage(10),
+        ];
         let layout = Layout::default()
             .constraints(constraints)
             .split(frame.area());
+        self.buffer_bar.render(
+            frame,
+            RenderProps {
+                area: layout[0],
+                border_color: Color::LightBlue,
+            },
+        );
+
         self.input_box.render(
             frame,
             RenderProps {
-                area: layout[1],
+                area: layout[2],
                 border_color: Color::LightBlue,
             },
         );
 
+        // The sidebar can be hidden to leave more room on narrow terminals
+        let sidebar_width = match self.show_sidebar {
+            true => SIDEBAR_WIDTH,
+            false => 0,
+        };
+        let columns = Layout::default()
+            .direction(Direction::Horizontal)
+            .constraints([Constraint::Min(0), Constraint::Length(sidebar_width)])
+            .split(layout[1]);
+
         self.primary.render(
             frame,
             RenderProps {
-                area: layout[0],
+                area: columns[0],
                 border_color: Color::LightBlue,
             },
         );
+
+        if self.show_sidebar {
+            self.sidebar.render(
+                frame,
+                RenderProps {
+                    area: columns[1],
+                    border_color: Color::LightBlue,
+                },
+            );
+        }
     }
 }
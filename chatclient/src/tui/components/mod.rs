@@ -1,6 +1,8 @@
+mod buffer_bar;
 pub mod component;
 mod input_box;
 pub mod main_page;
 mod primary;
+mod sidebar;
 
 pub use super::TextType;
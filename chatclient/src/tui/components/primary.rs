@@ -1,18 +1,238 @@
 use super::component::{Component, ComponentRender, RenderProps};
-use crate::state_handler::{Action, ClientState};
+use crate::state_handler::{Action, BufferId, ClientState, ConnectionStatus};
 
 use super::TextType;
-use crossterm::event::KeyEvent;
+use chrono::Local;
+use common::message::ChatMessage;
+use crossterm::event::{KeyCode, KeyEvent, MouseEvent, MouseEventKind};
 use ratatui::{
     prelude::*,
     widgets::{Block, Borders, List, ListDirection},
     Frame,
 };
+use std::cell::Cell;
 use tokio::sync::mpsc::UnboundedSender;
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
+
+// Lines moved by one step of the mouse wheel
+const WHEEL_STEP: usize = 3;
+
+// Local time at which the server received the message
+fn timestamp(message: &ChatMessage) -> String {
+    message
+        .timestamp
+        .with_timezone(&Local)
+        .format("%H:%M")
+        .to_string()
+}
+
+// Server the client is on, along with the state of the connection
+// while it is being brought back or its latency once measured
+fn title(state: &ClientState) -> String {
+    match (&state.connection_status, state.latency) {
+        (ConnectionStatus::Reconnecting { attempt }, _) => {
+            format!(
+                "{0} - reconnecting (attempt {attempt})",
+                state.current_server
+            )
+        }
+        (ConnectionStatus::Established, Some(latency)) => {
+            format!("{0} ({1} ms)", state.current_server, latency.as_millis())
+        }
+        _ => state.current_server.clone(),
+    }
+}
+
+// Text of the entry, with the indent of its wrapped lines and its style.
+// Messages hang under the prefix with the time and author
+fn styled(line: &TextType) -> (String, usize, Style) {
+    match line {
+        TextType::Notification { text } => (
+            text.clone(),
+            0,
+            Style::new().fg(Color::Blue).add_modifier(Modifier::BOLD),
+        ),
+        TextType::Error { text } => (
+            text.clone(),
+            0,
+            Style::new()
+                .fg(Color::LightRed)
+                .add_modifier(Modifier::BOLD),
+        ),
+        TextType::Listing { text } => (
+            text.clone(),
+            0,
+            Style::new()
+                .fg(Color::White)
+                .add_modifier(Modifier::UNDERLINED),
+        ),
+        TextType::PrivateMessage { outgoing, message } => {
+            let color = match outgoing {
+                true => Color::Cyan,
+                false => Color::White,
+            };
+            let prefix = format!("[{0}] {1}: ", timestamp(message), message.author.username);
+
+            (
+                format!("{prefix}{0}", message.text),
+                prefix.width(),
+                Style::new().fg(color).add_modifier(Modifier::BOLD),
+            )
+        }
+        TextType::RoomMessage { message } => {
+            let prefix = format!("[{0}] {1}: ", timestamp(message), message.author.username);
+
+            (
+                format!("{prefix}{0}", message.text),
+                prefix.width(),
+                Style::new().fg(Color::White).add_modifier(Modifier::BOLD),
+            )
+        }
+    }
+}
+
+// Breaks text into lines of at most `width` columns, between words where
+// possible and between graphemes for words longer than a line. Lines
+// after the first are indented by `indent` columns
+fn wrap(text: &str, width: usize, indent: usize) -> Vec<String> {
+    // Narrow panes give the room of the indent to the text instead
+    let indent = match indent * 2 > width {
+        true => 0,
+        false => indent,
+    };
+    let mut wrapper = Wrapper {
+        width: width.max(1),
+        indent,
+        lines: Vec::new(),
+        line: String::new(),
+        line_width: 0,
+        empty: true,
+    };
+
+    for (index, paragraph) in text.split('\n').enumerate() {
+        if index > 0 {
+            wrapper.break_line();
+        }
+
+        let mut rest = paragraph;
+        while let Some(first) = rest.chars().next() {
+            // Runs of either whitespace or anything else
+            let space = first.is_whitespace();
+            let end = rest
+                .find(|c: char| c.is_whitespace() != space)
+                .unwrap_or(rest.len());
+            let (token, tail) = rest.split_at(end);
+
+            match space {
+                true => wrapper.push_space(token),
+                false => wrapper.push_word(token),
+            }
+
+            rest = tail;
+        }
+    }
+
+    wrapper.lines.push(wrapper.line);
+    wrapper.lines
+}
+
+struct Wrapper {
+    width: usize,
+    indent: usize,
+    lines: Vec<String>,
+    line: String,
+    line_width: usize,
+    // Whether the line holds nothing but its indent
+    empty: bool,
+}
+
+impl Wrapper {
+    fn break_line(&mut self) {
+        let line = std::mem::replace(&mut self.line, " ".repeat(self.indent));
+
+        self.lines.push(line.trim_end().to_string());
+        self.line_width = self.indent;
+        self.empty = true;
+    }
+
+    fn fits(&self, width: usize) -> bool {
+        self.line_width + width <= self.width
+    }
+
+    fn push(&mut self, text: &str, width: usize) {
+        self.line.push_str(text);
+        self.line_width += width;
+        self.empty = false;
+    }
+
+    // Whitespace is dropped where lines are broken
+    fn push_space(&mut self, space: &str) {
+        let width = space.width();
+
+        if self.empty {
+            return;
+        }
+
+        match self.fits(width) {
+            true => self.push(space, width),
+            false => self.break_line(),
+        }
+    }
+
+    fn push_word(&mut self, word: &str) {
+        let width = word.width();
+
+        if !self.fits(width) && !self.empty {
+            self.break_line();
+        }
+
+        if self.fits(width) {
+            self.push(word, width);
+            return;
+        }
+
+        for grapheme in word.graphemes(true) {
+            let width = grapheme.width();
+
+            if !self.fits(width) && !self.empty {
+                self.break_line();
+            }
+
+            self.push(grapheme, width);
+        }
+    }
+}
 
 pub struct Primary {
     print_buffer: Vec<TextType>,
     title: String,
+    buffer: BufferId,
+    // Number of the newest lines scrolled past, 0 when following
+    // the buffer. Entries arriving meanwhile keep the view in place
+    scroll: usize,
+    // Chat messages which arrived below the view while scrolled up
+    unseen: usize,
+    // Lines fitting in the view when last rendered, used as page size
+    page: Cell<usize>,
+    // Columns the entries were wrapped to when last rendered
+    width: Cell<usize>,
+    // Lines which can be scrolled past before reaching the oldest one,
+    // known once wrapped to the width of the view
+    max_scroll: Cell<usize>,
+}
+
+impl Primary {
+    fn scroll_by(&mut self, lines: isize) {
+        self.scroll = self
+            .scroll
+            .saturating_add_signed(lines)
+            .min(self.max_scroll.get());
+
+        if self.scroll == 0 {
+            self.unseen = 0;
+        }
+    }
 }
 
 impl Component for Primary {
@@ -21,8 +241,14 @@ impl Component for Primary {
         Self: Sized,
     {
         Self {
-            print_buffer: state.notifications.clone(),
-            title: state.current_server.clone(),
+            print_buffer: state.active_lines().to_vec(),
+            title: title(state),
+            buffer: state.active_buffer.clone(),
+            scroll: 0,
+            unseen: 0,
+            page: Cell::new(0),
+            width: Cell::new(0),
+            max_scroll: Cell::new(0),
         }
     }
 
@@ -30,13 +256,68 @@ impl Component for Primary {
     where
         Self: Sized,
     {
+        let print_buffer = state.active_lines().to_vec();
+
+        // Switching buffers goes back to the newest lines
+        let (scroll, unseen) = match state.active_buffer == self.buffer {
+            true if self.scroll > 0 => {
+                let added = print_buffer
+                    .get(self.print_buffer.len()..)
+                    .unwrap_or_default();
+                let lines = added
+                    .iter()
+                    .map(|line| {
+                        let (text, indent, _) = styled(line);
+
+                        wrap(&text, self.width.get(), indent).len()
+                    })
+                    .sum::<usize>();
+                let messages = added
+                    .iter()
+                    .filter(|line| {
+                        matches!(
+                            line,
+                            TextType::RoomMessage { .. } | TextType::PrivateMessage { .. }
+                        )
+                    })
+                    .count();
+
+                (self.scroll + lines, self.unseen + messages)
+            }
+            _ => (0, 0),
+        };
+
         Self {
-            print_buffer: state.notifications.clone(),
-            title: state.current_server.clone(),
+            print_buffer,
+            title: title(state),
+            buffer: state.active_buffer.clone(),
+            scroll,
+            unseen,
+            page: self.page,
+            width: self.width,
+            max_scroll: self.max_scroll,
         }
     }
 
-    fn handle_key_event(&mut self, _key: KeyEvent) {}
+    fn handle_key_event(&mut self, key: KeyEvent) {
+        let page = self.page.get().max(1) as isize;
+
+        match key.code {
+            KeyCode::PageUp => self.scroll_by(page),
+            KeyCode::PageDown => self.scroll_by(-page),
+            KeyCode::Home => self.scroll_by(isize::MAX),
+            KeyCode::End => self.scroll_by(isize::MIN),
+            _ => {}
+        }
+    }
+
+    fn handle_mouse_event(&mut self, mouse: MouseEvent) {
+        match mouse.kind {
+            MouseEventKind::ScrollUp => self.scroll_by(WHEEL_STEP as isize),
+            MouseEventKind::ScrollDown => self.scroll_by(-(WHEEL_STEP as isize)),
+            _ => {}
+        }
+    }
 }
 
 impl ComponentRender<RenderProps> for Primary {
@@ -46,52 +327,211 @@ impl ComponentRender<RenderProps> for Primary {
             false => self.title.clone(),
         };
 
-        let mut rev_buffer = self.print_buffer.clone();
-        rev_buffer.reverse();
+        self.page.set(props.area.height.saturating_sub(2) as usize);
+
+        let mut block = Block::default()
+            .title(title)
+            .borders(Borders::ALL)
+            .fg(props.border_color);
+
+        // Reminder that the view no longer follows the buffer
+        if self.scroll > 0 {
+            let indicator = match self.unseen {
+                0 => String::from(" More below, End to jump back "),
+                1 => String::from(" 1 new message below, End to jump back "),
+                unseen => format!(" {unseen} new messages below, End to jump back "),
+            };
+
+            block = block.title_bottom(
+                Line::from(indicator)
+                    .style(Style::new().fg(Color::Yellow).add_modifier(Modifier::BOLD))
+                    .right_aligned(),
+            );
+        }
+
+        // Entries are wrapped to the width of the pane and listed
+        // line by line, newest first, so scrolling moves by lines
+        let width = props.area.width.saturating_sub(2) as usize;
+        self.width.set(width);
+
+        let lines = self
+            .print_buffer
+            .iter()
+            .rev()
+            .flat_map(|line| {
+                let (text, indent, style) = styled(line);
+
+                wrap(&text, width, indent)
+                    .into_iter()
+                    .rev()
+                    .map(move |line| Line::from(line).style(style))
+            })
+            .collect::<Vec<Line>>();
+
+        // Scrolling stops once the oldest line is at the top
+        self.max_scroll
+            .set(lines.len().saturating_sub(self.page.get()));
+
         let text = List::new(
-            rev_buffer
+            lines
                 .into_iter()
-                .map(|line| match line {
-                    TextType::Notification { text } => {
-                        let style = Style::new().fg(Color::Blue).add_modifier(Modifier::BOLD);
-
-                        Line::from(text).style(style)
-                    }
-                    TextType::Error { text } => {
-                        let style = Style::new()
-                            .fg(Color::LightRed)
-                            .add_modifier(Modifier::BOLD);
-
-                        Line::from(text).style(style)
-                    }
-                    TextType::Listing { text } => {
-                        let style = Style::new()
-                            .fg(Color::White)
-                            .add_modifier(Modifier::UNDERLINED);
-
-                        Line::from(text).style(style)
-                    }
-                    TextType::PrivateMessage { text } => {
-                        let style = Style::new().fg(Color::White).add_modifier(Modifier::BOLD);
-
-                        Line::from(text).style(style)
-                    }
-                    TextType::RoomMessage { text } => {
-                        let style = Style::new().fg(Color::White).add_modifier(Modifier::BOLD);
-
-                        Line::from(text).style(style)
-                    }
-                })
-                .collect::<Vec<_>>(),
+                .skip(self.scroll.min(self.max_scroll.get()))
+                .collect::<Vec<Line>>(),
         )
         .direction(ListDirection::BottomToTop)
-        .block(
-            Block::default()
-                .title(title)
-                .borders(Borders::ALL)
-                .fg(props.border_color),
-        );
+        .block(block);
 
         frame.render_widget(text, props.area);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::message::Author;
+    use tokio::sync::mpsc;
+
+    fn chat(text: &str) -> ChatMessage {
+        let author = Author {
+            id: 2,
+            username: String::from("bob"),
+        };
+
+        ChatMessage::new(1, author, text.to_string())
+    }
+
+    fn notification(text: &str) -> TextType {
+        TextType::Notification {
+            text: text.to_string(),
+        }
+    }
+
+    // Primary as if rendered 20 columns wide with room to scroll
+    fn primary(state: &ClientState) -> Primary {
+        let (action_tx, _) = mpsc::unbounded_channel();
+        let primary = Primary::new(state, action_tx);
+        primary.width.set(20);
+        primary.max_scroll.set(5);
+
+        primary
+    }
+
+    #[test]
+    fn scrolling_stops_at_either_end() {
+        let primary = &mut primary(&ClientState::default());
+
+        primary.scroll_by(isize::MAX);
+        assert_eq!(primary.scroll, 5);
+        primary.scroll_by(1);
+        assert_eq!(primary.scroll, 5);
+
+        primary.scroll_by(-2);
+        assert_eq!(primary.scroll, 3);
+        primary.scroll_by(isize::MIN);
+        assert_eq!(primary.scroll, 0);
+        primary.scroll_by(-1);
+        assert_eq!(primary.scroll, 0);
+    }
+
+    #[test]
+    fn unseen_messages_are_cleared_at_the_newest_lines() {
+        let primary = &mut primary(&ClientState::default());
+        primary.scroll = 3;
+        primary.unseen = 2;
+
+        primary.scroll_by(-2);
+        assert_eq!((primary.scroll, primary.unseen), (1, 2));
+
+        primary.scroll_by(-1);
+        assert_eq!((primary.scroll, primary.unseen), (0, 0));
+    }
+
+    #[test]
+    fn update_keeps_a_scrolled_view_in_place() {
+        let mut state = ClientState::default();
+        state.push_notification(notification("older"));
+        let mut primary = primary(&state);
+        primary.scroll_by(2);
+
+        // Wrapped over two lines, only chat messages are unseen
+        state.push_notification(notification("aaaa bbbb cccc dddd eeee"));
+        state.push_notification(TextType::RoomMessage {
+            message: chat("hi"),
+        });
+        state.push_notification(TextType::PrivateMessage {
+            outgoing: false,
+            message: chat("hey"),
+        });
+        state.push_notification(TextType::Error {
+            text: String::from("[-] oops"),
+        });
+
+        let primary = primary.update(&state);
+        assert_eq!((primary.scroll, primary.unseen), (7, 2));
+
+        // Nothing new, nothing moves
+        let primary = primary.update(&state);
+        assert_eq!((primary.scroll, primary.unseen), (7, 2));
+    }
+
+    #[test]
+    fn update_follows_the_newest_lines_otherwise() {
+        let mut state = ClientState::default();
+        let primary = primary(&state);
+
+        state.push_notification(TextType::RoomMessage {
+            message: chat("hi"),
+        });
+        let mut primary = primary.update(&state);
+        assert_eq!((primary.scroll, primary.unseen), (0, 0));
+
+        // Switching buffers goes back to the newest lines
+        primary.scroll_by(2);
+        state.focus_buffer(BufferId::Room(String::from("main")));
+        let primary = primary.update(&state);
+        assert_eq!((primary.scroll, primary.unseen), (0, 0));
+    }
+
+    #[test]
+    fn wrap_breaks_between_words() {
+        assert_eq!(wrap("hello world foo", 11, 0), vec!["hello world", "foo"]);
+        assert_eq!(wrap("short", 20, 0), vec!["short"]);
+    }
+
+    #[test]
+    fn wrap_splits_long_words_by_grapheme() {
+        assert_eq!(wrap("abcdefgh", 3, 0), vec!["abc", "def", "gh"]);
+        assert_eq!(wrap("ab abcdefgh", 4, 0), vec!["ab", "abcd", "efgh"]);
+
+        // Combining accents stay with the letter they belong to
+        assert_eq!(
+            wrap("e\u{301}e\u{301}e\u{301}", 2, 0),
+            vec!["e\u{301}e\u{301}", "e\u{301}"]
+        );
+    }
+
+    #[test]
+    fn wrap_counts_wide_characters_as_two_columns() {
+        assert_eq!(wrap("你好世界", 5, 0), vec!["你好", "世界"]);
+        assert_eq!(wrap("a 你好", 4, 0), vec!["a", "你好"]);
+    }
+
+    #[test]
+    fn wrap_hangs_lines_under_the_prefix() {
+        assert_eq!(
+            wrap("ab: one two three", 10, 4),
+            vec!["ab: one", "    two", "    three"]
+        );
+    }
+
+    #[test]
+    fn wrap_drops_the_indent_on_narrow_panes() {
+        assert_eq!(wrap("ab: one two", 6, 4), vec!["ab:", "one", "two"]);
+    }
+
+    #[test]
+    fn wrap_keeps_embedded_newlines() {
+        assert_eq!(wrap("ab: one\ntwo", 20, 4), vec!["ab: one", "    two"]);
+        assert_eq!(wrap("a\n\nb", 10, 0), vec!["a", "", "b"]);
+    }
+}
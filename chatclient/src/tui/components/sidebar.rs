@@ -0,0 +1,128 @@
+use super::component::{Component, ComponentRender, RenderProps};
+use crate::state_handler::{Action, BufferId, ClientState};
+
+use common::message::{Member, Presence};
+use crossterm::event::KeyEvent;
+use ratatui::{
+    prelude::*,
+    widgets::{Block, Borders, List},
+    Frame,
+};
+use tokio::sync::mpsc::UnboundedSender;
+
+struct RoomEntry {
+    label: String,
+    unread: bool,
+    active: bool,
+}
+
+// Side panel listing the joined rooms, marking those with unread
+// messages, and the members of the room being looked at
+pub struct Sidebar {
+    rooms: Vec<RoomEntry>,
+    members: Option<Vec<Member>>,
+}
+
+impl Sidebar {
+    fn from_state(state: &ClientState) -> Self {
+        let rooms = state
+            .buffers
+            .iter()
+            .filter(|buffer| matches!(buffer.id, BufferId::Room(_)))
+            .map(|buffer| RoomEntry {
+                label: buffer.id.label(),
+                unread: buffer.unread > 0,
+                active: buffer.id == state.active_buffer,
+            })
+            .collect::<Vec<RoomEntry>>();
+
+        let members = state
+            .buffers
+            .iter()
+            .find(|buffer| buffer.id == state.active_buffer)
+            .filter(|buffer| matches!(buffer.id, BufferId::Room(_)))
+            .map(|buffer| buffer.members.clone());
+
+        Self { rooms, members }
+    }
+}
+
+impl Component for Sidebar {
+    fn new(state: &ClientState, _action_tx: UnboundedSender<Action>) -> Self
+    where
+        Self: Sized,
+    {
+        Self::from_state(state)
+    }
+
+    fn update(self, state: &ClientState) -> Self
+    where
+        Self: Sized,
+    {
+        Self::from_state(state)
+    }
+
+    fn handle_key_event(&mut self, _key: KeyEvent) {}
+}
+
+impl ComponentRender<RenderProps> for Sidebar {
+    fn render(&self, frame: &mut Frame, props: RenderProps) {
+        // Rooms take up to half of the panel, members the rest
+        let rooms_height = (self.rooms.len() as u16 + 2).min(props.area.height / 2);
+        let layout = Layout::default()
+            .constraints([Constraint::Length(rooms_height), Constraint::Min(0)])
+            .split(props.area);
+
+        let rooms = List::new(
+            self.rooms
+                .iter()
+                .map(|room| {
+                    let marker = match room.unread {
+                        true => "* ",
+                        false => "  ",
+                    };
+                    let style = match room.active {
+                        true => Style::new()
+                            .fg(Color::White)
+                            .add_modifier(Modifier::BOLD | Modifier::REVERSED),
+                        false => Style::new().fg(Color::White),
+                    };
+
+                    Line::from(format!("{marker}{0}", room.label)).style(style)
+                })
+                .collect::<Vec<_>>(),
+        )
+        .block(
+            Block::default()
+                .title("Rooms")
+                .borders(Borders::ALL)
+                .fg(props.border_color),
+        );
+
+        frame.render_widget(rooms, layout[0]);
+
+        let Some(members) = &self.members else {
+            return;
+        };
+
+        let list = List::new(
+            members
+                .iter()
+                .map(|member| match member.presence {
+                    Presence::Online => Line::from(format!("● {0}", member.username))
+                        .style(Style::new().fg(Color::Green)),
+                    Presence::Away => Line::from(format!("○ {0}", member.username))
+                        .style(Style::new().fg(Color::DarkGray)),
+                })
+                .collect::<Vec<_>>(),
+        )
+        .block(
+            Block::default()
+                .title(format!("Members ({0})", members.len()))
+                .borders(Borders::ALL)
+                .fg(props.border_color),
+        );
+
+        frame.render_widget(list, layout[1]);
+    }
+}
@@ -2,10 +2,11 @@ pub mod app_router;
 pub mod components;
 
 use crate::state_handler::Action;
+use common::message::ChatMessage;
 
 use color_eyre::eyre::Result;
 use crossterm::{
-    event::{DisableMouseCapture, EnableMouseCapture, KeyEvent},
+    event::{DisableMouseCapture, EnableMouseCapture, KeyEvent, MouseEvent, MouseEventKind},
     execute,
     terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
 };
@@ -18,11 +19,22 @@ use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
 // printed out
 #[derive(Clone)]
 pub enum TextType {
-    Notification { text: String },
-    Error { text: String },
-    RoomMessage { text: String },
-    PrivateMessage { text: String },
-    Listing { text: String },
+    Notification {
+        text: String,
+    },
+    Error {
+        text: String,
+    },
+    RoomMessage {
+        message: ChatMessage,
+    },
+    PrivateMessage {
+        outgoing: bool,
+        message: ChatMessage,
+    },
+    Listing {
+        text: String,
+    },
 }
 
 pub struct Tui {
@@ -34,6 +46,7 @@ pub enum Event {
     Error,
     Tick,
     Key(KeyEvent),
+    Mouse(MouseEvent),
 }
 
 pub struct EventHandler {
@@ -57,13 +70,22 @@ impl EventHandler {
                 tokio::select! {
                     maybe_event = crossterm_event => {
                         match maybe_event {
-                            Some(Ok(event)) => {
-                                if let crossterm::event::Event::Key(key) = event {
-                                    if key.kind == crossterm::event::KeyEventKind::Press {
-                                        tx.send(Event::Key(key)).unwrap();
-                                    }
-                                }
+                            Some(Ok(crossterm::event::Event::Key(key)))
+                                if key.kind == crossterm::event::KeyEventKind::Press =>
+                            {
+                                tx.send(Event::Key(key)).unwrap();
                             }
+                            // Only the wheel is used, mouse movements
+                            // would just cause needless redraws
+                            Some(Ok(crossterm::event::Event::Mouse(mouse)))
+                                if matches!(
+                                    mouse.kind,
+                                    MouseEventKind::ScrollUp | MouseEventKind::ScrollDown
+                                ) =>
+                            {
+                                tx.send(Event::Mouse(mouse)).unwrap();
+                            }
+                            Some(Ok(_)) => {}
                             Some(Err(_)) => {
                                 tx.send(Event::Error).unwrap();
                             }
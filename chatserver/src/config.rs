@@ -0,0 +1,818 @@
+use anyhow::{anyhow, Context, Result};
+use clap::Parser;
+use common::connection::TransportKind;
+use common::validation::{name_key, Limits, MAX_PASSWORD_LENGTH};
+use log::LevelFilter;
+use serde::Deserialize;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::room::RoomConfig;
+
+// Command line options. Anything given here takes
+// precedence over the configuration file
+#[derive(Parser, Debug)]
+pub struct ServerConfig {
+    // TOML file to read the configuration from
+    #[arg(short, long)]
+    config: Option<PathBuf>,
+
+    // Port to listen on, on all IPv4 interfaces
+    #[arg(short, long)]
+    port: Option<u16>,
+
+    // Address to listen on as host:port, optionally prefixed with the
+    // transport (ws://, tcp://, or wss:// and tls:// for TLS). May be
+    // given several times
+    #[arg(short, long)]
+    listen: Vec<String>,
+
+    // Transport used for listeners which don't name one: ws or tcp
+    #[arg(short, long)]
+    transport: Option<TransportKind>,
+
+    // Longest user or room name accepted, in characters
+    #[arg(long)]
+    max_name_length: Option<usize>,
+
+    // Longest message body accepted, in characters
+    #[arg(long)]
+    max_message_length: Option<usize>,
+
+    // Characters allowed in names besides letters and digits
+    #[arg(long)]
+    name_symbols: Option<String>,
+
+    // Shortest account password accepted, in characters
+    #[arg(long)]
+    min_password_length: Option<usize>,
+
+    // Only let users in after logging in to an account
+    #[arg(long)]
+    no_guests: bool,
+
+    // Seconds the session of a lost connection is kept for the
+    // client to resume it. 0 disables resuming
+    #[arg(long)]
+    resume_grace: Option<u64>,
+
+    // Seconds between pings sent to every connection
+    #[arg(long)]
+    ping_interval: Option<u64>,
+
+    // Seconds without hearing from a connection before it is dropped.
+    // Clients wait as long by default before reconnecting, their
+    // timeout should not be any shorter
+    #[arg(long)]
+    ping_timeout: Option<u64>,
+
+    // Seconds given to new connections to register or log in
+    #[arg(long)]
+    register_timeout: Option<u64>,
+
+    // Number of messages kept per room
+    #[arg(long)]
+    history_size: Option<usize>,
+
+    // Number of recent messages sent to users joining a room
+    #[arg(long)]
+    join_backlog: Option<usize>,
+
+    // Messages buffered per room before slow readers miss some
+    #[arg(long)]
+    room_capacity: Option<usize>,
+
+    // SQLite database keeping rooms, accounts and history across
+    // restarts. Nothing is kept if not set
+    #[arg(short, long)]
+    database: Option<PathBuf>,
+
+    // Message shown to users once connected
+    #[arg(long)]
+    motd: Option<String>,
+
+    // Seconds given to connections to close when shutting down,
+    // after which they are dropped
+    #[arg(long)]
+    shutdown_timeout: Option<u64>,
+
+    // PEM file with the certificate chain presented on TLS listeners
+    #[arg(long)]
+    tls_cert: Option<PathBuf>,
+
+    // PEM file with the private key of the certificate
+    #[arg(long)]
+    tls_key: Option<PathBuf>,
+
+    // Least severe log messages shown: error, warn, info, debug or
+    // trace. RUST_LOG takes precedence when set
+    #[arg(long)]
+    log_level: Option<String>,
+}
+
+// Layout of the configuration file, where every setting is optional
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+struct FileConfig {
+    listen: Vec<String>,
+    transport: Option<String>,
+    database: Option<PathBuf>,
+    motd: Option<String>,
+    log_level: Option<String>,
+    allow_guests: Option<bool>,
+    resume_grace: Option<u64>,
+    rooms: FileRooms,
+    limits: FileLimits,
+    tls: FileTls,
+    shutdown: FileShutdown,
+    heartbeat: FileHeartbeat,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+struct FileRooms {
+    default: Option<Vec<String>>,
+    history_size: Option<usize>,
+    join_backlog: Option<usize>,
+    capacity: Option<usize>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+struct FileShutdown {
+    timeout: Option<u64>,
+    reconnect_after: Option<u64>,
+    reason: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+struct FileHeartbeat {
+    interval: Option<u64>,
+    timeout: Option<u64>,
+    register_timeout: Option<u64>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+struct FileTls {
+    cert: Option<PathBuf>,
+    key: Option<PathBuf>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+struct FileLimits {
+    max_name_length: Option<usize>,
+    max_message_length: Option<usize>,
+    name_symbols: Option<String>,
+    min_password_length: Option<usize>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Listener {
+    pub address: SocketAddr,
+    pub transport: TransportKind,
+    // Whether connections start with a TLS handshake
+    pub tls: bool,
+}
+
+impl Listener {
+    // Parses host:port, optionally prefixed with the transport to use
+    fn parse(spec: &str, transport: TransportKind) -> Result<Self> {
+        let (transport, tls, address) = if let Some(address) = spec.strip_prefix("tcp://") {
+            (TransportKind::Tcp, false, address)
+        } else if let Some(address) = spec.strip_prefix("tls://") {
+            (TransportKind::Tcp, true, address)
+        } else if let Some(address) = spec.strip_prefix("ws://") {
+            (TransportKind::WebSocket, false, address)
+        } else if let Some(address) = spec.strip_prefix("wss://") {
+            (TransportKind::WebSocket, true, address)
+        } else {
+            (transport, false, spec)
+        };
+
+        let address = address.parse::<SocketAddr>().map_err(|_| {
+            anyhow!(
+                "Invalid listen address \"{spec}\" (expected ip:port such as 0.0.0.0:6777 \
+                 or [::]:6777, optionally prefixed with ws://, wss://, tcp:// or tls://)"
+            )
+        })?;
+
+        Ok(Listener {
+            address,
+            transport,
+            tls,
+        })
+    }
+}
+
+// Certificate and private key used by TLS listeners
+#[derive(Debug)]
+pub struct TlsFiles {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+// What clients are told when the server shuts down,
+// and how long they get to go away
+#[derive(Debug)]
+pub struct Shutdown {
+    pub timeout: Duration,
+    pub reconnect_after: Duration,
+    pub reason: String,
+}
+
+// How often connections are pinged, how long they may stay
+// silent before being considered gone, and how long new ones
+// get to register
+#[derive(Clone, Copy, Debug)]
+pub struct Heartbeat {
+    pub interval: Duration,
+    pub timeout: Duration,
+    pub register_timeout: Duration,
+}
+
+// Settings the server runs with, once the command line
+// and configuration file have been merged and checked
+#[derive(Debug)]
+pub struct Config {
+    pub listeners: Vec<Listener>,
+    pub limits: Limits,
+    pub room_config: RoomConfig,
+    // Rooms which always exist, created at startup if needed
+    pub default_rooms: Vec<String>,
+    pub allow_guests: bool,
+    pub resume_grace: Duration,
+    pub heartbeat: Heartbeat,
+    pub database: Option<PathBuf>,
+    pub motd: Option<String>,
+    pub tls: Option<TlsFiles>,
+    pub shutdown: Shutdown,
+    pub log_level: LevelFilter,
+}
+
+impl Config {
+    pub fn load(args: ServerConfig) -> Result<Self> {
+        let file = match &args.config {
+            Some(path) => read_file(path)?,
+            None => FileConfig::default(),
+        };
+
+        let transport = match args.transport {
+            Some(transport) => transport,
+            None => file
+                .transport
+                .as_deref()
+                .map(TransportKind::from_str)
+                .transpose()
+                .context("Invalid transport in configuration file")?
+                .unwrap_or(TransportKind::WebSocket),
+        };
+
+        // Listeners on the command line replace those of the file
+        let mut specs = match args.listen.is_empty() && args.port.is_none() {
+            true => file.listen,
+            false => args.listen,
+        };
+        if let Some(port) = args.port {
+            specs.push(format!("0.0.0.0:{port}"));
+        }
+
+        let mut listeners: Vec<Listener> = Vec::new();
+        for spec in &specs {
+            let listener = Listener::parse(spec, transport)?;
+
+            if listeners
+                .iter()
+                .any(|known| known.address == listener.address)
+            {
+                return Err(anyhow!(
+                    "Address {0} is listened on twice",
+                    listener.address
+                ));
+            }
+
+            listeners.push(listener);
+        }
+
+        if listeners.is_empty() {
+            return Err(anyhow!(
+                "No address to listen on, set one with --port, --listen \
+                 or `listen` in the configuration file"
+            ));
+        }
+
+        let defaults = Limits::default();
+        let limits = Limits {
+            max_name_length: args
+                .max_name_length
+                .or(file.limits.max_name_length)
+                .unwrap_or(defaults.max_name_length),
+            max_message_length: args
+                .max_message_length
+                .or(file.limits.max_message_length)
+                .unwrap_or(defaults.max_message_length),
+            name_symbols: args
+                .name_symbols
+                .or(file.limits.name_symbols)
+                .unwrap_or(defaults.name_symbols),
+            min_password_length: args
+                .min_password_length
+                .or(file.limits.min_password_length)
+                .unwrap_or(defaults.min_password_length),
+        };
+
+        let room_config = RoomConfig {
+            history_size: args.history_size.or(file.rooms.history_size).unwrap_or(200),
+            join_backlog: args.join_backlog.or(file.rooms.join_backlog).unwrap_or(20),
+            channel_capacity: args.room_capacity.or(file.rooms.capacity).unwrap_or(256),
+        };
+
+        let log_level = match args.log_level.or(file.log_level) {
+            Some(level) => LevelFilter::from_str(&level).map_err(|_| {
+                anyhow!(
+                    "Invalid log level \"{level}\" (expected off, error, warn, info, debug or trace)"
+                )
+            })?,
+            None => LevelFilter::Info,
+        };
+
+        let tls = match (
+            args.tls_cert.or(file.tls.cert),
+            args.tls_key.or(file.tls.key),
+        ) {
+            (Some(cert), Some(key)) => Some(TlsFiles { cert, key }),
+            (None, None) => None,
+            (Some(_), None) => return Err(anyhow!("A TLS certificate needs its private key")),
+            (None, Some(_)) => return Err(anyhow!("A TLS private key needs its certificate")),
+        };
+
+        let config = Config {
+            listeners,
+            limits,
+            room_config,
+            default_rooms: file
+                .rooms
+                .default
+                .unwrap_or_else(|| vec![String::from("main")]),
+            allow_guests: !args.no_guests && file.allow_guests.unwrap_or(true),
+            resume_grace: Duration::from_secs(
+                args.resume_grace.or(file.resume_grace).unwrap_or(60),
+            ),
+            heartbeat: Heartbeat {
+                interval: Duration::from_secs(
+                    args.ping_interval.or(file.heartbeat.interval).unwrap_or(30),
+                ),
+                timeout: Duration::from_secs(
+                    args.ping_timeout.or(file.heartbeat.timeout).unwrap_or(90),
+                ),
+                register_timeout: Duration::from_secs(
+                    args.register_timeout
+                        .or(file.heartbeat.register_timeout)
+                        .unwrap_or(30),
+                ),
+            },
+            database: args.database.or(file.database),
+            motd: args.motd.or(file.motd),
+            tls,
+            shutdown: Shutdown {
+                timeout: Duration::from_secs(
+                    args.shutdown_timeout
+                        .or(file.shutdown.timeout)
+                        .unwrap_or(10),
+                ),
+                reconnect_after: Duration::from_secs(file.shutdown.reconnect_after.unwrap_or(5)),
+                reason: file
+                    .shutdown
+                    .reason
+                    .unwrap_or_else(|| String::from("Server is shutting down")),
+            },
+            log_level,
+        };
+
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    // Catches settings the server could not sensibly run with
+    fn validate(&self) -> Result<()> {
+        let limits = &self.limits;
+
+        if limits.max_name_length == 0 {
+            return Err(anyhow!("max_name_length has to be at least 1"));
+        }
+
+        if limits.max_message_length == 0 {
+            return Err(anyhow!("max_message_length has to be at least 1"));
+        }
+
+        if limits.min_password_length > MAX_PASSWORD_LENGTH {
+            return Err(anyhow!(
+                "min_password_length can be at most {MAX_PASSWORD_LENGTH}"
+            ));
+        }
+
+        // Names are typed as a single word in commands
+        if let Some(symbol) = limits
+            .name_symbols
+            .chars()
+            .find(|c| c.is_whitespace() || c.is_control())
+        {
+            return Err(anyhow!(
+                "name_symbols can't contain {symbol:?}, only visible characters are allowed"
+            ));
+        }
+
+        let heartbeat = &self.heartbeat;
+
+        if heartbeat.interval.is_zero() {
+            return Err(anyhow!("The ping interval has to be at least 1 second"));
+        }
+
+        // Otherwise idle but healthy connections would be dropped
+        // before they are even pinged
+        if heartbeat.timeout <= heartbeat.interval {
+            return Err(anyhow!(
+                "The ping timeout ({0}s) has to be longer than the ping interval ({1}s)",
+                heartbeat.timeout.as_secs(),
+                heartbeat.interval.as_secs()
+            ));
+        }
+
+        if heartbeat.register_timeout.is_zero() {
+            return Err(anyhow!("The register timeout has to be at least 1 second"));
+        }
+
+        let tls_listener = self.listeners.iter().find(|listener| listener.tls);
+
+        match (tls_listener, &self.tls) {
+            (Some(listener), None) => {
+                return Err(anyhow!(
+                    "Listening with TLS at {0} needs a certificate and private key, \
+                     set them with --tls-cert and --tls-key or in the [tls] section",
+                    listener.address
+                ));
+            }
+            // Most likely the listeners were meant to be prefixed
+            (None, Some(_)) => {
+                return Err(anyhow!(
+                    "A TLS certificate is set but no listener uses TLS, \
+                     prefix their addresses with wss:// or tls://"
+                ));
+            }
+            _ => {}
+        }
+
+        // Caught here rather than once the first listener is set up
+        if let Some(files) = &self.tls {
+            for (file, path) in [("certificate", &files.cert), ("private key", &files.key)] {
+                if !path.is_file() {
+                    return Err(anyhow!("TLS {file} {0} does not exist", path.display()));
+                }
+            }
+        }
+
+        let rooms = &self.room_config;
+
+        if rooms.channel_capacity == 0 {
+            return Err(anyhow!("Room capacity has to be at least 1"));
+        }
+
+        if rooms.join_backlog > rooms.history_size {
+            return Err(anyhow!(
+                "join_backlog ({0}) can't be larger than history_size ({1})",
+                rooms.join_backlog,
+                rooms.history_size
+            ));
+        }
+
+        if self.default_rooms.is_empty() {
+            return Err(anyhow!("At least one default room is needed"));
+        }
+
+        let mut keys = Vec::new();
+        for room in &self.default_rooms {
+            let name = limits
+                .validate_name(room)
+                .map_err(|code| anyhow!("Invalid default room \"{room}\": {code}"))?;
+
+            if keys.contains(&name_key(&name)) {
+                return Err(anyhow!("Default room \"{room}\" is listed twice"));
+            }
+
+            keys.push(name_key(&name));
+        }
+
+        Ok(())
+    }
+}
+
+fn read_file(path: &Path) -> Result<FileConfig> {
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read configuration file {}", path.display()))?;
+
+    toml::from_str::<FileConfig>(&text)
+        .with_context(|| format!("Invalid configuration file {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // File removed again once the test is done
+    struct TempFile(PathBuf);
+
+    impl TempFile {
+        fn new(name: &str, contents: &str) -> Self {
+            let path =
+                std::env::temp_dir().join(format!("chatserver-{0}-{name}", std::process::id()));
+            std::fs::write(&path, contents).unwrap();
+
+            TempFile(path)
+        }
+
+        fn path(&self) -> &str {
+            self.0.to_str().unwrap()
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    fn load(args: &[&str]) -> Result<Config> {
+        Config::load(ServerConfig::parse_from(
+            std::iter::once("chatserver").chain(args.iter().copied()),
+        ))
+    }
+
+    // Loads the configuration file along with the given arguments
+    fn load_file(name: &str, toml: &str, args: &[&str]) -> Result<Config> {
+        let file = TempFile::new(name, toml);
+
+        load(&[&["-c", file.path()], args].concat())
+    }
+
+    fn error(config: Result<Config>) -> String {
+        format!("{:#}", config.unwrap_err())
+    }
+
+    #[test]
+    fn defaults() {
+        let config = load(&["--port", "6777"]).unwrap();
+
+        assert_eq!(
+            config.listeners,
+            vec![Listener {
+                address: "0.0.0.0:6777".parse().unwrap(),
+                transport: TransportKind::WebSocket,
+                tls: false,
+            }]
+        );
+        assert_eq!(config.limits, Limits::default());
+        assert_eq!(config.default_rooms, vec![String::from("main")]);
+        assert!(config.allow_guests);
+        assert_eq!(config.resume_grace, Duration::from_secs(60));
+        assert_eq!(config.heartbeat.interval, Duration::from_secs(30));
+        assert_eq!(config.heartbeat.timeout, Duration::from_secs(90));
+        assert_eq!(config.log_level, LevelFilter::Info);
+        assert!(config.tls.is_none());
+    }
+
+    #[test]
+    fn reads_file() {
+        let config = load_file(
+            "reads_file.toml",
+            r#"
+                listen = ["tcp://127.0.0.1:7000", "[::1]:7001"]
+                transport = "tcp"
+                database = "chat.db"
+                motd = "Welcome!"
+                log_level = "debug"
+                allow_guests = false
+                resume_grace = 0
+
+                [rooms]
+                default = ["lobby", "rust"]
+                history_size = 50
+                join_backlog = 5
+                capacity = 16
+
+                [limits]
+                max_name_length = 16
+                name_symbols = "-,"
+
+                [heartbeat]
+                interval = 10
+                timeout = 20
+
+                [shutdown]
+                timeout = 3
+                reconnect_after = 7
+                reason = "Maintenance"
+            "#,
+            &[],
+        )
+        .unwrap();
+
+        let addresses = config
+            .listeners
+            .iter()
+            .map(|listener| (listener.address.to_string(), listener.transport))
+            .collect::<Vec<(String, TransportKind)>>();
+        assert_eq!(
+            addresses,
+            vec![
+                (String::from("127.0.0.1:7000"), TransportKind::Tcp),
+                (String::from("[::1]:7001"), TransportKind::Tcp),
+            ]
+        );
+        assert_eq!(config.database, Some(PathBuf::from("chat.db")));
+        assert_eq!(config.motd.as_deref(), Some("Welcome!"));
+        assert_eq!(config.log_level, LevelFilter::Debug);
+        assert!(!config.allow_guests);
+        assert!(config.resume_grace.is_zero());
+        assert_eq!(config.default_rooms, vec!["lobby", "rust"]);
+        assert_eq!(config.room_config.history_size, 50);
+        assert_eq!(config.room_config.join_backlog, 5);
+        assert_eq!(config.room_config.channel_capacity, 16);
+        assert_eq!(config.limits.max_name_length, 16);
+        assert_eq!(config.limits.name_symbols, "-,");
+        assert_eq!(
+            config.limits.max_message_length,
+            Limits::default().max_message_length
+        );
+        assert_eq!(config.heartbeat.interval, Duration::from_secs(10));
+        assert_eq!(config.heartbeat.timeout, Duration::from_secs(20));
+        assert_eq!(config.shutdown.timeout, Duration::from_secs(3));
+        assert_eq!(config.shutdown.reconnect_after, Duration::from_secs(7));
+        assert_eq!(config.shutdown.reason, "Maintenance");
+    }
+
+    #[test]
+    fn command_line_takes_precedence() {
+        let toml = r#"
+            listen = ["127.0.0.1:7000"]
+            motd = "From the file"
+            allow_guests = true
+
+            [limits]
+            max_name_length = 16
+            min_password_length = 12
+        "#;
+
+        let config = load_file(
+            "precedence.toml",
+            toml,
+            &[
+                "--listen",
+                "tcp://127.0.0.1:8000",
+                "--max-name-length",
+                "20",
+                "--motd",
+                "From the command line",
+                "--no-guests",
+            ],
+        )
+        .unwrap();
+
+        // Listeners on the command line replace those of the file
+        assert_eq!(config.listeners.len(), 1);
+        assert_eq!(config.listeners[0].address.port(), 8000);
+        assert_eq!(config.limits.max_name_length, 20);
+        assert_eq!(config.motd.as_deref(), Some("From the command line"));
+        assert!(!config.allow_guests);
+        // Settings missing from the command line come from the file
+        assert_eq!(config.limits.min_password_length, 12);
+
+        let config = load_file("precedence_port.toml", toml, &["--port", "9000"]).unwrap();
+        assert_eq!(config.listeners.len(), 1);
+        assert_eq!(config.listeners[0].address.port(), 9000);
+    }
+
+    #[test]
+    fn rejects_invalid_files() {
+        let unknown = load_file("unknown.toml", "listen = []\nlisten_on = 1", &[]);
+        assert!(error(unknown).contains("unknown field"));
+
+        let syntax = load_file("syntax.toml", "listen = [", &[]);
+        assert!(error(syntax).contains("Invalid configuration file"));
+
+        let missing = load(&["-c", "/nonexistent/chatserver.toml"]);
+        assert!(error(missing).contains("Failed to read configuration file"));
+
+        let transport = load_file("transport.toml", "transport = \"udp\"", &["--port", "1"]);
+        assert!(error(transport).contains("Invalid transport"));
+    }
+
+    #[test]
+    fn rejects_bad_listeners() {
+        assert!(error(load(&[])).contains("No address to listen on"));
+
+        let duplicate = load(&["-l", "ws://127.0.0.1:7000", "-l", "tcp://127.0.0.1:7000"]);
+        assert!(error(duplicate).contains("listened on twice"));
+
+        let invalid = load(&["-l", "localhost"]);
+        assert!(error(invalid).contains("Invalid listen address"));
+    }
+
+    #[test]
+    fn rejects_bad_tls_settings() {
+        let cert = TempFile::new("cert.pem", "");
+        let key = TempFile::new("key.pem", "");
+
+        let config = load(&[
+            "-l",
+            "tls://127.0.0.1:7000",
+            "--tls-cert",
+            cert.path(),
+            "--tls-key",
+            key.path(),
+        ])
+        .unwrap();
+        assert!(config.listeners[0].tls);
+        assert!(config.tls.is_some());
+
+        let no_files = load(&["-l", "wss://127.0.0.1:7000"]);
+        assert!(error(no_files).contains("needs a certificate and private key"));
+
+        let no_key = load(&["-l", "wss://127.0.0.1:7000", "--tls-cert", cert.path()]);
+        assert!(error(no_key).contains("needs its private key"));
+
+        let no_cert = load(&["-l", "wss://127.0.0.1:7000", "--tls-key", key.path()]);
+        assert!(error(no_cert).contains("needs its certificate"));
+
+        let unused = load(&[
+            "-l",
+            "ws://127.0.0.1:7000",
+            "--tls-cert",
+            cert.path(),
+            "--tls-key",
+            key.path(),
+        ]);
+        assert!(error(unused).contains("no listener uses TLS"));
+
+        let missing = load(&[
+            "-l",
+            "wss://127.0.0.1:7000",
+            "--tls-cert",
+            "/nonexistent/cert.pem",
+            "--tls-key",
+            key.path(),
+        ]);
+        assert!(error(missing).contains("TLS certificate /nonexistent/cert.pem does not exist"));
+    }
+
+    #[test]
+    fn rejects_bad_limits() {
+        let port = ["--port", "7000"];
+
+        for symbols in [" ", "-\t", "\u{7}"] {
+            let symbols = format!("--name-symbols={symbols}");
+            let config = load(&[&port[..], &[symbols.as_str()]].concat());
+            assert!(error(config).contains("name_symbols can't contain"));
+        }
+
+        let config = load(&[&port[..], &["--max-name-length", "0"]].concat());
+        assert!(error(config).contains("max_name_length"));
+
+        let config = load(&[&port[..], &["--min-password-length", "5000"]].concat());
+        assert!(error(config).contains("min_password_length"));
+    }
+
+    #[test]
+    fn rejects_bad_heartbeat_and_rooms() {
+        let port = ["--port", "7000"];
+
+        let config = load(
+            &[
+                &port[..],
+                &["--ping-interval", "30", "--ping-timeout", "30"],
+            ]
+            .concat(),
+        );
+        assert!(error(config).contains("has to be longer than the ping interval"));
+
+        let config = load(&[&port[..], &["--history-size", "10", "--join-backlog", "20"]].concat());
+        assert!(error(config).contains("can't be larger than history_size"));
+
+        let config = load_file(
+            "rooms.toml",
+            "[rooms]\ndefault = [\"Main\", \"main\"]",
+            &port,
+        );
+        assert!(error(config).contains("listed twice"));
+
+        let config = load_file("no_rooms.toml", "[rooms]\ndefault = []", &port);
+        assert!(error(config).contains("At least one default room"));
+
+        let config = load(&[&port[..], &["--log-level", "loud"]].concat());
+        assert!(error(config).contains("Invalid log level"));
+    }
+}
@@ -1,41 +1,44 @@
 #![warn(clippy::all)]
 
+mod config;
 mod room;
 mod server;
+mod storage;
 
 use anyhow::{anyhow, Result};
 use clap::Parser;
-use log::{error, info};
+use config::{Config, ServerConfig};
+use log::{error, info, LevelFilter};
 
 use server::Server;
-
-#[derive(Parser, Debug)]
-struct ServerConfig {
-    // Port to listening on
-    #[arg(short, long)]
-    port: u64,
-}
-
-// Set RUST_LOG if not already set
-fn init_logging() {
-    match std::env::var("RUST_LOG") {
-        Ok(_) => {}
-        Err(_) => {
-            std::env::set_var("RUST_LOG", "info");
-        }
-    };
-    env_logger::init();
+use storage::{MemoryStorage, SqliteStorage, Storage};
+
+// RUST_LOG takes precedence over the configured level
+fn init_logging(level: LevelFilter) {
+    env_logger::Builder::new()
+        .filter_level(level)
+        .parse_env("RUST_LOG")
+        .init();
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    init_logging();
+    // Get server config from cli arguments and the config file
+    let config = Config::load(ServerConfig::parse())?;
 
-    // Get server config from cli arguments
-    let config = ServerConfig::parse();
+    init_logging(config.log_level);
 
     info!("[*] Starting server");
-    let mut server = Server::new(config.port);
+
+    let storage: Box<dyn Storage> = match &config.database {
+        Some(path) => {
+            info!("[*] Using database {}", path.display());
+            Box::new(SqliteStorage::open(path)?)
+        }
+        None => Box::new(MemoryStorage::new()),
+    };
+
+    let mut server = Server::new(config, storage)?;
 
     match server.start().await {
         Ok(()) => {}
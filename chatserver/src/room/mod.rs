@@ -1,47 +1,283 @@
 pub mod room_manager;
 
 use anyhow::{anyhow, Result};
-use common::message::Message;
+use common::history::History;
+use common::message::{ChatMessage, Member, Message, MessageType};
+use common::validation::name_key;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
 use tokio::sync::broadcast::{self};
 
+#[derive(Clone, Copy, Debug)]
+pub struct RoomConfig {
+    // Number of messages each room keeps around
+    pub history_size: usize,
+    // Number of those sent to users joining the room
+    pub join_backlog: usize,
+    // Messages buffered for members before slow ones start lagging
+    pub channel_capacity: usize,
+}
+
 pub struct Room {
     pub name: String,
     broadcast_tx: broadcast::Sender<Message>,
+    history: History,
+    join_backlog: usize,
+    // Members keyed by session id
+    members: HashMap<u64, Member>,
 }
 
 impl Room {
-    pub fn new(name: &str) -> Self {
-        let (broadcast_tx, _) = broadcast::channel::<Message>(10);
+    pub fn new(name: &str, config: RoomConfig) -> Self {
+        let (broadcast_tx, _) = broadcast::channel::<Message>(config.channel_capacity);
 
         Room {
             name: name.to_owned(),
             broadcast_tx,
+            history: History::new(config.history_size),
+            join_backlog: config.join_backlog,
+            members: HashMap::new(),
         }
     }
 
-    pub fn join(&mut self) -> (broadcast::Receiver<Message>, UserHandle) {
-        let broadcast_tx = self.broadcast_tx.clone();
-        let broadcast_rx = self.broadcast_tx.subscribe();
+    // Refills the history, used when loading the room from storage
+    pub fn restore(&mut self, messages: Vec<ChatMessage>) {
+        for message in messages {
+            self.history.push(message);
+        }
+    }
+
+    // Adds the session to the members and subscribes to the room, along
+    // with the current backlog and members. All are taken under the room
+    // lock so no message or membership change is missed or sent twice
+    pub fn join(&mut self, member: Member, handle: UserHandle) -> Membership {
+        let message = Message::build(
+            MessageType::MemberJoined {
+                room: self.name.clone(),
+                member: member.clone(),
+            },
+            handle.id,
+        );
+        let _ = self.broadcast_tx.send(message);
+
+        self.members.insert(handle.id, member);
+
+        Membership {
+            broadcast_rx: self.broadcast_tx.subscribe(),
+            backlog: self.history.latest(self.join_backlog),
+            last_id: self.history.last_id(),
+            members: self.members(),
+            handle,
+        }
+    }
 
-        let user_handle = UserHandle::new(broadcast_tx);
+    // Members sorted by name
+    pub fn members(&self) -> Vec<Member> {
+        let mut members = self.members.values().cloned().collect::<Vec<Member>>();
+        members.sort_by_key(|member| name_key(&member.username));
 
-        (broadcast_rx, user_handle)
+        members
     }
 }
-#[derive(Clone, Debug)]
+
+// Everything a session needs after joining a room
+pub struct Membership {
+    pub broadcast_rx: broadcast::Receiver<Message>,
+    pub backlog: Vec<ChatMessage>,
+    // Newest message in the room history when joining
+    pub last_id: Option<u64>,
+    pub members: Vec<Member>,
+    pub handle: UserHandle,
+}
+
+// Room as seen by one of its members, the session `id`
+#[derive(Clone)]
 pub struct UserHandle {
-    broadcast_tx: broadcast::Sender<Message>,
+    id: u64,
+    room: Arc<Mutex<Room>>,
 }
 
 impl UserHandle {
-    pub fn new(broadcast_tx: broadcast::Sender<Message>) -> Self {
-        UserHandle { broadcast_tx }
+    pub fn new(id: u64, room: Arc<Mutex<Room>>) -> Self {
+        UserHandle { id, room }
     }
 
+    // Chat messages are recorded in the room history before
+    // being broadcast to its members
     pub fn send_message(&self, message: Message) -> Result<()> {
-        match self.broadcast_tx.send(message) {
+        let mut room = self.room.lock().unwrap();
+
+        if let Some(chat_message) = message.message_type.chat_message() {
+            room.history.push(chat_message.clone());
+        }
+
+        match room.broadcast_tx.send(message) {
             Ok(..) => Ok(()),
             Err(..) => Err(anyhow!("Failed to send message to room broadcast channel")),
         }
     }
+
+    pub fn history(&self, before: Option<u64>, limit: usize) -> (Vec<ChatMessage>, bool) {
+        self.room.lock().unwrap().history.page(before, limit)
+    }
+
+    // Catches up a member which fell behind, with the messages after
+    // `after` and the current members. The new subscription is taken
+    // under the room lock too, so it picks up right where they end
+    pub fn resync(
+        &self,
+        after: Option<u64>,
+    ) -> (broadcast::Receiver<Message>, Vec<ChatMessage>, Vec<Member>) {
+        let room = self.room.lock().unwrap();
+
+        (
+            room.broadcast_tx.subscribe(),
+            room.history.since(after),
+            room.members(),
+        )
+    }
+
+    // Replaces the member's name or presence, telling the other members.
+    // Sessions which already left the room stay out of it
+    pub fn update_member(&self, member: Member) {
+        let mut room = self.room.lock().unwrap();
+
+        if let Some(known) = room.members.get_mut(&self.id) {
+            let previous = std::mem::replace(known, member.clone());
+            let message = Message::build(
+                MessageType::MemberChanged {
+                    room: room.name.clone(),
+                    username: previous.username,
+                    member,
+                },
+                self.id,
+            );
+
+            let _ = room.broadcast_tx.send(message);
+        }
+    }
+
+    pub fn leave(&self, disconnected: bool) {
+        let mut room = self.room.lock().unwrap();
+
+        if let Some(member) = room.members.remove(&self.id) {
+            let message = Message::build(
+                MessageType::MemberLeft {
+                    room: room.name.clone(),
+                    username: member.username,
+                    disconnected,
+                },
+                self.id,
+            );
+
+            let _ = room.broadcast_tx.send(message);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::message::Presence;
+
+    fn member(username: &str) -> Member {
+        Member {
+            username: username.to_string(),
+            presence: Presence::Online,
+        }
+    }
+
+    fn room() -> Arc<Mutex<Room>> {
+        let config = RoomConfig {
+            history_size: 10,
+            join_backlog: 10,
+            channel_capacity: 16,
+        };
+
+        Arc::new(Mutex::new(Room::new("main", config)))
+    }
+
+    fn join(room: &Arc<Mutex<Room>>, id: u64, username: &str) -> Membership {
+        let handle = UserHandle::new(id, Arc::clone(room));
+
+        room.lock().unwrap().join(member(username), handle)
+    }
+
+    // Everything broadcast to the member so far
+    fn received(membership: &mut Membership) -> Vec<Message> {
+        std::iter::from_fn(|| membership.broadcast_rx.try_recv().ok()).collect()
+    }
+
+    #[test]
+    fn joins_are_announced_to_the_other_members() {
+        let room = room();
+        let mut alice = join(&room, 1, "alice");
+        let mut bob = join(&room, 2, "Bob");
+
+        assert_eq!(
+            received(&mut alice),
+            vec![Message::build(
+                MessageType::MemberJoined {
+                    room: String::from("main"),
+                    member: member("Bob"),
+                },
+                2,
+            )]
+        );
+        // Bob finds himself among the members instead
+        assert!(received(&mut bob).is_empty());
+        assert_eq!(bob.members, vec![member("alice"), member("Bob")]);
+    }
+
+    #[test]
+    fn changes_are_announced_once_to_every_member() {
+        let room = room();
+        let mut alice = join(&room, 1, "alice");
+        let mut bob = join(&room, 2, "bob");
+        received(&mut alice);
+
+        let away = Member {
+            username: String::from("bobby"),
+            presence: Presence::Away,
+        };
+        bob.handle.update_member(away.clone());
+
+        let changed = vec![Message::build(
+            MessageType::MemberChanged {
+                room: String::from("main"),
+                username: String::from("bob"),
+                member: away.clone(),
+            },
+            2,
+        )];
+        assert_eq!(received(&mut alice), changed);
+        assert_eq!(received(&mut bob), changed);
+        assert_eq!(room.lock().unwrap().members(), vec![member("alice"), away]);
+    }
+
+    #[test]
+    fn leaving_is_announced_once() {
+        let room = room();
+        let mut alice = join(&room, 1, "alice");
+        let bob = join(&room, 2, "bob");
+        received(&mut alice);
+
+        bob.handle.leave(true);
+        bob.handle.leave(false);
+        // No longer a member, so the change isn't announced
+        bob.handle.update_member(member("bobby"));
+
+        assert_eq!(
+            received(&mut alice),
+            vec![Message::build(
+                MessageType::MemberLeft {
+                    room: String::from("main"),
+                    username: String::from("bob"),
+                    disconnected: true,
+                },
+                2,
+            )]
+        );
+        assert_eq!(room.lock().unwrap().members(), vec![member("alice")]);
+    }
 }
@@ -1,12 +1,14 @@
-use super::{Room, UserHandle};
-use anyhow::{anyhow, Result};
-use common::message::Message;
+use super::{Membership, Room, UserHandle};
+use common::error::ErrorCode;
+use common::message::Member;
+use common::validation::name_key;
 use std::{
     collections::{HashMap, HashSet},
     sync::{Arc, Mutex},
 };
-use tokio::sync::broadcast::{self};
 
+// Rooms are keyed by `name_key` so lookups ignore case,
+// while the room itself keeps the name it was created with
 pub struct RoomManager {
     rooms: HashMap<String, Arc<Mutex<Room>>>,
 }
@@ -16,29 +18,99 @@ impl RoomManager {
         RoomManager {
             rooms: rooms
                 .into_iter()
-                .map(|r| (r.clone().lock().unwrap().name.clone(), r))
+                .map(|r| (name_key(&r.clone().lock().unwrap().name), r))
                 .collect(),
         }
     }
 
     pub fn add_room(&mut self, room: Arc<Mutex<Room>>, name: String) {
-        self.rooms.insert(name, room);
+        self.rooms.insert(name_key(&name), room);
     }
 
-    pub async fn join(&self, room: &str) -> Result<(broadcast::Receiver<Message>, UserHandle)> {
-        let room = self.rooms.get(room);
+    pub async fn join(&self, room: &str, id: u64, member: Member) -> Result<Membership, ErrorCode> {
+        let room = self.rooms.get(&name_key(room));
 
         match room {
             Some(room) => {
-                let mut room = room.lock().unwrap();
-                let (broadcast_rx, user_handle) = room.join();
-                Ok((broadcast_rx, user_handle))
+                let handle = UserHandle::new(id, Arc::clone(room));
+
+                Ok(room.lock().unwrap().join(member, handle))
             }
-            None => Err(anyhow!("Failed to join room")),
+            None => Err(ErrorCode::NoSuchRoom),
         }
     }
 
+    pub fn members(&self, room: &str) -> Result<Vec<Member>, ErrorCode> {
+        match self.rooms.get(&name_key(room)) {
+            Some(room) => Ok(room.lock().unwrap().members()),
+            None => Err(ErrorCode::NoSuchRoom),
+        }
+    }
+
+    // Name of the room as it was created, if it exists
+    pub fn find(&self, room: &str) -> Option<String> {
+        self.rooms
+            .get(&name_key(room))
+            .map(|room| room.lock().unwrap().name.clone())
+    }
+
     pub fn get_rooms(&self) -> HashSet<String> {
-        HashSet::from_iter(self.rooms.clone().into_keys())
+        self.rooms
+            .values()
+            .map(|room| room.lock().unwrap().name.clone())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::room::RoomConfig;
+    use common::message::{MessageType, Presence};
+
+    fn member(username: &str) -> Member {
+        Member {
+            username: username.to_string(),
+            presence: Presence::Online,
+        }
+    }
+
+    #[tokio::test]
+    async fn rooms_are_joined_whatever_the_case() {
+        let config = RoomConfig {
+            history_size: 10,
+            join_backlog: 10,
+            channel_capacity: 16,
+        };
+        let rooms = RoomManager::new(vec![Arc::new(Mutex::new(Room::new("Main", config)))]);
+
+        let mut alice = rooms.join("main", 1, member("alice")).await.unwrap();
+        rooms.join("MAIN", 2, member("bob")).await.unwrap();
+
+        let joined = alice.broadcast_rx.try_recv().unwrap();
+        assert_eq!(
+            joined.message_type,
+            MessageType::MemberJoined {
+                room: String::from("Main"),
+                member: member("bob"),
+            }
+        );
+        assert!(alice.broadcast_rx.try_recv().is_err());
+        assert_eq!(
+            rooms.members("main").unwrap(),
+            vec![member("alice"), member("bob")]
+        );
+        assert_eq!(rooms.find("mAiN"), Some(String::from("Main")));
+    }
+
+    #[tokio::test]
+    async fn unknown_rooms_cannot_be_joined() {
+        let rooms = RoomManager::new(Vec::new());
+
+        assert_eq!(
+            rooms.join("main", 1, member("alice")).await.err(),
+            Some(ErrorCode::NoSuchRoom)
+        );
+        assert_eq!(rooms.members("main").err(), Some(ErrorCode::NoSuchRoom));
     }
 }
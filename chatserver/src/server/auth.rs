@@ -0,0 +1,81 @@
+use anyhow::{anyhow, Result};
+use argon2::{
+    password_hash::{
+        rand_core::{OsRng, RngCore},
+        PasswordHash, PasswordHasher, PasswordVerifier, SaltString,
+    },
+    Argon2,
+};
+use std::time::Duration;
+
+// Failed logins to an account allowed before attempts are slowed down
+const FREE_LOGIN_ATTEMPTS: u32 = 3;
+
+const MAX_LOGIN_BACKOFF: Duration = Duration::from_secs(300);
+
+// Hashes are kept as PHC strings, which carry the algorithm
+// parameters and salt along with the hash itself
+pub fn hash_password(password: &str) -> Result<String> {
+    let salt = SaltString::generate(&mut OsRng);
+
+    match Argon2::default().hash_password(password.as_bytes(), &salt) {
+        Ok(hash) => Ok(hash.to_string()),
+        Err(e) => Err(anyhow!("Failed to hash password: {e}")),
+    }
+}
+
+pub fn verify_password(password: &str, password_hash: &str) -> bool {
+    match PasswordHash::new(password_hash) {
+        Ok(hash) => Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok(),
+        Err(_) => false,
+    }
+}
+
+// Random token handed to clients for resuming their session
+pub fn generate_token() -> String {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+// How long after the latest failed login to an account further attempts
+// are refused, doubling with every failure past the free ones
+pub fn login_backoff(failures: u32) -> Duration {
+    match failures.checked_sub(FREE_LOGIN_ATTEMPTS) {
+        Some(doublings) => 2u64
+            .checked_pow(doublings)
+            .map_or(MAX_LOGIN_BACKOFF, Duration::from_secs)
+            .min(MAX_LOGIN_BACKOFF),
+        None => Duration::ZERO,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn login_backoff_doubles_up_to_the_limit() {
+        assert_eq!(login_backoff(0), Duration::ZERO);
+        assert_eq!(login_backoff(FREE_LOGIN_ATTEMPTS - 1), Duration::ZERO);
+        assert_eq!(login_backoff(FREE_LOGIN_ATTEMPTS), Duration::from_secs(1));
+        assert_eq!(
+            login_backoff(FREE_LOGIN_ATTEMPTS + 3),
+            Duration::from_secs(8)
+        );
+        assert_eq!(login_backoff(FREE_LOGIN_ATTEMPTS + 20), MAX_LOGIN_BACKOFF);
+        assert_eq!(login_backoff(u32::MAX), MAX_LOGIN_BACKOFF);
+    }
+
+    #[test]
+    fn verifies_hashed_passwords() {
+        let hash = hash_password("correct horse").unwrap();
+
+        assert!(verify_password("correct horse", &hash));
+        assert!(!verify_password("wrong horse", &hash));
+        assert!(!verify_password("correct horse", "not a hash"));
+    }
+}
@@ -1,34 +1,93 @@
+mod auth;
 mod server_events;
 mod session;
 
-use common::message::{Message, MessageType};
+use common::codec::{Codec, Frame};
+use common::connection::{self, Transport};
+use common::error::ErrorCode;
+use common::handshake::{Capability, Hello, HelloReply, PROTOCOL_VERSION};
+use common::message::{ListOption, Message, MessageType};
+use common::tls::{self, TlsAcceptor};
+use common::validation::{name_key, Limits, MAX_PASSWORD_LENGTH};
 use server_events::{ServerEvent, ServerReply};
-pub use session::Session;
+pub use session::{Session, SessionState};
 
-use crate::room::{room_manager::RoomManager, Room};
+use crate::config::{Config, Heartbeat, Listener, Shutdown};
+use crate::room::{room_manager::RoomManager, Room, RoomConfig};
+use crate::storage::{Account, RoomRecord, Storage, StorageWriter};
 use anyhow::{anyhow, Result};
-use futures_util::{SinkExt, StreamExt};
+use chrono::Utc;
 use log::{error, info};
 use std::collections::HashMap;
+use std::future::Future;
 use std::sync::{
     atomic::{AtomicU64, Ordering},
     Arc, Mutex,
 };
+use std::time::{Duration, Instant};
 use tokio::{
     net::{TcpListener, TcpStream},
     sync::{
         broadcast::{self},
         mpsc::{self},
         oneshot::{self},
+        Notify,
     },
     task::JoinSet,
+    time::MissedTickBehavior,
 };
 
 type SessionHandle = mpsc::UnboundedSender<Message>;
 
+// Optional protocol features this server implements
+const SERVER_CAPABILITIES: &[Capability] = &[Capability::History, Capability::Resume];
+
+// Most messages handed out for a single history request
+const MAX_HISTORY_PAGE: usize = 100;
+
+// Message ids reserved in storage at a time, so handing one out
+// rarely has to wait for a write. A crash skips the rest of the block
+const MESSAGE_ID_BLOCK: u64 = 1000;
+
+// How often batched storage writes are committed
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+// What every connection is handled with
+struct ConnectionConfig {
+    limits: Limits,
+    // Shown to users once connected
+    motd: Option<String>,
+    heartbeat: Heartbeat,
+}
+
 pub struct Server {
     next_client_id: AtomicU64,
-    port: u64,
+    next_message_id: u64,
+    // Highest message id reserved in storage
+    reserved_message_id: u64,
+    listeners: Vec<Listener>,
+    // Set when some listeners use TLS
+    tls: Option<TlsAcceptor>,
+    connection_config: Arc<ConnectionConfig>,
+    room_config: RoomConfig,
+    storage: StorageWriter,
+    // Registered accounts, keyed by `name_key`
+    accounts: HashMap<String, Account>,
+    // Whether users may pick a name without an account
+    allow_guests: bool,
+    // Failed logins per account, keyed by `name_key`, along
+    // with the time of the latest one
+    login_failures: HashMap<String, (u32, Instant)>,
+    // How long sessions of lost connections are kept for resuming
+    resume_grace: Duration,
+    resume_tokens: HashMap<String, u64>,
+    // Sessions waiting to be resumed, with the messages sent to
+    // them so far and the time their connection was lost
+    detached: HashMap<u64, (mpsc::UnboundedReceiver<Message>, Instant)>,
+    // Sessions resumed while their old connection was still around,
+    // handed over once it lets go. Keyed by the resumed session, with
+    // the session of the resuming connection
+    pending_resumes: HashMap<u64, (u64, oneshot::Sender<ServerReply>)>,
     username_to_id: HashMap<String, u64>,
     id_to_username: HashMap<u64, String>,
     room_manager: RoomManager,
@@ -36,18 +95,97 @@ pub struct Server {
     rx: mpsc::UnboundedReceiver<(ServerEvent, oneshot::Sender<ServerReply>)>,
     sessions: HashMap<u64, (Session, SessionHandle)>,
     session_tasks: JoinSet<()>,
+    shutdown: Shutdown,
+    // Hands the farewell message to every connection
+    shutdown_tx: broadcast::Sender<Message>,
 }
 
 impl Server {
-    pub fn new(port: u64) -> Self {
-        let default_rooms: Vec<Arc<Mutex<Room>>> = vec![Arc::new(Mutex::new(Room::new("main")))];
-        let room_manager = RoomManager::new(default_rooms);
+    pub fn new(config: Config, mut storage: Box<dyn Storage>) -> Result<Self> {
+        let mut records = storage.rooms()?;
+
+        // The default rooms always exist
+        for name in config.default_rooms.iter().rev() {
+            if records
+                .iter()
+                .any(|record| name_key(&record.name) == name_key(name))
+            {
+                continue;
+            }
+
+            let record = RoomRecord {
+                name: name.clone(),
+                created_by: None,
+                created_at: Utc::now(),
+            };
+
+            storage.add_room(&record)?;
+            records.insert(0, record);
+        }
+
+        let mut rooms: Vec<Arc<Mutex<Room>>> = Vec::new();
+        for record in records {
+            let mut room = Room::new(&record.name, config.room_config);
+            room.restore(storage.history(&record.name, config.room_config.history_size)?);
+
+            rooms.push(Arc::new(Mutex::new(room)));
+        }
+
+        let accounts = storage
+            .accounts()?
+            .into_iter()
+            .map(|account| (name_key(&account.username), account))
+            .collect::<HashMap<String, Account>>();
+
+        info!(
+            "[*] Loaded {0} rooms and {1} accounts",
+            rooms.len(),
+            accounts.len()
+        );
+
+        let reserved_message_id = storage.last_message_id()?.unwrap_or(0);
+        let room_manager = RoomManager::new(rooms);
         let (to_server_tx, rx) =
             mpsc::unbounded_channel::<(ServerEvent, oneshot::Sender<ServerReply>)>();
 
-        Self {
+        let tls = match config.tls {
+            Some(files) => {
+                let acceptor = tls::acceptor(&files.cert, &files.key)?;
+
+                // Users trusting the certificate on first use can
+                // compare it with this one
+                if let Some(cert) = tls::read_certs(&files.cert)?.first() {
+                    info!(
+                        "[*] TLS certificate fingerprint {0}",
+                        tls::fingerprint(cert)
+                    );
+                }
+
+                Some(acceptor)
+            }
+            None => None,
+        };
+
+        Ok(Self {
             next_client_id: AtomicU64::new(1),
-            port,
+            next_message_id: reserved_message_id + 1,
+            reserved_message_id,
+            listeners: config.listeners,
+            tls,
+            connection_config: Arc::new(ConnectionConfig {
+                limits: config.limits,
+                motd: config.motd,
+                heartbeat: config.heartbeat,
+            }),
+            room_config: config.room_config,
+            storage: StorageWriter::spawn(storage),
+            accounts,
+            allow_guests: config.allow_guests,
+            login_failures: HashMap::new(),
+            resume_grace: config.resume_grace,
+            resume_tokens: HashMap::new(),
+            detached: HashMap::new(),
+            pending_resumes: HashMap::new(),
             username_to_id: HashMap::new(),
             id_to_username: HashMap::new(),
             room_manager,
@@ -55,51 +193,140 @@ impl Server {
             rx,
             sessions: HashMap::new(),
             session_tasks: JoinSet::new(),
-        }
+            shutdown: config.shutdown,
+            shutdown_tx: broadcast::channel(1).0,
+        })
     }
 
+    // Runs until asked to stop by a signal, `close_server` then has to
+    // be called
     pub async fn start(&mut self) -> Result<()> {
-        let addr = format!("0.0.0.0:{}", self.port);
-        let listener = TcpListener::bind(addr).await?;
-        let (shutdown_tx, shutdown_rx) = broadcast::channel(1);
+        let mut listeners = Vec::new();
+
+        for config in self.listeners.clone() {
+            let address = config.address;
+            let listener = TcpListener::bind(address)
+                .await
+                .map_err(|e| anyhow!("Failed to listen at {address}: {e}"))?;
+
+            match config.tls {
+                true => info!(
+                    "[+] Listening at {address} ({0:?} over TLS)",
+                    config.transport
+                ),
+                false => info!("[+] Listening at {address} ({0:?})", config.transport),
+            }
+
+            listeners.push((listener, config));
+        }
+
+        self.serve(listeners, shutdown_signal()).await;
+
+        Ok(())
+    }
+
+    // Handles the connections of the listeners until `shutdown`
+    // resolves with the reason to stop
+    async fn serve(
+        &mut self,
+        listeners: Vec<(TcpListener, Listener)>,
+        shutdown: impl Future<Output = &'static str>,
+    ) {
+        // Every listener hands its connections over to the main loop,
+        // along with how they have to be set up
+        let (accepted_tx, mut accepted_rx) = mpsc::unbounded_channel::<(TcpStream, Listener)>();
+        let mut listener_tasks = JoinSet::new();
+
+        for (listener, config) in listeners {
+            listener_tasks.spawn({
+                let accepted_tx = accepted_tx.clone();
+
+                async move {
+                    loop {
+                        match listener.accept().await {
+                            Ok((stream, _)) => {
+                                let _ = accepted_tx.send((stream, config));
+                            }
+                            Err(e) => {
+                                error!("[-] Failed to accept new connection: {}", e);
+                            }
+                        }
+                    }
+                }
+            });
+        }
 
         info!("[+] Server started");
-        info!("[+] Listening at port {0}", self.port);
+
+        tokio::pin!(shutdown);
+
+        let mut flush = tokio::time::interval(FLUSH_INTERVAL);
+        flush.set_missed_tick_behavior(MissedTickBehavior::Delay);
 
         loop {
             tokio::select! {
-                _ = tokio::signal::ctrl_c() => {
-                    let _ = shutdown_tx.send(());
+                reason = &mut shutdown => {
+                    info!("[*] Received {reason}, shutting down");
                     break;
                 },
-                result = listener.accept() => match result {
-                    Ok((stream, _)) => {
+                _ = flush.tick() => self.storage.flush(),
+                accepted = accepted_rx.recv() => match accepted {
+                    Some((stream, listener)) => {
                         info!("[*] New connection");
 
+                        let (id, session_rx, takeover) = self.add_session();
+
                         // Spawn new thread in join set
                         self.session_tasks.spawn({
                             let to_server_tx = self.to_server_tx.clone();
 
-                            let id = self.next_client_id.fetch_add(1, Ordering::Relaxed);
-                            let (to_session_tx, session_rx, session) = Session::new(id);
-                            let session_shutdown_rx = shutdown_rx.resubscribe();
-
-                            self.sessions.insert(id, (session, to_session_tx));
+                            let session_shutdown_rx = self.shutdown_tx.subscribe();
+                            let config = Arc::clone(&self.connection_config);
+                            let acceptor = match listener.tls {
+                                true => self.tls.clone(),
+                                false => None,
+                            };
 
                             async move {
-                                let _ = handle_connection(
-                                        id,
-                                        stream,
-                                        to_server_tx,
-                                        session_rx,
-                                        session_shutdown_rx
-                                    ).await;
+                                let transport = async {
+                                    match &acceptor {
+                                        Some(acceptor) => {
+                                            connection::accept_tls(stream, listener.transport, acceptor).await
+                                        }
+                                        None => connection::accept(stream, listener.transport).await,
+                                    }
+                                };
+
+                                // Peers stalling the setup are given as long as for registering
+                                let transport = tokio::time::timeout(config.heartbeat.register_timeout, transport)
+                                    .await
+                                    .unwrap_or_else(|_| Err(anyhow!("Timed out")));
+
+                                match transport {
+                                    Ok(transport) => {
+                                        let _ = handle_connection(
+                                                id,
+                                                transport,
+                                                config,
+                                                to_server_tx,
+                                                session_rx,
+                                                takeover,
+                                                session_shutdown_rx
+                                            ).await;
+                                    },
+                                    Err(e) => {
+                                        error!("[-] Failed to set up connection: {e}");
+
+                                        let event = ServerEvent::DropSession { id };
+
+                                        let (tx, _rx) = oneshot::channel::<ServerReply>();
+                                        let _ = to_server_tx.send((event, tx));
+                                    },
+                                }
                             }
                         });
                     },
-                    Err(e) => {
-                        error!("[-] Failed to accept new connection: {}", e);
-                    }
+                    None => break,
                 },
                 server_request = self.rx.recv() => {
                     if let Some((event, reply_tx)) = server_request {
@@ -109,60 +336,300 @@ impl Server {
             }
         }
 
-        Ok(())
+        // No new connections while the others are closed
+        listener_tasks.shutdown().await;
+    }
+
+    // Sets up the session of a new connection, handing back what
+    // the connection needs to serve it
+    fn add_session(&mut self) -> (u64, mpsc::UnboundedReceiver<Message>, Arc<Notify>) {
+        let id = self.next_client_id.fetch_add(1, Ordering::Relaxed);
+        let (to_session_tx, session_rx, session) = Session::new(id);
+        let takeover = Arc::clone(&session.takeover);
+
+        self.sessions.insert(id, (session, to_session_tx));
+
+        (id, session_rx, takeover)
+    }
+
+    // Ids handed out to chat messages, unique for the server's lifetime
+    fn next_message_id(&mut self) -> u64 {
+        let id = self.next_message_id;
+        self.next_message_id += 1;
+
+        if id > self.reserved_message_id {
+            self.reserved_message_id = id + MESSAGE_ID_BLOCK - 1;
+            self.storage.set_last_message_id(self.reserved_message_id);
+        }
+
+        id
     }
 
-    pub async fn close_server(self) {
+    // Tells every connection that the server is going away and waits
+    // for them to close, dropping those which take too long
+    pub async fn close_server(mut self) {
         info!("[*] Closing server");
 
-        self.session_tasks.join_all().await;
+        for (session, _) in self.sessions.values_mut() {
+            session.state = SessionState::Closing;
+        }
+
+        let notice = Message::build(
+            MessageType::ShuttingDown {
+                reason: self.shutdown.reason.clone(),
+                reconnect_after: self.shutdown.reconnect_after.as_secs(),
+            },
+            0,
+        );
+        let _ = self.shutdown_tx.send(notice);
+
+        let deadline = tokio::time::sleep(self.shutdown.timeout);
+        tokio::pin!(deadline);
+
+        // Connections may still be waiting on requests they sent before
+        // being told, so events are handled until the last one is gone.
+        // Requests are refused by now, apart from closing the connection
+        loop {
+            tokio::select! {
+                joined = self.session_tasks.join_next() => {
+                    if joined.is_none() {
+                        break;
+                    }
+                },
+                server_request = self.rx.recv() => {
+                    if let Some((event, reply_tx)) = server_request {
+                        let _ = server_events::handle_event(event, reply_tx, &mut self).await;
+                    }
+                },
+                _ = &mut deadline => {
+                    info!(
+                        "[-] Dropping {0} connections which did not close in time",
+                        self.session_tasks.len()
+                    );
+                    self.session_tasks.shutdown().await;
+                    break;
+                },
+            }
+        }
+
+        // Ids left in the reserved block are used after a restart
+        self.storage.set_last_message_id(self.next_message_id - 1);
+
+        if let Err(e) = self.storage.close().await {
+            error!("[-] Failed to save state: {e}");
+        }
+    }
+}
+
+// Resolves with the name of the signal once the server is asked to
+// stop, by Ctrl-C (SIGINT) or SIGTERM
+async fn shutdown_signal() -> &'static str {
+    let interrupt = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            error!("[-] Failed to listen for Ctrl-C: {e}");
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(e) => {
+                error!("[-] Failed to listen for SIGTERM: {e}");
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = interrupt => "SIGINT",
+        _ = terminate => "SIGTERM",
     }
 }
 
 async fn handle_connection(
-    session_id: u64,
-    stream: TcpStream,
+    mut session_id: u64,
+    mut transport: Box<dyn Transport>,
+    config: Arc<ConnectionConfig>,
     to_server_tx: mpsc::UnboundedSender<(ServerEvent, oneshot::Sender<ServerReply>)>,
     mut session_rx: mpsc::UnboundedReceiver<Message>,
-    mut shutdown_rx: broadcast::Receiver<()>,
+    takeover: Arc<Notify>,
+    mut shutdown_rx: broadcast::Receiver<Message>,
 ) -> Result<()> {
-    let mut ws_stream = tokio_tungstenite::accept_async(stream)
-        .await
-        .expect("Unable to accept websocket");
+    // Replies use the codec of the last frame received from the client
+    let mut codec = Codec::Postcard;
+    let heartbeat = config.heartbeat;
+
+    // Both the handshake and registering have to be done by then
+    let register_deadline = tokio::time::sleep(heartbeat.register_timeout);
+    tokio::pin!(register_deadline);
+    let mut registered = false;
+
+    let handshake = tokio::select! {
+        result = handshake(transport.as_mut(), &mut codec) => result,
+        _ = &mut register_deadline => Err(anyhow!("Timed out")),
+    };
+
+    match handshake {
+        Ok(capabilities) => {
+            let event = ServerEvent::SetCapabilities {
+                id: session_id,
+                capabilities,
+            };
+
+            let (tx, _rx) = oneshot::channel::<ServerReply>();
+            let _ = to_server_tx.send((event, tx));
+
+            if let Some(motd) = &config.motd {
+                let message = Message::build(MessageType::Motd { text: motd.clone() }, 0);
+                let _ = transport.send(&message, codec).await;
+            }
+        }
+        Err(e) => {
+            info!("[-] Handshake failed: {e}");
+
+            let _ = transport.close().await;
+
+            let event = ServerEvent::DropSession { id: session_id };
+
+            let (tx, _rx) = oneshot::channel::<ServerReply>();
+            let _ = to_server_tx.send((event, tx));
+
+            return Ok(());
+        }
+    }
+
+    let mut ping = tokio::time::interval_at(
+        tokio::time::Instant::now() + heartbeat.interval,
+        heartbeat.interval,
+    );
+    ping.set_missed_tick_behavior(MissedTickBehavior::Delay);
 
     loop {
         tokio::select! {
-            _ = shutdown_rx.recv() => break,
+            _ = &mut register_deadline, if !registered => {
+                info!("[-] Connection {session_id} did not register in time");
+
+                let message = failed("register", ErrorCode::RegisterTimeout);
+                let _ = transport.send(&message, codec).await;
+                let _ = transport.close().await;
+
+                let event = ServerEvent::DropSession { id: session_id };
+
+                let (tx, _rx) = oneshot::channel::<ServerReply>();
+                let _ = to_server_tx.send((event, tx));
+
+                break;
+            },
+            _ = ping.tick() => {
+                // A peer which stopped reading must not hold up the loop
+                if transport.idle_time() < heartbeat.timeout {
+                    let _ = tokio::time::timeout(heartbeat.interval, transport.send_ping()).await;
+                    continue;
+                }
+
+                info!("[-] Connection {session_id} stopped responding");
+
+                // Handled like a lost connection, so the session can
+                // still be resumed from a working one
+                let _ = tokio::time::timeout(heartbeat.interval, transport.close()).await;
+
+                let event = ServerEvent::DetachSession {
+                    id: session_id,
+                    session_rx,
+                };
+
+                let (tx, _rx) = oneshot::channel::<ServerReply>();
+                let _ = to_server_tx.send((event, tx));
+
+                break;
+            },
+            _ = takeover.notified() => {
+                info!("[*] Session {session_id} was resumed from another connection");
+
+                let _ = tokio::time::timeout(heartbeat.interval, transport.close()).await;
+
+                // The session is handed over along with its queued messages
+                let event = ServerEvent::DetachSession {
+                    id: session_id,
+                    session_rx,
+                };
+
+                let (tx, _rx) = oneshot::channel::<ServerReply>();
+                let _ = to_server_tx.send((event, tx));
+
+                break;
+            },
+            notice = shutdown_rx.recv() => {
+                // Messages queued before the notice still go out first
+                while let Ok(message) = session_rx.try_recv() {
+                    let _ = transport.send(&message, codec).await;
+                }
+
+                if let Ok(notice) = notice {
+                    let _ = transport.send(&notice, codec).await;
+                }
+
+                // The client acknowledges the close by closing its side
+                let _ = transport.close().await;
+                while let Some(Ok(_)) = transport.recv_frame().await {}
+
+                break;
+            },
             session_message = session_rx.recv() => {
                 if let Some(message) = session_message {
-                    let message_bytes = message.to_bytes();
-
-                    let _ = ws_stream.send(message_bytes.into()).await;
+                    let _ = transport.send(&message, codec).await;
                 }
             },
-            message = ws_stream.next() => {
-                match message {
-                    Some(message) if message.is_ok() => {
-                        let message = message.unwrap();
-                        let message = Message::from_bytes(message.into_data().into());
-
-                        if message.is_ok() {
-                            let message = message.unwrap();
-                            let reply_message = handle_message(message, session_id, to_server_tx.clone()).await;
-
-                            match reply_message {
-                                Ok(message) => {
-                                    let _ = ws_stream.send(message.to_bytes().into()).await;
+            frame = transport.recv_frame() => {
+                match frame {
+                    Some(Ok(frame)) => {
+                        codec = frame.codec();
+
+                        if let Ok(message) = frame.decode::<Message>() {
+                            // Replies carry the request id of the message that caused them
+                            let request_id = message.header.request_id;
+                            let reply_message = match message.message_type {
+                                MessageType::Resume { token } => Ok(resume_session(token, &mut session_id, &mut session_rx, &to_server_tx).await),
+                                MessageType::Quit => {
+                                    info!("[*] Session {session_id} quit");
+
+                                    let _ = transport.close().await;
+
+                                    let event = ServerEvent::DropSession { id: session_id };
+
+                                    let (tx, _rx) = oneshot::channel::<ServerReply>();
+                                    let _ = to_server_tx.send((event, tx));
+
+                                    break;
                                 },
-                                Err(_) => {},
+                                _ => handle_message(message, session_id, &config.limits, to_server_tx.clone()).await,
+                            };
+
+                            if let Ok(message) = reply_message {
+                                if let MessageType::Registered { .. } | MessageType::Resumed { .. } = message.message_type {
+                                    registered = true;
+                                }
+
+                                let message = message.with_request_id(request_id);
+                                let _ = transport.send(&message, codec).await;
                             }
                         }
                     },
                     None => {
-                        // Connection to the client has been closed/dropped
-                        // session has to be dropped as well
-                        let event = ServerEvent::DropSession {
+                        // Connection to the client has been closed/dropped,
+                        // the server decides whether the session is kept
+                        let event = ServerEvent::DetachSession {
                             id: session_id,
+                            session_rx,
                         };
 
                         let (tx, _rx) = oneshot::channel::<ServerReply>();
@@ -179,18 +646,93 @@ async fn handle_connection(
     Ok(())
 }
 
+// Swaps the session of the connection for the session the token
+// belongs to, so its queued messages get delivered from here on
+async fn resume_session(
+    token: String,
+    session_id: &mut u64,
+    session_rx: &mut mpsc::UnboundedReceiver<Message>,
+    to_server_tx: &mpsc::UnboundedSender<(ServerEvent, oneshot::Sender<ServerReply>)>,
+) -> Message {
+    let event = ServerEvent::Resume {
+        id: *session_id,
+        token,
+    };
+
+    let (tx, rx) = oneshot::channel::<ServerReply>();
+    let _ = to_server_tx.send((event, tx));
+
+    match rx.await {
+        Ok(ServerReply::Resumed {
+            id,
+            username,
+            rooms,
+            session_rx: resumed_rx,
+        }) => {
+            *session_id = id;
+            *session_rx = resumed_rx;
+
+            Message::build(
+                MessageType::Resumed {
+                    id,
+                    username,
+                    rooms,
+                },
+                0,
+            )
+        }
+        Ok(ServerReply::Failed { code }) => failed("resume", code),
+        _ => failed("resume", ErrorCode::Internal),
+    }
+}
+
+// The first frame sent by a client has to be a hello carrying its
+// protocol version and capabilities. Incompatible clients are sent a
+// rejection before the connection is closed
+async fn handshake(transport: &mut dyn Transport, codec: &mut Codec) -> Result<Vec<Capability>> {
+    let hello = match transport.recv_frame().await {
+        Some(Ok(frame)) => {
+            *codec = frame.codec();
+            frame.decode::<Hello>()
+        }
+        _ => return Err(anyhow!("Connection closed before handshake")),
+    };
+
+    let reply = match &hello {
+        Ok(hello) => HelloReply::answer(hello, SERVER_CAPABILITIES),
+        Err(_) => HelloReply::Rejected {
+            protocol_version: PROTOCOL_VERSION,
+            reason: String::from("Expected handshake"),
+        },
+    };
+
+    transport.send_frame(Frame::encode(&reply, *codec)?).await?;
+
+    match reply {
+        HelloReply::Accepted { capabilities, .. } => Ok(capabilities
+            .iter()
+            .filter_map(|name| Capability::from_name(name))
+            .collect()),
+        HelloReply::Rejected { reason, .. } => Err(anyhow!(reason)),
+    }
+}
+
 async fn handle_message(
     message: Message,
     session_id: u64,
+    limits: &Limits,
     to_server_tx: mpsc::UnboundedSender<(ServerEvent, oneshot::Sender<ServerReply>)>,
 ) -> Result<Message> {
-    let header = message.header;
-    match header.message_type {
-        MessageType::Register => {
-            let body = message.body;
+    let message_type = match validate_request(message.message_type, limits) {
+        Ok(message_type) => message_type,
+        Err((command, code)) => return Ok(failed(command, code)),
+    };
+
+    match message_type {
+        MessageType::Register { username } => {
             let event = ServerEvent::Register {
                 id: session_id,
-                username: body.arg.unwrap(),
+                username,
             };
 
             let (tx, rx) = oneshot::channel::<ServerReply>();
@@ -200,34 +742,107 @@ async fn handle_message(
             let server_reply = rx.await;
 
             match server_reply {
-                Ok(ServerReply::Registered { username }) => {
-                    let message = Message::build(
-                        MessageType::Registered,
-                        0,
-                        Some(session_id.to_string()),
-                        Some(username),
-                    )?;
-
-                    Ok(message)
-                }
-                Ok(ServerReply::Failed { error }) => {
-                    let message = Message::build(
-                        MessageType::Failed,
-                        0,
-                        Some(String::from("register")),
-                        Some(error),
-                    )?;
-
-                    Ok(message)
-                }
+                Ok(ServerReply::Registered { username }) => Ok(Message::build(
+                    MessageType::Registered {
+                        id: session_id,
+                        username,
+                    },
+                    0,
+                )),
+                Ok(ServerReply::Failed { code }) => Ok(failed("register", code)),
+                _ => Err(anyhow!("Unexpected server reply")),
+            }
+        }
+        MessageType::CreateAccount { username, password } => {
+            // Hashing is slow on purpose, so it's kept off the async runtime
+            let password_hash =
+                match tokio::task::spawn_blocking(move || auth::hash_password(&password)).await {
+                    Ok(Ok(password_hash)) => password_hash,
+                    _ => return Ok(failed("createaccount", ErrorCode::Internal)),
+                };
+
+            let event = ServerEvent::CreateAccount {
+                id: session_id,
+                username,
+                password_hash,
+            };
+
+            let (tx, rx) = oneshot::channel::<ServerReply>();
+            let _ = to_server_tx.send((event, tx));
+
+            match rx.await {
+                Ok(ServerReply::Registered { username }) => Ok(Message::build(
+                    MessageType::Registered {
+                        id: session_id,
+                        username,
+                    },
+                    0,
+                )),
+                Ok(ServerReply::Failed { code }) => Ok(failed("createaccount", code)),
                 _ => Err(anyhow!("Unexpected server reply")),
             }
         }
-        MessageType::ChangeName => {
-            let body = message.body;
+        MessageType::Login { username, password } => {
+            let event = ServerEvent::GetAccount {
+                id: session_id,
+                username: username.clone(),
+            };
+
+            let (tx, rx) = oneshot::channel::<ServerReply>();
+            let _ = to_server_tx.send((event, tx));
+
+            let password_hash = match rx.await {
+                Ok(ServerReply::Account { password_hash }) => password_hash,
+                Ok(ServerReply::Failed { code }) => return Ok(failed("login", code)),
+                _ => return Err(anyhow!("Unexpected server reply")),
+            };
+
+            // Verified here rather than by the server task, see above
+            let verified = match password_hash {
+                Some(password_hash) => tokio::task::spawn_blocking(move || {
+                    auth::verify_password(&password, &password_hash)
+                })
+                .await
+                .unwrap_or(false),
+                None => false,
+            };
+
+            if !verified {
+                let event = ServerEvent::LoginFailed {
+                    id: session_id,
+                    username,
+                };
+
+                let (tx, _rx) = oneshot::channel::<ServerReply>();
+                let _ = to_server_tx.send((event, tx));
+
+                return Ok(failed("login", ErrorCode::InvalidCredentials));
+            }
+
+            let event = ServerEvent::Login {
+                id: session_id,
+                username,
+            };
+
+            let (tx, rx) = oneshot::channel::<ServerReply>();
+            let _ = to_server_tx.send((event, tx));
+
+            match rx.await {
+                Ok(ServerReply::Registered { username }) => Ok(Message::build(
+                    MessageType::Registered {
+                        id: session_id,
+                        username,
+                    },
+                    0,
+                )),
+                Ok(ServerReply::Failed { code }) => Ok(failed("login", code)),
+                _ => Err(anyhow!("Unexpected server reply")),
+            }
+        }
+        MessageType::ChangeName { username } => {
             let event = ServerEvent::ChangeName {
                 id: session_id,
-                new_username: body.arg.unwrap(),
+                new_username: username,
             };
 
             let (tx, rx) = oneshot::channel::<ServerReply>();
@@ -239,33 +854,18 @@ async fn handle_message(
                 Ok(ServerReply::NameChanged {
                     new_username,
                     old_username,
-                }) => {
-                    let message = Message::build(
-                        MessageType::ChangedName,
-                        0,
-                        Some(new_username),
-                        Some(old_username),
-                    )?;
-
-                    Ok(message)
-                }
-                Ok(ServerReply::Failed { error }) => {
-                    let message = Message::build(
-                        MessageType::Failed,
-                        0,
-                        Some(String::from("changename")),
-                        Some(error),
-                    )?;
-
-                    Ok(message)
-                }
+                }) => Ok(Message::build(
+                    MessageType::ChangedName {
+                        new_username,
+                        old_username,
+                    },
+                    0,
+                )),
+                Ok(ServerReply::Failed { code }) => Ok(failed("changename", code)),
                 _ => Err(anyhow!("Unexpected server reply")),
             }
         }
-        MessageType::Join => {
-            let body = message.body;
-            let room = body.arg.unwrap();
-
+        MessageType::Join { room } => {
             let event = ServerEvent::JoinRoom {
                 id: session_id,
                 room,
@@ -277,28 +877,23 @@ async fn handle_message(
             let server_reply = rx.await;
 
             match server_reply {
-                Ok(ServerReply::Joined { room }) => {
-                    let message = Message::build(MessageType::Joined, 0, Some(room), None)?;
-
-                    Ok(message)
-                }
-                Ok(ServerReply::Failed { error }) => {
-                    let message = Message::build(
-                        MessageType::Failed,
-                        0,
-                        Some(String::from("join")),
-                        Some(error),
-                    )?;
-
-                    Ok(message)
-                }
+                Ok(ServerReply::Joined {
+                    room,
+                    history,
+                    members,
+                }) => Ok(Message::build(
+                    MessageType::Joined {
+                        room,
+                        history,
+                        members,
+                    },
+                    0,
+                )),
+                Ok(ServerReply::Failed { code }) => Ok(failed("join", code)),
                 _ => Err(anyhow!("Unexpected server reply")),
             }
         }
-        MessageType::Leave => {
-            let body = message.body;
-            let room = body.arg.unwrap();
-
+        MessageType::Leave { room } => {
             let event = ServerEvent::LeaveRoom {
                 id: session_id,
                 room,
@@ -311,28 +906,17 @@ async fn handle_message(
 
             match server_reply {
                 Ok(ServerReply::LeftRoom { room }) => {
-                    let message = Message::build(MessageType::LeftRoom, 0, Some(room), None)?;
-
-                    Ok(message)
-                }
-                Ok(ServerReply::Failed { error }) => {
-                    let message = Message::build(
-                        MessageType::Failed,
-                        0,
-                        Some(String::from("leave")),
-                        Some(error),
-                    )?;
-
-                    Ok(message)
+                    Ok(Message::build(MessageType::LeftRoom { room }, 0))
                 }
+                Ok(ServerReply::Failed { code }) => Ok(failed("leave", code)),
                 _ => Err(anyhow!("Unexpected server reply")),
             }
         }
-        MessageType::Create => {
-            let body = message.body;
-            let room = body.arg.unwrap();
-
-            let event = ServerEvent::CreateRoom { room };
+        MessageType::Create { room } => {
+            let event = ServerEvent::CreateRoom {
+                id: session_id,
+                room,
+            };
 
             let (tx, rx) = oneshot::channel::<ServerReply>();
             let _ = to_server_tx.send((event, tx));
@@ -341,31 +925,17 @@ async fn handle_message(
 
             match server_reply {
                 Ok(ServerReply::CreatedRoom { room }) => {
-                    let message = Message::build(MessageType::CreatedRoom, 0, Some(room), None)?;
-
-                    Ok(message)
-                }
-                Ok(ServerReply::Failed { error }) => {
-                    let message = Message::build(
-                        MessageType::Failed,
-                        0,
-                        Some(String::from("create")),
-                        Some(error),
-                    )?;
-
-                    Ok(message)
+                    Ok(Message::build(MessageType::CreatedRoom { room }, 0))
                 }
+                Ok(ServerReply::Failed { code }) => Ok(failed("create", code)),
                 _ => Err(anyhow!("Unexpected server reply")),
             }
         }
-        MessageType::SendTo => {
-            let body = message.body;
-            let room = body.arg.unwrap();
-            let content = body.content.unwrap();
+        MessageType::SendTo { room, text } => {
             let event = ServerEvent::SendTo {
                 id: session_id,
                 room: room.clone(),
-                content: content.clone(),
+                content: text,
             };
 
             let (tx, rx) = oneshot::channel::<ServerReply>();
@@ -374,29 +944,14 @@ async fn handle_message(
             let server_reply = rx.await;
 
             match server_reply {
-                Ok(ServerReply::MessagedRoom) => {
-                    let message =
-                        Message::build(MessageType::MessagedRoom, 0, Some(room), Some(content))?;
-
-                    Ok(message)
-                }
-                Ok(ServerReply::Failed { error }) => {
-                    let message = Message::build(
-                        MessageType::Failed,
-                        0,
-                        Some(String::from("sendto")),
-                        Some(error),
-                    )?;
-
-                    Ok(message)
+                Ok(ServerReply::MessagedRoom { id }) => {
+                    Ok(Message::build(MessageType::MessagedRoom { room, id }, 0))
                 }
+                Ok(ServerReply::Failed { code }) => Ok(failed("sendto", code)),
                 _ => Err(anyhow!("Unexpected server reply")),
             }
         }
-        MessageType::List => {
-            let body = message.body;
-            let opt = body.arg.unwrap();
-
+        MessageType::List(opt) => {
             let event = ServerEvent::List {
                 id: session_id,
                 opt,
@@ -408,42 +963,42 @@ async fn handle_message(
             let server_reply = rx.await;
 
             match server_reply {
-                Ok(ServerReply::ListingUsers { content }) => {
-                    let message = Message::build(MessageType::Users, 0, None, Some(content))?;
-
-                    Ok(message)
-                }
-                Ok(ServerReply::ListingUserRooms { content }) => {
-                    let message = Message::build(MessageType::UserRooms, 0, None, Some(content))?;
-
-                    Ok(message)
+                Ok(ServerReply::ListingUsers { users }) => {
+                    Ok(Message::build(MessageType::Users(users), 0))
                 }
-                Ok(ServerReply::ListingRooms { content }) => {
-                    let message = Message::build(MessageType::AllRooms, 0, None, Some(content))?;
-
-                    Ok(message)
+                Ok(ServerReply::ListingUserRooms { rooms }) => {
+                    Ok(Message::build(MessageType::UserRooms(rooms), 0))
                 }
-                Ok(ServerReply::Failed { error }) => {
-                    let message = Message::build(
-                        MessageType::Failed,
-                        0,
-                        Some(String::from("list")),
-                        Some(error),
-                    )?;
-
-                    Ok(message)
+                Ok(ServerReply::ListingRooms { rooms }) => {
+                    Ok(Message::build(MessageType::AllRooms(rooms), 0))
                 }
+                Ok(ServerReply::Failed { code }) => Ok(failed("list", code)),
                 _ => Err(anyhow!("Unexpected server reply")),
             }
         }
-        MessageType::PrivMsg => {
-            let body = message.body;
-            let username = body.arg.unwrap();
-            let content = body.content.unwrap();
+        MessageType::Members { room } => {
+            let event = ServerEvent::Members {
+                id: session_id,
+                room,
+            };
+
+            let (tx, rx) = oneshot::channel::<ServerReply>();
+            let _ = to_server_tx.send((event, tx));
+
+            match rx.await {
+                Ok(ServerReply::Members { room, members }) => Ok(Message::build(
+                    MessageType::RoomMembers { room, members },
+                    0,
+                )),
+                Ok(ServerReply::Failed { code }) => Ok(failed("members", code)),
+                _ => Err(anyhow!("Unexpected server reply")),
+            }
+        }
+        MessageType::PrivMsg { to, text } => {
             let event = ServerEvent::PrivMsg {
                 id: session_id,
-                username: username.clone(),
-                content: content.clone(),
+                username: to,
+                content: text,
             };
 
             let (tx, rx) = oneshot::channel::<ServerReply>();
@@ -452,25 +1007,350 @@ async fn handle_message(
             let server_reply = rx.await;
 
             match server_reply {
-                Ok(ServerReply::MessagedUser) => {
-                    let message =
-                        Message::build(MessageType::OutgoingMsg, 0, Some(username), Some(content))?;
-
-                    Ok(message)
-                }
-                Ok(ServerReply::Failed { error }) => {
-                    let message = Message::build(
-                        MessageType::Failed,
-                        0,
-                        Some(String::from("privmsg")),
-                        Some(error),
-                    )?;
-
-                    Ok(message)
+                Ok(ServerReply::MessagedUser { to, message }) => {
+                    Ok(Message::build(MessageType::OutgoingMsg { to, message }, 0))
                 }
+                Ok(ServerReply::Failed { code }) => Ok(failed("privmsg", code)),
+                _ => Err(anyhow!("Unexpected server reply")),
+            }
+        }
+        MessageType::FetchHistory {
+            room,
+            before,
+            limit,
+        } => {
+            let event = ServerEvent::FetchHistory {
+                id: session_id,
+                room,
+                before,
+                limit: (limit as usize).min(MAX_HISTORY_PAGE),
+            };
+
+            let (tx, rx) = oneshot::channel::<ServerReply>();
+            let _ = to_server_tx.send((event, tx));
+
+            let server_reply = rx.await;
+
+            match server_reply {
+                Ok(ServerReply::History {
+                    room,
+                    messages,
+                    more,
+                }) => Ok(Message::build(
+                    MessageType::History {
+                        room,
+                        messages,
+                        more,
+                    },
+                    0,
+                )),
+                Ok(ServerReply::Failed { code }) => Ok(failed("history", code)),
                 _ => Err(anyhow!("Unexpected server reply")),
             }
         }
-        _ => Err(anyhow!("Unexpected message type")),
+        // Such as replies only ever sent by the server
+        _ => Ok(failed("request", ErrorCode::UnexpectedMessage)),
+    }
+}
+
+// Normalizes the names in a request and checks them, along with
+// any message body, against the configured limits. Invalid
+// requests give back the failed command and the reason
+fn validate_request(
+    message_type: MessageType,
+    limits: &Limits,
+) -> Result<MessageType, (&'static str, ErrorCode)> {
+    match message_type {
+        MessageType::Register { username } => limits
+            .validate_name(&username)
+            .map(|username| MessageType::Register { username })
+            .map_err(|code| ("register", code)),
+        MessageType::ChangeName { username } => limits
+            .validate_name(&username)
+            .map(|username| MessageType::ChangeName { username })
+            .map_err(|code| ("changename", code)),
+        MessageType::CreateAccount { username, password } => limits
+            .validate_name(&username)
+            .and_then(|username| limits.validate_password(&password).map(|_| username))
+            .map(|username| MessageType::CreateAccount { username, password })
+            .map_err(|code| ("createaccount", code)),
+        MessageType::Login { username, password } => {
+            if password.len() > MAX_PASSWORD_LENGTH {
+                return Err(("login", ErrorCode::InvalidCredentials));
+            }
+
+            limits
+                .validate_name(&username)
+                .map(|username| MessageType::Login { username, password })
+                .map_err(|code| ("login", code))
+        }
+        MessageType::Join { room } => limits
+            .validate_name(&room)
+            .map(|room| MessageType::Join { room })
+            .map_err(|code| ("join", code)),
+        MessageType::Leave { room } => limits
+            .validate_name(&room)
+            .map(|room| MessageType::Leave { room })
+            .map_err(|code| ("leave", code)),
+        MessageType::Create { room } => limits
+            .validate_name(&room)
+            .map(|room| MessageType::Create { room })
+            .map_err(|code| ("create", code)),
+        MessageType::SendTo { room, text } => limits
+            .validate_name(&room)
+            .and_then(|room| limits.validate_text(&text).map(|_| room))
+            .map(|room| MessageType::SendTo { room, text })
+            .map_err(|code| ("sendto", code)),
+        MessageType::FetchHistory {
+            room,
+            before,
+            limit,
+        } => limits
+            .validate_name(&room)
+            .map(|room| MessageType::FetchHistory {
+                room,
+                before,
+                limit,
+            })
+            .map_err(|code| ("history", code)),
+        MessageType::Members { room } => limits
+            .validate_name(&room)
+            .map(|room| MessageType::Members { room })
+            .map_err(|code| ("members", code)),
+        MessageType::PrivMsg { to, text } => limits
+            .validate_name(&to)
+            .and_then(|to| limits.validate_text(&text).map(|_| to))
+            .map(|to| MessageType::PrivMsg { to, text })
+            .map_err(|code| ("privmsg", code)),
+        message_type => Ok(message_type),
+    }
+}
+
+// Builds the reply sent back when a request fails
+fn failed(command: &str, code: ErrorCode) -> Message {
+    Message::build(
+        MessageType::Failed {
+            command: command.to_string(),
+            code,
+        },
+        0,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn unexpected_messages_are_answered() {
+        let (to_server_tx, _rx) = mpsc::unbounded_channel();
+        let message = Message::build(
+            MessageType::Registered {
+                id: 1,
+                username: String::from("alice"),
+            },
+            1,
+        );
+
+        let reply = handle_message(message, 1, &Limits::default(), to_server_tx)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            reply.message_type,
+            MessageType::Failed {
+                command: String::from("request"),
+                code: ErrorCode::UnexpectedMessage,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn quitting_drops_the_session() {
+        let (server_side, mut client) = connection::memory_pair();
+        let (to_server_tx, mut to_server_rx) = mpsc::unbounded_channel();
+        let (_to_session_tx, session_rx) = mpsc::unbounded_channel();
+        let (_shutdown_tx, shutdown_rx) = broadcast::channel(1);
+        let config = Arc::new(ConnectionConfig {
+            limits: Limits::default(),
+            motd: None,
+            heartbeat: Heartbeat {
+                interval: Duration::from_secs(15),
+                timeout: Duration::from_secs(60),
+                register_timeout: Duration::from_secs(30),
+            },
+        });
+
+        let connection = tokio::spawn(handle_connection(
+            1,
+            Box::new(server_side),
+            config,
+            to_server_tx,
+            session_rx,
+            Arc::new(Notify::new()),
+            shutdown_rx,
+        ));
+
+        let hello = Hello::new(&[Capability::Resume]);
+        client
+            .send_frame(Frame::encode(&hello, Codec::Postcard).unwrap())
+            .await
+            .unwrap();
+        client.recv_frame().await.unwrap().unwrap();
+        client
+            .send(&Message::build(MessageType::Quit, 1), Codec::Postcard)
+            .await
+            .unwrap();
+
+        connection.await.unwrap().unwrap();
+
+        assert!(matches!(
+            to_server_rx.recv().await,
+            Some((ServerEvent::SetCapabilities { id: 1, .. }, _))
+        ));
+        // Not detached, so nothing is kept for resuming
+        assert!(matches!(
+            to_server_rx.recv().await,
+            Some((ServerEvent::DropSession { id: 1 }, _))
+        ));
+        assert!(to_server_rx.recv().await.is_none());
+    }
+
+    struct TempDatabase(std::path::PathBuf);
+
+    impl Drop for TempDatabase {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    // Messages from the server up to the first one `until` accepts
+    async fn recv_until(
+        client: &mut Box<dyn Transport>,
+        until: impl Fn(&MessageType) -> bool,
+    ) -> MessageType {
+        loop {
+            let message = client.recv().await.unwrap().unwrap();
+
+            if until(&message.message_type) {
+                return message.message_type;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn shutting_down_notifies_connections_and_saves_state() {
+        let database = TempDatabase(std::env::temp_dir().join(format!(
+            "chatserver-shutdown-{0}.sqlite",
+            std::process::id()
+        )));
+        let _ = std::fs::remove_file(&database.0);
+
+        let config = Config {
+            listeners: Vec::new(),
+            limits: Limits::default(),
+            room_config: RoomConfig {
+                history_size: 10,
+                join_backlog: 10,
+                channel_capacity: 16,
+            },
+            default_rooms: vec![String::from("main")],
+            allow_guests: true,
+            resume_grace: Duration::from_secs(60),
+            heartbeat: Heartbeat {
+                interval: Duration::from_secs(15),
+                timeout: Duration::from_secs(60),
+                register_timeout: Duration::from_secs(30),
+            },
+            database: None,
+            motd: None,
+            tls: None,
+            shutdown: Shutdown {
+                timeout: Duration::from_secs(5),
+                reconnect_after: Duration::from_secs(10),
+                reason: String::from("Maintenance"),
+            },
+            log_level: log::LevelFilter::Off,
+        };
+        let storage = crate::storage::SqliteStorage::open(&database.0).unwrap();
+        let mut server = Server::new(config, Box::new(storage)).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let listener_config = Listener {
+            address: listener.local_addr().unwrap(),
+            transport: connection::TransportKind::Tcp,
+            tls: false,
+        };
+
+        let (stop_tx, stop_rx) = oneshot::channel::<()>();
+        let client = tokio::spawn(async move {
+            let mut client = connection::connect(&address, connection::TransportKind::Tcp)
+                .await
+                .unwrap();
+            let hello = Hello::new(&[]);
+            client
+                .send_frame(Frame::encode(&hello, Codec::Postcard).unwrap())
+                .await
+                .unwrap();
+            client.recv_frame().await.unwrap().unwrap();
+
+            for message_type in [
+                MessageType::Register {
+                    username: String::from("alice"),
+                },
+                MessageType::Join {
+                    room: String::from("main"),
+                },
+                MessageType::SendTo {
+                    room: String::from("main"),
+                    text: String::from("hello"),
+                },
+            ] {
+                client
+                    .send(&Message::build(message_type, 0), Codec::Postcard)
+                    .await
+                    .unwrap();
+            }
+            recv_until(&mut client, |message_type| {
+                matches!(message_type, MessageType::MessagedRoom { .. })
+            })
+            .await;
+
+            stop_tx.send(()).unwrap();
+
+            recv_until(&mut client, |message_type| {
+                matches!(message_type, MessageType::ShuttingDown { .. })
+            })
+            .await
+        });
+
+        server
+            .serve(vec![(listener, listener_config)], async {
+                let _ = stop_rx.await;
+                "stop"
+            })
+            .await;
+        server.close_server().await;
+
+        assert_eq!(
+            client.await.unwrap(),
+            MessageType::ShuttingDown {
+                reason: String::from("Maintenance"),
+                reconnect_after: 10,
+            }
+        );
+
+        // The message is saved, and the ids reserved after it given back
+        let storage = crate::storage::SqliteStorage::open(&database.0).unwrap();
+        let history = storage.history("main", 10).unwrap();
+        assert_eq!(
+            history
+                .iter()
+                .map(|message| message.text.as_str())
+                .collect::<Vec<&str>>(),
+            vec!["hello"]
+        );
+        assert_eq!(storage.last_message_id().unwrap(), Some(history[0].id));
     }
 }
@@ -1,10 +1,20 @@
 use anyhow::Result;
+use chrono::Utc;
+use common::error::ErrorCode;
+use common::handshake::Capability;
+use common::message::{Author, ChatMessage, Member, Presence};
+use common::validation::name_key;
+use log::info;
 use std::sync::{Arc, Mutex};
-use tokio::sync::oneshot::{self};
+use std::time::Instant;
+use tokio::sync::{
+    mpsc::{self},
+    oneshot::{self},
+};
 
-use crate::server::{Message, MessageType, Room, Server};
+use crate::server::{auth, ListOption, Message, MessageType, Room, Server, SessionState};
+use crate::storage::{Account, RoomRecord};
 
-#[derive(Clone)]
 pub enum ServerEvent {
     Register {
         id: u64,
@@ -25,12 +35,33 @@ pub enum ServerEvent {
     },
     List {
         id: u64,
-        opt: String,
+        opt: ListOption,
+    },
+    Members {
+        id: u64,
+        room: String,
     },
     DropSession {
         id: u64,
     },
+    // The connection of a session was lost. Sessions holding a resume
+    // token are kept, along with the messages sent to them, until
+    // resumed or the grace period runs out
+    DetachSession {
+        id: u64,
+        session_rx: mpsc::UnboundedReceiver<Message>,
+    },
+    ExpireSession {
+        id: u64,
+    },
+    // Hands the session of the token over to the connection of
+    // session `id`, taking it from its old connection if need be
+    Resume {
+        id: u64,
+        token: String,
+    },
     CreateRoom {
+        id: u64,
         room: String,
     },
     PrivMsg {
@@ -42,24 +73,90 @@ pub enum ServerEvent {
         id: u64,
         new_username: String,
     },
+    SetCapabilities {
+        id: u64,
+        capabilities: Vec<Capability>,
+    },
+    FetchHistory {
+        id: u64,
+        room: String,
+        before: Option<u64>,
+        limit: usize,
+    },
+    CreateAccount {
+        id: u64,
+        username: String,
+        password_hash: String,
+    },
+    GetAccount {
+        id: u64,
+        username: String,
+    },
+    // Sent once the password has been verified against the account
+    Login {
+        id: u64,
+        username: String,
+    },
+    // The password did not match the account
+    LoginFailed {
+        id: u64,
+        username: String,
+    },
+}
+
+impl ServerEvent {
+    // Session the event is sent for, along with the states it is
+    // handled in. Events about the connection itself are always handled
+    fn accepted_states(&self) -> Option<(u64, &'static [SessionState])> {
+        match self {
+            ServerEvent::Register { id, .. }
+            | ServerEvent::Resume { id, .. }
+            | ServerEvent::SetCapabilities { id, .. } => Some((*id, &[SessionState::Connected])),
+            // Guests may log in to an account later on
+            ServerEvent::CreateAccount { id, .. }
+            | ServerEvent::GetAccount { id, .. }
+            | ServerEvent::Login { id, .. }
+            | ServerEvent::LoginFailed { id, .. } => {
+                Some((*id, &[SessionState::Connected, SessionState::Registered]))
+            }
+            // Users are known by name to everyone they deal with
+            ServerEvent::JoinRoom { id, .. }
+            | ServerEvent::LeaveRoom { id, .. }
+            | ServerEvent::SendTo { id, .. }
+            | ServerEvent::List { id, .. }
+            | ServerEvent::Members { id, .. }
+            | ServerEvent::CreateRoom { id, .. }
+            | ServerEvent::PrivMsg { id, .. }
+            | ServerEvent::ChangeName { id, .. }
+            | ServerEvent::FetchHistory { id, .. } => Some((*id, &[SessionState::Registered])),
+            ServerEvent::DropSession { .. }
+            | ServerEvent::DetachSession { .. }
+            | ServerEvent::ExpireSession { .. } => None,
+        }
+    }
 }
 
-#[derive(Clone)]
 pub enum ServerReply {
     Registered {
         username: String,
     },
     Joined {
         room: String,
+        history: Vec<ChatMessage>,
+        members: Vec<Member>,
+    },
+    Members {
+        room: String,
+        members: Vec<Member>,
     },
     ListingUsers {
-        content: String,
+        users: Vec<String>,
     },
     ListingRooms {
-        content: String,
+        rooms: Vec<String>,
     },
     ListingUserRooms {
-        content: String,
+        rooms: Vec<String>,
     },
     LeftRoom {
         room: String,
@@ -67,14 +164,34 @@ pub enum ServerReply {
     CreatedRoom {
         room: String,
     },
-    MessagedUser,
-    MessagedRoom,
+    // `to` is the name of the receiver as they registered it
+    MessagedUser {
+        to: String,
+        message: ChatMessage,
+    },
+    MessagedRoom {
+        id: u64,
+    },
     NameChanged {
         new_username: String,
         old_username: String,
     },
+    History {
+        room: String,
+        messages: Vec<ChatMessage>,
+        more: bool,
+    },
+    Account {
+        password_hash: Option<String>,
+    },
+    Resumed {
+        id: u64,
+        username: String,
+        rooms: Vec<String>,
+        session_rx: mpsc::UnboundedReceiver<Message>,
+    },
     Failed {
-        error: String,
+        code: ErrorCode,
     },
 }
 
@@ -83,19 +200,41 @@ pub async fn handle_event(
     reply_tx: oneshot::Sender<ServerReply>,
     server: &mut Server,
 ) -> Result<()> {
+    if let Err(code) = check_state(server, &event) {
+        let _ = reply_tx.send(ServerReply::Failed { code });
+
+        return Ok(());
+    }
+
     match event {
         ServerEvent::Register { id, username } => {
-            if server.username_to_id.contains_key(&username) {
+            let key = name_key(&username);
+
+            if !server.allow_guests {
+                let reply = ServerReply::Failed {
+                    code: ErrorCode::GuestsDisabled,
+                };
+
+                let _ = reply_tx.send(reply);
+            } else if server.accounts.contains_key(&key) {
+                let reply = ServerReply::Failed {
+                    code: ErrorCode::NameReserved,
+                };
+
+                let _ = reply_tx.send(reply);
+            } else if server.username_to_id.contains_key(&key) {
                 let reply = ServerReply::Failed {
-                    error: String::from("Username already exists"),
+                    code: ErrorCode::NameTaken,
                 };
 
                 let _ = reply_tx.send(reply);
             } else if let Some((session, _)) = server.sessions.get_mut(&id) {
                 session.set_username(&username);
+                session.state = SessionState::Registered;
 
-                server.username_to_id.insert(username.clone(), id);
+                server.username_to_id.insert(key, id);
                 server.id_to_username.insert(id, username.clone());
+                issue_resume_token(server, id);
 
                 let reply = ServerReply::Registered { username };
 
@@ -103,18 +242,46 @@ pub async fn handle_event(
             }
         }
         ServerEvent::ChangeName { id, new_username } => {
-            if server.username_to_id.contains_key(&new_username) {
+            let key = name_key(&new_username);
+            let old_username = server
+                .sessions
+                .get(&id)
+                .map(|(session, _)| session.username.clone())
+                .unwrap_or_default();
+
+            if server
+                .sessions
+                .get(&id)
+                .is_some_and(|(session, _)| session.logged_in)
+                && name_key(&old_username) != key
+            {
+                // Accounts may only change the case of their name
                 let reply = ServerReply::Failed {
-                    error: String::from("Username already exists"),
+                    code: ErrorCode::NameLocked,
                 };
 
                 let _ = reply_tx.send(reply);
-            } else if let Some((session, _)) = server.sessions.get_mut(&id) {
-                let old_username = server.id_to_username[&id].clone();
+            } else if server.accounts.contains_key(&key) && name_key(&old_username) != key {
+                let reply = ServerReply::Failed {
+                    code: ErrorCode::NameReserved,
+                };
 
-                *server.id_to_username.get_mut(&id).unwrap() = new_username.clone();
-                server.username_to_id.remove(&old_username);
-                server.username_to_id.insert(new_username.clone(), id);
+                let _ = reply_tx.send(reply);
+            } else if server
+                .username_to_id
+                .get(&key)
+                .is_some_and(|owner| *owner != id)
+            {
+                // Only the owner may change the case of a name
+                let reply = ServerReply::Failed {
+                    code: ErrorCode::NameTaken,
+                };
+
+                let _ = reply_tx.send(reply);
+            } else if let Some((session, _)) = server.sessions.get_mut(&id) {
+                server.id_to_username.insert(id, new_username.clone());
+                server.username_to_id.remove(&name_key(&old_username));
+                server.username_to_id.insert(key, id);
 
                 session.set_username(&new_username);
 
@@ -126,66 +293,74 @@ pub async fn handle_event(
                 let _ = reply_tx.send(reply);
             }
         }
-        ServerEvent::List { id, opt } => match opt.as_ref() {
-            "users" => {
+        ServerEvent::List { id, opt } => match opt {
+            ListOption::Users => {
                 let users = server
-                    .username_to_id
-                    .clone()
-                    .into_keys()
-                    .map(|s| s.to_string())
-                    .collect::<Vec<String>>()
-                    .join(",");
+                    .id_to_username
+                    .values()
+                    .cloned()
+                    .collect::<Vec<String>>();
 
-                let reply = ServerReply::ListingUsers { content: users };
+                let reply = ServerReply::ListingUsers { users };
 
                 let _ = reply_tx.send(reply);
             }
-            "rooms" => {
+            ListOption::Rooms => {
                 if let Some((session, _)) = server.sessions.get_mut(&id) {
-                    let user_rooms = session.joined_rooms();
+                    let rooms = session.joined_rooms();
 
-                    let reply = ServerReply::ListingUserRooms {
-                        content: user_rooms,
-                    };
+                    let reply = ServerReply::ListingUserRooms { rooms };
 
                     let _ = reply_tx.send(reply);
                 }
             }
-            "allrooms" => {
-                let rooms = server.room_manager.get_rooms();
-                let rooms = rooms
+            ListOption::AllRooms => {
+                let rooms = server
+                    .room_manager
+                    .get_rooms()
                     .into_iter()
-                    .map(|s| s.to_string())
-                    .collect::<Vec<String>>()
-                    .join(",");
+                    .collect::<Vec<String>>();
 
-                let reply = ServerReply::ListingRooms { content: rooms };
+                let reply = ServerReply::ListingRooms { rooms };
 
                 let _ = reply_tx.send(reply);
             }
-            _ => {
-                let reply = ServerReply::Failed {
-                    error: String::from("Invalid option"),
-                };
+        },
+        ServerEvent::Members { room, .. } => {
+            let room = server.room_manager.find(&room).unwrap_or(room);
 
-                let _ = reply_tx.send(reply);
+            match server.room_manager.members(&room) {
+                Ok(members) => {
+                    let _ = reply_tx.send(ServerReply::Members { room, members });
+                }
+                Err(code) => {
+                    let _ = reply_tx.send(ServerReply::Failed { code });
+                }
             }
-        },
+        }
         ServerEvent::JoinRoom { id, room } => {
+            let room = server.room_manager.find(&room).unwrap_or(room);
+
             if let Some((session, _)) = server.sessions.get_mut(&id) {
                 match session.join_room(&room, &server.room_manager).await {
-                    Ok(()) => {
-                        let _ = reply_tx.send(ServerReply::Joined { room });
+                    Ok((history, members)) => {
+                        let reply = ServerReply::Joined {
+                            room,
+                            history,
+                            members,
+                        };
+
+                        let _ = reply_tx.send(reply);
                     }
-                    Err(e) => {
-                        let _ = reply_tx.send(ServerReply::Failed {
-                            error: e.to_string(),
-                        });
+                    Err(code) => {
+                        let _ = reply_tx.send(ServerReply::Failed { code });
                     }
                 }
             }
         }
         ServerEvent::LeaveRoom { id, room } => {
+            let room = server.room_manager.find(&room).unwrap_or(room);
+
             if let Some((session, _)) = server.sessions.get_mut(&id) {
                 match session.leave_room(&room) {
                     Ok(()) => {
@@ -193,27 +368,31 @@ pub async fn handle_event(
 
                         let _ = reply_tx.send(reply);
                     }
-                    Err(e) => {
-                        let reply = ServerReply::Failed {
-                            error: e.to_string(),
-                        };
+                    Err(code) => {
+                        let reply = ServerReply::Failed { code };
 
                         let _ = reply_tx.send(reply);
                     }
                 }
             }
         }
-        ServerEvent::CreateRoom { room } => {
-            let existing_rooms = server.room_manager.get_rooms();
-
-            if existing_rooms.contains(&room) {
+        ServerEvent::CreateRoom { id, room } => {
+            if server.room_manager.find(&room).is_some() {
                 let reply = ServerReply::Failed {
-                    error: String::from("Room already exists"),
+                    code: ErrorCode::RoomExists,
                 };
 
                 let _ = reply_tx.send(reply);
             } else {
-                let new_room = Arc::new(Mutex::new(Room::new(&room)));
+                let record = RoomRecord {
+                    name: room.clone(),
+                    created_by: server.id_to_username.get(&id).cloned(),
+                    created_at: Utc::now(),
+                };
+
+                server.storage.add_room(record);
+
+                let new_room = Arc::new(Mutex::new(Room::new(&room, server.room_config)));
                 server.room_manager.add_room(new_room, room.clone());
 
                 let reply = ServerReply::CreatedRoom { room };
@@ -222,15 +401,32 @@ pub async fn handle_event(
             }
         }
         ServerEvent::SendTo { id, room, content } => {
-            if let Some((session, _)) = server.sessions.get_mut(&id) {
-                match session.send_room_message(&room, &content).await {
-                    Ok(()) => {
-                        let _ = reply_tx.send(ServerReply::MessagedRoom);
-                    }
-                    Err(e) => {
-                        let _ = reply_tx.send(ServerReply::Failed {
-                            error: e.to_string(),
-                        });
+            let room = server.room_manager.find(&room).unwrap_or(room);
+
+            // Ids are only handed out to messages which get sent
+            if !server
+                .sessions
+                .get(&id)
+                .is_some_and(|(session, _)| session.in_room(&room))
+            {
+                let reply = ServerReply::Failed {
+                    code: ErrorCode::NotInRoom,
+                };
+
+                let _ = reply_tx.send(reply);
+            } else {
+                let message_id = server.next_message_id();
+
+                if let Some((session, _)) = server.sessions.get(&id) {
+                    match session.send_room_message(&room, message_id, content).await {
+                        Ok(chat_message) => {
+                            server.storage.add_message(&room, chat_message);
+
+                            let _ = reply_tx.send(ServerReply::MessagedRoom { id: message_id });
+                        }
+                        Err(code) => {
+                            let _ = reply_tx.send(ServerReply::Failed { code });
+                        }
                     }
                 }
             }
@@ -240,47 +436,731 @@ pub async fn handle_event(
             username,
             content,
         } => {
-            if !server.username_to_id.contains_key(&username) {
-                let reply = ServerReply::Failed {
-                    error: String::from("No such user"),
+            let sender = server
+                .sessions
+                .get(&id)
+                .map(|(session, _)| session.username.clone())
+                .unwrap_or_default();
+            let receiver = server
+                .username_to_id
+                .get(&name_key(&username))
+                .and_then(|receiver_id| server.sessions.get(receiver_id))
+                .map(|(session, session_tx)| (session.username.clone(), session_tx.clone()));
+
+            if let Some((receiver, receiving_session_tx)) = receiver {
+                let author = Author {
+                    id,
+                    username: sender,
+                };
+                let chat_message = ChatMessage::new(server.next_message_id(), author, content);
+
+                let message = Message::build(MessageType::IncomingMsg(chat_message.clone()), id);
+                let _ = receiving_session_tx.send(message);
+
+                let reply = ServerReply::MessagedUser {
+                    to: receiver,
+                    message: chat_message,
                 };
 
                 let _ = reply_tx.send(reply);
             } else {
-                let sender = &server.id_to_username[&id];
-                let receiver_id = server.username_to_id[&username];
+                let reply = ServerReply::Failed {
+                    code: ErrorCode::NoSuchUser,
+                };
 
-                if let Some((_, receiving_session_tx)) = server.sessions.get_mut(&receiver_id) {
-                    if let Ok(message) = Message::build(
-                        MessageType::IncomingMsg,
-                        id,
-                        None,
-                        Some(format!("from {sender}: {content}")),
-                    ) {
-                        let _ = receiving_session_tx.send(message);
+                let _ = reply_tx.send(reply);
+            }
+        }
+        ServerEvent::FetchHistory {
+            id,
+            room,
+            before,
+            limit,
+        } => {
+            let room = server.room_manager.find(&room).unwrap_or(room);
 
-                        let reply = ServerReply::MessagedUser;
+            if let Some((session, _)) = server.sessions.get(&id) {
+                match session.room_history(&room, before, limit) {
+                    Ok((messages, more)) => {
+                        let reply = ServerReply::History {
+                            room,
+                            messages,
+                            more,
+                        };
 
                         let _ = reply_tx.send(reply);
                     }
-                } else {
+                    Err(code) => {
+                        let _ = reply_tx.send(ServerReply::Failed { code });
+                    }
+                }
+            }
+        }
+        ServerEvent::CreateAccount {
+            id,
+            username,
+            password_hash,
+        } => {
+            let key = name_key(&username);
+
+            if server.accounts.contains_key(&key) {
+                let reply = ServerReply::Failed {
+                    code: ErrorCode::NameTaken,
+                };
+
+                let _ = reply_tx.send(reply);
+            } else if let Err(code) = can_log_in(server, id, &key) {
+                let _ = reply_tx.send(ServerReply::Failed { code });
+            } else {
+                let account = Account {
+                    username: username.clone(),
+                    password_hash,
+                    created_at: Utc::now(),
+                };
+
+                server.accounts.insert(key, account.clone());
+                server.storage.add_account(account);
+
+                let _ = reply_tx.send(log_in(server, id, username));
+            }
+        }
+        ServerEvent::GetAccount { username, .. } => {
+            let key = name_key(&username);
+
+            // Guessing passwords gets slower with every wrong one
+            if server
+                .login_failures
+                .get(&key)
+                .is_some_and(|(failures, failed_at)| {
+                    failed_at.elapsed() < auth::login_backoff(*failures)
+                })
+            {
+                let reply = ServerReply::Failed {
+                    code: ErrorCode::RateLimited,
+                };
+
+                let _ = reply_tx.send(reply);
+            } else {
+                let password_hash = server
+                    .accounts
+                    .get(&key)
+                    .map(|account| account.password_hash.clone());
+
+                let _ = reply_tx.send(ServerReply::Account { password_hash });
+            }
+        }
+        ServerEvent::LoginFailed { username, .. } => {
+            let key = name_key(&username);
+
+            // Only accounts are tracked, so made up names take no memory
+            if server.accounts.contains_key(&key) {
+                let (failures, failed_at) = server
+                    .login_failures
+                    .entry(key)
+                    .or_insert((0, Instant::now()));
+
+                *failures = failures.saturating_add(1);
+                *failed_at = Instant::now();
+            }
+        }
+        ServerEvent::Login { id, username } => {
+            let key = name_key(&username);
+
+            match server.accounts.get(&key) {
+                Some(account) => {
+                    let username = account.username.clone();
+
+                    match can_log_in(server, id, &key) {
+                        Ok(()) => {
+                            server.login_failures.remove(&key);
+
+                            let _ = reply_tx.send(log_in(server, id, username));
+                        }
+                        Err(code) => {
+                            let _ = reply_tx.send(ServerReply::Failed { code });
+                        }
+                    }
+                }
+                None => {
                     let reply = ServerReply::Failed {
-                        error: String::from("Receiving session not found"),
+                        code: ErrorCode::InvalidCredentials,
                     };
 
                     let _ = reply_tx.send(reply);
                 }
             }
         }
+        ServerEvent::SetCapabilities { id, capabilities } => {
+            if let Some((session, _)) = server.sessions.get_mut(&id) {
+                session.set_capabilities(capabilities);
+            }
+        }
         ServerEvent::DropSession { id } => {
-            if server.id_to_username.contains_key(&id) {
-                let username = &server.id_to_username[&id].clone();
-                server.id_to_username.remove(&id);
-                server.username_to_id.remove(username);
+            drop_session(server, id);
+        }
+        ServerEvent::DetachSession { id, session_rx } => match server.pending_resumes.remove(&id) {
+            Some((resuming_id, resume_tx)) => {
+                resume(server, resuming_id, id, session_rx, resume_tx);
+            }
+            None => detach_session(server, id, session_rx),
+        },
+        ServerEvent::ExpireSession { id } => {
+            // The session may have been resumed and detached
+            // again since the timer was started
+            let expired = server
+                .detached
+                .get(&id)
+                .is_some_and(|(_, detached_at)| detached_at.elapsed() >= server.resume_grace);
+
+            if expired {
+                info!("[*] Session {id} was not resumed in time");
+
+                server.detached.remove(&id);
+                drop_session(server, id);
+            }
+        }
+        ServerEvent::Resume { id, token } => {
+            let resumed_id = server.resume_tokens.get(&token).copied();
+            let detached = resumed_id.and_then(|resumed_id| server.detached.remove(&resumed_id));
+
+            match (resumed_id, detached) {
+                (Some(resumed_id), Some((session_rx, _))) => {
+                    resume(server, id, resumed_id, session_rx, reply_tx);
+                }
+                // The client noticed its connection was gone before the server
+                // did. The old connection is closed and hands the session over
+                // as it detaches
+                (Some(resumed_id), None) if server.sessions.contains_key(&resumed_id) => {
+                    info!("[*] Taking session {resumed_id} over from its old connection");
+
+                    if let Some((session, _)) = server.sessions.get(&resumed_id) {
+                        session.takeover.notify_one();
+                    }
+
+                    // Only the latest connection gets the session
+                    if let Some((_, replaced_tx)) =
+                        server.pending_resumes.insert(resumed_id, (id, reply_tx))
+                    {
+                        let reply = ServerReply::Failed {
+                            code: ErrorCode::InvalidToken,
+                        };
+
+                        let _ = replaced_tx.send(reply);
+                    }
+                }
+                _ => {
+                    let reply = ServerReply::Failed {
+                        code: ErrorCode::InvalidToken,
+                    };
+
+                    let _ = reply_tx.send(reply);
+                }
             }
-            server.sessions.remove(&id);
         }
     }
 
     Ok(())
 }
+
+// Refuses events sent for a session which isn't in a state to
+// make them, such as chatting before having registered
+fn check_state(server: &Server, event: &ServerEvent) -> Result<(), ErrorCode> {
+    let Some((id, accepted)) = event.accepted_states() else {
+        return Ok(());
+    };
+
+    // Sessions already dropped are past closing
+    let state = server
+        .sessions
+        .get(&id)
+        .map_or(SessionState::Closing, |(session, _)| session.state);
+
+    if accepted.contains(&state) {
+        return Ok(());
+    }
+
+    match state {
+        SessionState::Connected => Err(ErrorCode::NotRegistered),
+        SessionState::Registered => Err(ErrorCode::AlreadyRegistered),
+        SessionState::Closing => Err(ErrorCode::SessionClosing),
+    }
+}
+
+// Sessions log in to a single account, whose name must not be
+// in use by another session
+fn can_log_in(server: &Server, id: u64, key: &str) -> Result<(), ErrorCode> {
+    if server
+        .sessions
+        .get(&id)
+        .is_some_and(|(session, _)| session.logged_in)
+    {
+        return Err(ErrorCode::NameLocked);
+    }
+
+    if server
+        .username_to_id
+        .get(key)
+        .is_some_and(|owner| *owner != id)
+    {
+        return Err(ErrorCode::NameTaken);
+    }
+
+    Ok(())
+}
+
+// Gives the session the name of the account it logged in to,
+// replacing any guest name it was registered with
+fn log_in(server: &mut Server, id: u64, username: String) -> ServerReply {
+    if let Some(old_username) = server.id_to_username.remove(&id) {
+        server.username_to_id.remove(&name_key(&old_username));
+    }
+
+    server.username_to_id.insert(name_key(&username), id);
+    server.id_to_username.insert(id, username.clone());
+
+    if let Some((session, _)) = server.sessions.get_mut(&id) {
+        session.set_username(&username);
+        session.state = SessionState::Registered;
+        session.logged_in = true;
+    }
+
+    issue_resume_token(server, id);
+
+    ServerReply::Registered { username }
+}
+
+// Sends the session a resume token once it has registered, provided
+// resuming is enabled and the client supports it
+fn issue_resume_token(server: &mut Server, id: u64) {
+    if server.resume_grace.is_zero() {
+        return;
+    }
+
+    if let Some((session, session_tx)) = server.sessions.get_mut(&id) {
+        if session.resume_token.is_some() || !session.capabilities.contains(&Capability::Resume) {
+            return;
+        }
+
+        let token = auth::generate_token();
+
+        server.resume_tokens.insert(token.clone(), id);
+        session.resume_token = Some(token.clone());
+
+        let _ = session_tx.send(Message::build(MessageType::SessionToken { token }, 0));
+    }
+}
+
+// Keeps the session of a lost connection around for resuming
+// if it holds a token, and drops it otherwise
+fn detach_session(server: &mut Server, id: u64, session_rx: mpsc::UnboundedReceiver<Message>) {
+    let resumable = server
+        .sessions
+        .get(&id)
+        .is_some_and(|(session, _)| session.resume_token.is_some());
+
+    if !resumable {
+        drop_session(server, id);
+        return;
+    }
+
+    info!("[*] Keeping session {id} for resuming");
+
+    if let Some((session, _)) = server.sessions.get_mut(&id) {
+        session.set_presence(Presence::Away);
+        session.state = SessionState::Closing;
+    }
+
+    server.detached.insert(id, (session_rx, Instant::now()));
+
+    tokio::spawn({
+        let to_server_tx = server.to_server_tx.clone();
+        let grace = server.resume_grace;
+
+        async move {
+            tokio::time::sleep(grace).await;
+
+            let (tx, _rx) = oneshot::channel::<ServerReply>();
+            let _ = to_server_tx.send((ServerEvent::ExpireSession { id }, tx));
+        }
+    });
+}
+
+// Hands the session `resumed_id` over to the connection of session
+// `id`, whose own session is replaced by the resumed one
+fn resume(
+    server: &mut Server,
+    id: u64,
+    resumed_id: u64,
+    session_rx: mpsc::UnboundedReceiver<Message>,
+    reply_tx: oneshot::Sender<ServerReply>,
+) {
+    // The resumed session takes over the capabilities negotiated on
+    // the connection, and the means to be taken over from it in turn
+    let Some((connection_session, _)) = server.sessions.remove(&id) else {
+        detach_session(server, resumed_id, session_rx);
+        return;
+    };
+
+    info!("[*] Resuming session {resumed_id}");
+
+    if let Some((session, _)) = server.sessions.get_mut(&resumed_id) {
+        session.set_capabilities(connection_session.capabilities);
+        session.takeover = connection_session.takeover;
+        session.set_presence(Presence::Online);
+        session.state = SessionState::Registered;
+
+        let reply = ServerReply::Resumed {
+            id: resumed_id,
+            username: session.username.clone(),
+            rooms: session.joined_rooms(),
+            session_rx,
+        };
+
+        let _ = reply_tx.send(reply);
+    }
+}
+
+// Forgets the session for good, freeing its name
+fn drop_session(server: &mut Server, id: u64) {
+    if let Some(username) = server.id_to_username.remove(&id) {
+        server.username_to_id.remove(&name_key(&username));
+    }
+
+    // Connections waiting to resume the session won't get it
+    if let Some((_, resume_tx)) = server.pending_resumes.remove(&id) {
+        let reply = ServerReply::Failed {
+            code: ErrorCode::InvalidToken,
+        };
+
+        let _ = resume_tx.send(reply);
+    }
+
+    if let Some((mut session, _)) = server.sessions.remove(&id) {
+        session.leave_rooms();
+
+        if let Some(token) = session.resume_token {
+            server.resume_tokens.remove(&token);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Config, ServerConfig};
+    use crate::storage::MemoryStorage;
+    use clap::Parser;
+    use std::time::Duration;
+
+    fn server() -> Server {
+        let args = ServerConfig::parse_from(["chatserver", "--port", "0"]);
+
+        Server::new(Config::load(args).unwrap(), Box::new(MemoryStorage::new())).unwrap()
+    }
+
+    async fn send(server: &mut Server, event: ServerEvent) -> oneshot::Receiver<ServerReply> {
+        let (tx, rx) = oneshot::channel::<ServerReply>();
+        handle_event(event, tx, server).await.unwrap();
+
+        rx
+    }
+
+    // Registers a session which can be resumed, handing back its token
+    async fn register(server: &mut Server, id: u64, username: &str) -> String {
+        let capabilities = vec![Capability::Resume];
+        send(server, ServerEvent::SetCapabilities { id, capabilities }).await;
+
+        let username = username.to_string();
+        let reply = send(server, ServerEvent::Register { id, username }).await;
+        assert!(matches!(reply.await, Ok(ServerReply::Registered { .. })));
+
+        server.sessions[&id].0.resume_token.clone().unwrap()
+    }
+
+    // One of every event sent on behalf of a session
+    fn requests(id: u64) -> Vec<ServerEvent> {
+        let name = || String::from("bob");
+        let room = || String::from("main");
+
+        vec![
+            ServerEvent::Register {
+                id,
+                username: name(),
+            },
+            ServerEvent::Resume {
+                id,
+                token: String::from("token"),
+            },
+            ServerEvent::SetCapabilities {
+                id,
+                capabilities: Vec::new(),
+            },
+            ServerEvent::CreateAccount {
+                id,
+                username: name(),
+                password_hash: String::from("hash"),
+            },
+            ServerEvent::GetAccount {
+                id,
+                username: name(),
+            },
+            ServerEvent::Login {
+                id,
+                username: name(),
+            },
+            ServerEvent::LoginFailed {
+                id,
+                username: name(),
+            },
+            ServerEvent::JoinRoom { id, room: room() },
+            ServerEvent::LeaveRoom { id, room: room() },
+            ServerEvent::SendTo {
+                id,
+                room: room(),
+                content: String::from("hello"),
+            },
+            ServerEvent::List {
+                id,
+                opt: ListOption::Users,
+            },
+            ServerEvent::Members { id, room: room() },
+            ServerEvent::CreateRoom {
+                id,
+                room: String::from("other"),
+            },
+            ServerEvent::PrivMsg {
+                id,
+                username: name(),
+                content: String::from("hello"),
+            },
+            ServerEvent::ChangeName {
+                id,
+                new_username: name(),
+            },
+            ServerEvent::FetchHistory {
+                id,
+                room: room(),
+                before: None,
+                limit: 10,
+            },
+        ]
+    }
+
+    // Every event refused in the session's state is answered with the
+    // reason, without being handled
+    async fn check_refused(server: &mut Server, id: u64, code: ErrorCode) {
+        let state = server
+            .sessions
+            .get(&id)
+            .map_or(SessionState::Closing, |(session, _)| session.state);
+
+        for event in requests(id) {
+            let (_, accepted) = event.accepted_states().unwrap();
+            if accepted.contains(&state) {
+                assert_eq!(check_state(server, &event), Ok(()));
+                continue;
+            }
+
+            assert_eq!(check_state(server, &event), Err(code));
+
+            let reply = send(server, event).await;
+            assert!(
+                matches!(reply.await, Ok(ServerReply::Failed { code: refused }) if refused == code)
+            );
+        }
+    }
+
+    #[test]
+    fn connection_events_are_always_handled() {
+        let (_, session_rx) = mpsc::unbounded_channel::<Message>();
+
+        assert!(ServerEvent::DropSession { id: 1 }
+            .accepted_states()
+            .is_none());
+        assert!(ServerEvent::ExpireSession { id: 1 }
+            .accepted_states()
+            .is_none());
+        assert!(ServerEvent::DetachSession { id: 1, session_rx }
+            .accepted_states()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn session_lifecycle() {
+        let mut server = server();
+        let (id, session_rx, _) = server.add_session();
+
+        // Connected: only registering, logging in and resuming
+        assert_eq!(server.sessions[&id].0.state, SessionState::Connected);
+        check_refused(&mut server, id, ErrorCode::NotRegistered).await;
+
+        // Registered: anything but registering again
+        register(&mut server, id, "alice").await;
+        assert_eq!(server.sessions[&id].0.state, SessionState::Registered);
+        check_refused(&mut server, id, ErrorCode::AlreadyRegistered).await;
+
+        let reply = send(
+            &mut server,
+            ServerEvent::JoinRoom {
+                id,
+                room: String::from("main"),
+            },
+        )
+        .await;
+        assert!(matches!(reply.await, Ok(ServerReply::Joined { .. })));
+
+        // Closing: nothing until resumed
+        send(&mut server, ServerEvent::DetachSession { id, session_rx }).await;
+        assert_eq!(server.sessions[&id].0.state, SessionState::Closing);
+        check_refused(&mut server, id, ErrorCode::SessionClosing).await;
+
+        // Sessions gone for good are past closing
+        send(&mut server, ServerEvent::DropSession { id }).await;
+        assert!(!server.sessions.contains_key(&id));
+        check_refused(&mut server, id, ErrorCode::SessionClosing).await;
+    }
+
+    #[tokio::test]
+    async fn resumed_sessions_are_registered_again() {
+        let mut server = server();
+
+        let (old_id, old_rx, _) = server.add_session();
+        let token = register(&mut server, old_id, "alice").await;
+        send(
+            &mut server,
+            ServerEvent::DetachSession {
+                id: old_id,
+                session_rx: old_rx,
+            },
+        )
+        .await;
+
+        let (new_id, _new_rx, _) = server.add_session();
+        let reply = send(&mut server, ServerEvent::Resume { id: new_id, token }).await;
+        assert!(matches!(reply.await, Ok(ServerReply::Resumed { .. })));
+
+        assert_eq!(server.sessions[&old_id].0.state, SessionState::Registered);
+        check_refused(&mut server, old_id, ErrorCode::AlreadyRegistered).await;
+        // The connection's own session is gone
+        check_refused(&mut server, new_id, ErrorCode::SessionClosing).await;
+    }
+
+    #[tokio::test]
+    async fn resumes_detached_session() {
+        let mut server = server();
+
+        let (old_id, old_rx, _) = server.add_session();
+        let token = register(&mut server, old_id, "alice").await;
+        let event = ServerEvent::DetachSession {
+            id: old_id,
+            session_rx: old_rx,
+        };
+        send(&mut server, event).await;
+        assert_eq!(server.sessions[&old_id].0.state, SessionState::Closing);
+
+        let (new_id, _new_rx, _) = server.add_session();
+        let reply = send(&mut server, ServerEvent::Resume { id: new_id, token }).await;
+
+        match reply.await {
+            Ok(ServerReply::Resumed { id, username, .. }) => {
+                assert_eq!(id, old_id);
+                assert_eq!(username, "alice");
+            }
+            _ => panic!("expected the session to be resumed"),
+        }
+        assert_eq!(server.sessions[&old_id].0.state, SessionState::Registered);
+        assert!(!server.sessions.contains_key(&new_id));
+    }
+
+    #[tokio::test]
+    async fn resume_while_old_connection_is_still_attached() {
+        let mut server = server();
+
+        let (old_id, old_rx, old_takeover) = server.add_session();
+        let token = register(&mut server, old_id, "alice").await;
+
+        let (new_id, _new_rx, new_takeover) = server.add_session();
+        let mut reply = send(&mut server, ServerEvent::Resume { id: new_id, token }).await;
+
+        // The old connection is told to let go of the session first
+        tokio::time::timeout(Duration::from_secs(1), old_takeover.notified())
+            .await
+            .unwrap();
+        assert!(reply.try_recv().is_err());
+
+        // and hands it over as it detaches
+        let event = ServerEvent::DetachSession {
+            id: old_id,
+            session_rx: old_rx,
+        };
+        send(&mut server, event).await;
+
+        match reply.await {
+            Ok(ServerReply::Resumed { id, username, .. }) => {
+                assert_eq!(id, old_id);
+                assert_eq!(username, "alice");
+            }
+            _ => panic!("expected the session to be resumed"),
+        }
+
+        let (session, _) = &server.sessions[&old_id];
+        assert_eq!(session.state, SessionState::Registered);
+        assert!(!server.detached.contains_key(&old_id));
+        assert!(!server.sessions.contains_key(&new_id));
+
+        // The new connection is the one taken over from next time
+        assert!(Arc::ptr_eq(&session.takeover, &new_takeover));
+    }
+
+    #[tokio::test]
+    async fn resume_with_unknown_token_fails() {
+        let mut server = server();
+
+        let (id, _rx, _) = server.add_session();
+        let token = String::from("not a token");
+        let reply = send(&mut server, ServerEvent::Resume { id, token }).await;
+
+        assert!(matches!(
+            reply.await,
+            Ok(ServerReply::Failed {
+                code: ErrorCode::InvalidToken
+            })
+        ));
+        assert_eq!(server.sessions[&id].0.state, SessionState::Connected);
+    }
+
+    #[tokio::test]
+    async fn private_messages_name_the_receiver_as_registered() {
+        let mut server = server();
+
+        let (alice, mut alice_rx, _) = server.add_session();
+        register(&mut server, alice, "Alice").await;
+        let (bob, _bob_rx, _) = server.add_session();
+        register(&mut server, bob, "bob").await;
+
+        let reply = send(
+            &mut server,
+            ServerEvent::PrivMsg {
+                id: bob,
+                username: String::from("ALICE"),
+                content: String::from("hi"),
+            },
+        )
+        .await;
+
+        match reply.await {
+            Ok(ServerReply::MessagedUser { to, message }) => {
+                assert_eq!(to, "Alice");
+                assert_eq!(message.author.username, "bob");
+            }
+            _ => panic!("Expected MessagedUser"),
+        }
+
+        // Anything else sent to Alice comes before
+        let incoming = std::iter::from_fn(|| alice_rx.try_recv().ok()).last();
+        assert!(matches!(
+            incoming.map(|message| message.message_type),
+            Some(MessageType::IncomingMsg(message)) if message.text == "hi"
+        ));
+    }
+}
@@ -1,17 +1,48 @@
-use anyhow::{anyhow, Result};
+use common::error::ErrorCode;
+use common::handshake::Capability;
+use common::message::{Author, ChatMessage, Member, Presence};
+use log::warn;
 use std::collections::HashMap;
+use std::sync::Arc;
 use tokio::{
-    sync::mpsc::{self},
+    sync::{
+        broadcast::error::RecvError,
+        mpsc::{self},
+        Notify,
+    },
     task::{AbortHandle, JoinSet},
 };
 
 use crate::room::room_manager::RoomManager;
-use crate::room::UserHandle;
+use crate::room::{Membership, UserHandle};
 use crate::server::{Message, MessageType};
 
+// Where a session is in its lifecycle, which decides
+// the requests the server handles for it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SessionState {
+    // Connected without a name yet
+    Connected,
+    // Known by name to other users
+    Registered,
+    // The connection was lost or the server is shutting down.
+    // Sessions kept for resuming are registered again once resumed
+    Closing,
+}
+
 pub struct Session {
     pub id: u64,
+    pub state: SessionState,
     pub username: String,
+    // Set once logged in to an account, which then owns the name
+    pub logged_in: bool,
+    // Token the client can resume this session with, if any
+    pub resume_token: Option<String>,
+    pub capabilities: Vec<Capability>,
+    // Tells the connection serving the session to let go of it,
+    // once another connection resumes the session
+    pub takeover: Arc<Notify>,
+    presence: Presence,
     rooms: HashMap<String, (UserHandle, AbortHandle)>,
     room_task_set: JoinSet<()>, // Threads for receivng room messages
     to_session_tx: mpsc::UnboundedSender<Message>,
@@ -32,7 +63,13 @@ impl Session {
             rx,
             Self {
                 id,
+                state: SessionState::Connected,
                 username: String::new(),
+                logged_in: false,
+                resume_token: None,
+                capabilities: Vec::new(),
+                takeover: Arc::new(Notify::new()),
+                presence: Presence::Online,
                 rooms: HashMap::new(),
                 room_task_set: JoinSet::new(),
                 to_session_tx,
@@ -40,71 +77,270 @@ impl Session {
         )
     }
 
+    // Members of the rooms the session is in see the new name
     pub fn set_username(&mut self, username: &str) {
         self.username = username.to_string();
+        self.update_member();
+    }
+
+    pub fn set_presence(&mut self, presence: Presence) {
+        self.presence = presence;
+        self.update_member();
+    }
+
+    fn member(&self) -> Member {
+        Member {
+            username: self.username.clone(),
+            presence: self.presence,
+        }
+    }
+
+    fn update_member(&self) {
+        for (room_handle, _) in self.rooms.values() {
+            room_handle.update_member(self.member());
+        }
+    }
+
+    pub fn set_capabilities(&mut self, capabilities: Vec<Capability>) {
+        self.capabilities = capabilities;
     }
 
-    pub async fn join_room(&mut self, room: &str, room_manager: &RoomManager) -> Result<()> {
+    // Joins the room, returning its members along with its
+    // backlog if the client is able to make use of it
+    pub async fn join_room(
+        &mut self,
+        room: &str,
+        room_manager: &RoomManager,
+    ) -> Result<(Vec<ChatMessage>, Vec<Member>), ErrorCode> {
         if self.rooms.contains_key(room) {
-            return Err(anyhow!("Already part of room"));
+            return Err(ErrorCode::AlreadyInRoom);
         }
 
-        let (mut broadcast_rx, handle) = room_manager.join(room).await?;
+        let Membership {
+            mut broadcast_rx,
+            backlog,
+            mut last_id,
+            members,
+            handle,
+        } = room_manager.join(room, self.id, self.member()).await?;
+
+        let has_history = self.capabilities.contains(&Capability::History);
 
         let room_task = self.room_task_set.spawn({
             let to_session_tx = self.to_session_tx.clone();
+            let handle = handle.clone();
+            let room = room.to_string();
 
+            // Lagging readers are resynced from the newest chat
+            // message passed on so far, kept in `last_id`, and
+            // subscribe again from there
             async move {
-                while let Ok(message) = broadcast_rx.recv().await {
-                    let _ = to_session_tx.send(message);
+                loop {
+                    match broadcast_rx.recv().await {
+                        Ok(message) => {
+                            if let Some(chat_message) = message.message_type.chat_message() {
+                                // Already sent along with a resync
+                                if last_id.is_some_and(|last_id| chat_message.id <= last_id) {
+                                    continue;
+                                }
+
+                                last_id = Some(chat_message.id);
+                            }
+
+                            let _ = to_session_tx.send(message);
+                        }
+                        Err(RecvError::Lagged(count)) => {
+                            warn!("[!] Session lagged {count} messages behind on [{room}]");
+
+                            let (resubscribed_rx, messages, members) = handle.resync(last_id);
+                            broadcast_rx = resubscribed_rx;
+
+                            let messages = match has_history {
+                                true => messages,
+                                false => Vec::new(),
+                            };
+
+                            if let Some(message) = messages.last() {
+                                last_id = last_id.max(Some(message.id));
+                            }
+
+                            let notice = Message::build(
+                                MessageType::MissedMessages {
+                                    room: room.clone(),
+                                    count,
+                                    messages,
+                                    members,
+                                },
+                                0,
+                            );
+
+                            let _ = to_session_tx.send(notice);
+                        }
+                        Err(RecvError::Closed) => break,
+                    }
                 }
             }
         });
 
         self.rooms.insert(room.to_string(), (handle, room_task));
 
-        Ok(())
+        if has_history {
+            Ok((backlog, members))
+        } else {
+            Ok((Vec::new(), members))
+        }
+    }
+
+    pub fn room_history(
+        &self,
+        room: &str,
+        before: Option<u64>,
+        limit: usize,
+    ) -> Result<(Vec<ChatMessage>, bool), ErrorCode> {
+        match self.rooms.get(room) {
+            Some((room_handle, _)) => Ok(room_handle.history(before, limit)),
+            None => Err(ErrorCode::NotInRoom),
+        }
     }
 
-    pub fn joined_rooms(&self) -> String {
-        let rooms = self
-            .rooms
-            .clone()
-            .into_keys()
-            .map(|s| s.to_string())
-            .collect::<Vec<String>>()
-            .join(",");
+    pub fn in_room(&self, room: &str) -> bool {
+        self.rooms.contains_key(room)
+    }
 
-        rooms
+    pub fn joined_rooms(&self) -> Vec<String> {
+        self.rooms.keys().cloned().collect::<Vec<String>>()
     }
 
-    pub async fn send_room_message(&self, room: &str, content: &str) -> Result<()> {
+    pub async fn send_room_message(
+        &self,
+        room: &str,
+        message_id: u64,
+        content: String,
+    ) -> Result<ChatMessage, ErrorCode> {
         if let Some((room_handle, _)) = self.rooms.get(room) {
+            let author = Author {
+                id: self.id,
+                username: self.username.clone(),
+            };
+            let chat_message = ChatMessage::new(message_id, author, content);
+
             let message = Message::build(
-                MessageType::RoomMessage,
+                MessageType::RoomMessage {
+                    room: room.to_string(),
+                    message: chat_message.clone(),
+                },
                 self.id,
-                Some(room.to_string()),
-                Some(format!("{0}: {content}", self.username)),
-            )?;
+            );
 
             let _ = room_handle.send_message(message);
 
-            Ok(())
+            Ok(chat_message)
         } else {
-            Err(anyhow!("Not part of room"))
+            Err(ErrorCode::NotInRoom)
         }
     }
 
-    pub fn leave_room(&mut self, room: &str) -> Result<()> {
-        if !self.rooms.contains_key(room) {
-            Err(anyhow!("Not part of room"))
-        } else {
-            let (_, room_task) = self.rooms.get(room).unwrap();
+    pub fn leave_room(&mut self, room: &str) -> Result<(), ErrorCode> {
+        match self.rooms.remove(room) {
+            Some((room_handle, room_task)) => {
+                room_task.abort();
+                room_handle.leave(false);
+
+                Ok(())
+            }
+            None => Err(ErrorCode::NotInRoom),
+        }
+    }
+
+    // Leaves every room as the session is closed
+    pub fn leave_rooms(&mut self) {
+        for (room_handle, room_task) in self.rooms.values() {
             room_task.abort();
+            room_handle.leave(true);
+        }
+
+        self.rooms.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::room::{Room, RoomConfig};
+    use std::sync::Mutex;
+
+    async fn join(
+        id: u64,
+        username: &str,
+        rooms: &RoomManager,
+    ) -> (Session, mpsc::UnboundedReceiver<Message>) {
+        let (_, session_rx, mut session) = Session::new(id);
+        session.set_capabilities(vec![Capability::History]);
+        session.set_username(username);
+        session.join_room("main", rooms).await.unwrap();
+
+        (session, session_rx)
+    }
 
-            let _ = self.rooms.remove(room);
+    #[tokio::test]
+    async fn lagging_sessions_are_resynced_with_messages_and_members() {
+        let config = RoomConfig {
+            history_size: 10,
+            join_backlog: 10,
+            channel_capacity: 2,
+        };
+        let rooms = RoomManager::new(vec![Arc::new(Mutex::new(Room::new("main", config)))]);
 
-            Ok(())
+        let (_alice, mut alice_rx) = join(1, "alice", &rooms).await;
+
+        // Alice's task doesn't get to run until she waits for a message,
+        // so she falls behind on all of this
+        let (mut carol, _) = join(3, "carol", &rooms).await;
+        let (bob, _) = join(2, "bob", &rooms).await;
+        for id in 1..=3 {
+            bob.send_room_message("main", id, format!("message {id}"))
+                .await
+                .unwrap();
+        }
+        carol.leave_room("main").unwrap();
+
+        let resync = alice_rx.recv().await.unwrap();
+        match resync.message_type {
+            MessageType::MissedMessages {
+                room,
+                messages,
+                members,
+                ..
+            } => {
+                assert_eq!(room, "main");
+                assert_eq!(
+                    messages
+                        .iter()
+                        .map(|message| message.id)
+                        .collect::<Vec<u64>>(),
+                    vec![1, 2, 3]
+                );
+                assert_eq!(
+                    members
+                        .iter()
+                        .map(|member| member.username.as_str())
+                        .collect::<Vec<&str>>(),
+                    vec!["alice", "bob"]
+                );
+            }
+            _ => panic!("Expected MissedMessages"),
         }
+
+        // Picked up right after the resync, nothing is sent twice
+        bob.send_room_message("main", 4, String::from("message 4"))
+            .await
+            .unwrap();
+
+        let next = alice_rx.recv().await.unwrap();
+        assert_eq!(
+            next.message_type.chat_message().map(|message| message.id),
+            Some(4)
+        );
+        assert!(alice_rx.try_recv().is_err());
     }
 }
@@ -0,0 +1,77 @@
+use super::{Account, RoomRecord, Storage};
+use anyhow::Result;
+use common::message::ChatMessage;
+use common::validation::name_key;
+use std::collections::HashMap;
+
+// Keeps everything in memory, used when no database is configured.
+// Nothing survives a restart
+#[derive(Default)]
+pub struct MemoryStorage {
+    rooms: Vec<RoomRecord>,
+    accounts: Vec<Account>,
+    messages: HashMap<String, Vec<ChatMessage>>,
+    last_message_id: Option<u64>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn rooms(&self) -> Result<Vec<RoomRecord>> {
+        Ok(self.rooms.clone())
+    }
+
+    fn add_room(&mut self, room: &RoomRecord) -> Result<()> {
+        self.rooms.push(room.clone());
+
+        Ok(())
+    }
+
+    fn accounts(&self) -> Result<Vec<Account>> {
+        Ok(self.accounts.clone())
+    }
+
+    fn add_account(&mut self, account: &Account) -> Result<()> {
+        self.accounts.push(account.clone());
+
+        Ok(())
+    }
+
+    fn history(&self, room: &str, limit: usize) -> Result<Vec<ChatMessage>> {
+        let messages = match self.messages.get(&name_key(room)) {
+            Some(messages) => messages,
+            None => return Ok(Vec::new()),
+        };
+        let start = messages.len().saturating_sub(limit);
+
+        Ok(messages[start..].to_vec())
+    }
+
+    fn add_message(&mut self, room: &str, message: &ChatMessage) -> Result<()> {
+        self.messages
+            .entry(name_key(room))
+            .or_default()
+            .push(message.clone());
+
+        Ok(())
+    }
+
+    fn last_message_id(&self) -> Result<Option<u64>> {
+        Ok(self.last_message_id)
+    }
+
+    fn set_last_message_id(&mut self, id: u64) -> Result<()> {
+        self.last_message_id = Some(id);
+
+        Ok(())
+    }
+
+    // Nothing outlives the process anyway
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
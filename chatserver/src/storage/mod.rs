@@ -0,0 +1,53 @@
+pub mod memory;
+pub mod sqlite;
+pub mod writer;
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use common::message::ChatMessage;
+
+pub use memory::MemoryStorage;
+pub use sqlite::SqliteStorage;
+pub use writer::StorageWriter;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct RoomRecord {
+    pub name: String,
+    pub created_by: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Account {
+    pub username: String,
+    // Argon2 hash of the password, as a PHC string
+    pub password_hash: String,
+    pub created_at: DateTime<Utc>,
+}
+
+// State which has to survive a server restart. Names are passed
+// as shown to users, backends key them by `name_key` themselves
+pub trait Storage: Send {
+    fn rooms(&self) -> Result<Vec<RoomRecord>>;
+
+    fn add_room(&mut self, room: &RoomRecord) -> Result<()>;
+
+    fn accounts(&self) -> Result<Vec<Account>>;
+
+    fn add_account(&mut self, account: &Account) -> Result<()>;
+
+    // Up to `limit` of the newest messages of the room, oldest first
+    fn history(&self, room: &str, limit: usize) -> Result<Vec<ChatMessage>>;
+
+    fn add_message(&mut self, room: &str, message: &ChatMessage) -> Result<()>;
+
+    // Highest message id reserved so far. Kept separately from the
+    // stored messages since private messages use the same ids
+    fn last_message_id(&self) -> Result<Option<u64>>;
+
+    fn set_last_message_id(&mut self, id: u64) -> Result<()>;
+
+    // Writes out anything still buffered. Called regularly, and
+    // once more before the server exits
+    fn flush(&mut self) -> Result<()>;
+}
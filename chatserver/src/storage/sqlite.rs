@@ -0,0 +1,374 @@
+use super::{Account, RoomRecord, Storage};
+use anyhow::Result;
+use common::message::{Author, ChatMessage};
+use common::validation::name_key;
+use rusqlite::{params, Connection, OptionalExtension};
+use std::path::Path;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS rooms (
+        key TEXT PRIMARY KEY,
+        name TEXT NOT NULL,
+        created_by TEXT,
+        created_at TEXT NOT NULL
+    );
+
+    CREATE TABLE IF NOT EXISTS accounts (
+        key TEXT PRIMARY KEY,
+        username TEXT NOT NULL,
+        password_hash TEXT,
+        created_at TEXT NOT NULL
+    );
+
+    CREATE TABLE IF NOT EXISTS messages (
+        id INTEGER PRIMARY KEY,
+        room TEXT NOT NULL,
+        author_id INTEGER NOT NULL,
+        author TEXT NOT NULL,
+        text TEXT NOT NULL,
+        timestamp TEXT NOT NULL
+    );
+
+    CREATE INDEX IF NOT EXISTS messages_by_room ON messages (room, id);
+
+    CREATE TABLE IF NOT EXISTS meta (
+        key TEXT PRIMARY KEY,
+        value INTEGER NOT NULL
+    );
+";
+
+// Keeps everything in a single SQLite database file. Messages are
+// written in batches, committed by `flush`, while everything else is
+// committed right away along with any messages still pending
+pub struct SqliteStorage {
+    connection: Connection,
+    // Whether a batch of writes is waiting to be committed
+    in_transaction: bool,
+}
+
+impl SqliteStorage {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let connection = Connection::open(path)?;
+        connection.execute_batch(SCHEMA)?;
+
+        let storage = SqliteStorage {
+            connection,
+            in_transaction: false,
+        };
+        storage.migrate()?;
+
+        Ok(storage)
+    }
+
+    // Older databases have no password hash on accounts, whose rows only
+    // record a name. The column is added and rows without a hash are
+    // ignored, so their names can be claimed by a new account
+    fn migrate(&self) -> Result<()> {
+        let has_password_hash = self
+            .connection
+            .prepare("SELECT 1 FROM pragma_table_info('accounts') WHERE name = 'password_hash'")?
+            .exists([])?;
+
+        if !has_password_hash {
+            self.connection
+                .execute("ALTER TABLE accounts ADD COLUMN password_hash TEXT", [])?;
+        }
+
+        Ok(())
+    }
+
+    fn begin(&mut self) -> Result<()> {
+        if !self.in_transaction {
+            self.connection.execute_batch("BEGIN")?;
+            self.in_transaction = true;
+        }
+
+        Ok(())
+    }
+
+    fn commit(&mut self) -> Result<()> {
+        if self.in_transaction {
+            self.connection.execute_batch("COMMIT")?;
+            self.in_transaction = false;
+        }
+
+        Ok(())
+    }
+}
+
+impl Storage for SqliteStorage {
+    fn rooms(&self) -> Result<Vec<RoomRecord>> {
+        let mut statement = self
+            .connection
+            .prepare("SELECT name, created_by, created_at FROM rooms ORDER BY created_at")?;
+
+        let rooms = statement
+            .query_map([], |row| {
+                Ok(RoomRecord {
+                    name: row.get(0)?,
+                    created_by: row.get(1)?,
+                    created_at: row.get(2)?,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<RoomRecord>>>()?;
+
+        Ok(rooms)
+    }
+
+    fn add_room(&mut self, room: &RoomRecord) -> Result<()> {
+        self.connection.execute(
+            "INSERT OR IGNORE INTO rooms (key, name, created_by, created_at) VALUES (?1, ?2, ?3, ?4)",
+            params![name_key(&room.name), room.name, room.created_by, room.created_at],
+        )?;
+
+        self.commit()
+    }
+
+    fn accounts(&self) -> Result<Vec<Account>> {
+        let mut statement = self.connection.prepare(
+            "SELECT username, password_hash, created_at FROM accounts
+                 WHERE password_hash IS NOT NULL",
+        )?;
+
+        let accounts = statement
+            .query_map([], |row| {
+                Ok(Account {
+                    username: row.get(0)?,
+                    password_hash: row.get(1)?,
+                    created_at: row.get(2)?,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<Account>>>()?;
+
+        Ok(accounts)
+    }
+
+    fn add_account(&mut self, account: &Account) -> Result<()> {
+        self.connection.execute(
+            "INSERT INTO accounts (key, username, password_hash, created_at)
+             VALUES (?1, ?2, ?3, ?4)
+             ON CONFLICT (key) DO UPDATE SET
+                username = excluded.username,
+                password_hash = excluded.password_hash,
+                created_at = excluded.created_at
+             WHERE accounts.password_hash IS NULL",
+            params![
+                name_key(&account.username),
+                account.username,
+                account.password_hash,
+                account.created_at
+            ],
+        )?;
+
+        self.commit()
+    }
+
+    fn history(&self, room: &str, limit: usize) -> Result<Vec<ChatMessage>> {
+        let mut statement = self.connection.prepare(
+            "SELECT id, author_id, author, text, timestamp FROM messages
+             WHERE room = ?1 ORDER BY id DESC LIMIT ?2",
+        )?;
+
+        let mut messages = statement
+            .query_map(params![name_key(room), limit as i64], |row| {
+                Ok(ChatMessage {
+                    id: row.get::<_, i64>(0)? as u64,
+                    author: Author {
+                        id: row.get::<_, i64>(1)? as u64,
+                        username: row.get(2)?,
+                    },
+                    text: row.get(3)?,
+                    timestamp: row.get(4)?,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<ChatMessage>>>()?;
+        messages.reverse();
+
+        Ok(messages)
+    }
+
+    fn add_message(&mut self, room: &str, message: &ChatMessage) -> Result<()> {
+        self.begin()?;
+        self.connection.execute(
+            "INSERT INTO messages (id, room, author_id, author, text, timestamp)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                message.id as i64,
+                name_key(room),
+                message.author.id as i64,
+                message.author.username,
+                message.text,
+                message.timestamp
+            ],
+        )?;
+
+        Ok(())
+    }
+
+    fn last_message_id(&self) -> Result<Option<u64>> {
+        let id = self
+            .connection
+            .query_row(
+                "SELECT value FROM meta WHERE key = 'last_message_id'",
+                [],
+                |row| row.get::<_, i64>(0),
+            )
+            .optional()?;
+
+        Ok(id.map(|id| id as u64))
+    }
+
+    fn set_last_message_id(&mut self, id: u64) -> Result<()> {
+        self.connection.execute(
+            "INSERT INTO meta (key, value) VALUES ('last_message_id', ?1)
+             ON CONFLICT (key) DO UPDATE SET value = excluded.value",
+            params![id as i64],
+        )?;
+
+        self.commit()
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.commit()?;
+        self.connection.cache_flush()?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{TimeZone, Utc};
+    use std::path::PathBuf;
+
+    // Database file removed again once the test is done
+    struct TempDatabase(PathBuf);
+
+    impl TempDatabase {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir()
+                .join(format!("chatserver-{name}-{0}.sqlite", std::process::id()));
+            let _ = std::fs::remove_file(&path);
+
+            TempDatabase(path)
+        }
+    }
+
+    impl Drop for TempDatabase {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    fn account(username: &str) -> Account {
+        Account {
+            username: username.to_string(),
+            password_hash: String::from("$argon2id$hash"),
+            created_at: Utc.timestamp_opt(1_700_000_000, 0).unwrap(),
+        }
+    }
+
+    fn message(id: u64, text: &str) -> ChatMessage {
+        ChatMessage {
+            id,
+            author: Author {
+                id: 1,
+                username: String::from("alice"),
+            },
+            text: text.to_string(),
+            timestamp: Utc.timestamp_opt(1_700_000_000 + id as i64, 0).unwrap(),
+        }
+    }
+
+    #[test]
+    fn state_survives_reopening() {
+        let database = TempDatabase::new("reopen");
+
+        {
+            let mut storage = SqliteStorage::open(&database.0).unwrap();
+
+            storage.add_account(&account("Alice")).unwrap();
+            for id in 1..=3 {
+                storage
+                    .add_message("Main", &message(id, &format!("message {id}")))
+                    .unwrap();
+            }
+            storage.set_last_message_id(42).unwrap();
+            storage.flush().unwrap();
+        }
+
+        let storage = SqliteStorage::open(&database.0).unwrap();
+
+        assert_eq!(storage.accounts().unwrap(), vec![account("Alice")]);
+        assert_eq!(storage.last_message_id().unwrap(), Some(42));
+        // Rooms are looked up regardless of case
+        assert_eq!(
+            storage.history("main", 2).unwrap(),
+            vec![message(2, "message 2"), message(3, "message 3")]
+        );
+    }
+
+    #[test]
+    fn pending_messages_are_committed_by_flush() {
+        let database = TempDatabase::new("flush");
+
+        let mut storage = SqliteStorage::open(&database.0).unwrap();
+        storage.add_message("main", &message(1, "hello")).unwrap();
+
+        let reader = SqliteStorage::open(&database.0).unwrap();
+        assert!(reader.history("main", 10).unwrap().is_empty());
+
+        storage.flush().unwrap();
+        assert_eq!(
+            reader.history("main", 10).unwrap(),
+            vec![message(1, "hello")]
+        );
+    }
+
+    #[test]
+    fn accounts_are_not_replaced() {
+        let database = TempDatabase::new("accounts");
+
+        let mut storage = SqliteStorage::open(&database.0).unwrap();
+        storage.add_account(&account("alice")).unwrap();
+
+        let mut other = account("ALICE");
+        other.password_hash = String::from("$argon2id$other");
+        storage.add_account(&other).unwrap();
+
+        assert_eq!(storage.accounts().unwrap(), vec![account("alice")]);
+    }
+
+    #[test]
+    fn migrates_accounts_without_passwords() {
+        let database = TempDatabase::new("migrate");
+
+        {
+            let connection = Connection::open(&database.0).unwrap();
+            connection
+                .execute_batch(
+                    "CREATE TABLE accounts (
+                        key TEXT PRIMARY KEY,
+                        username TEXT NOT NULL,
+                        created_at TEXT NOT NULL
+                    );
+                    INSERT INTO accounts (key, username, created_at)
+                        VALUES ('alice', 'alice', '2024-01-01T00:00:00Z');",
+                )
+                .unwrap();
+        }
+
+        let mut storage = SqliteStorage::open(&database.0).unwrap();
+        assert!(storage.accounts().unwrap().is_empty());
+
+        // The name is free to be claimed again
+        storage.add_account(&account("Alice")).unwrap();
+        assert_eq!(storage.accounts().unwrap(), vec![account("Alice")]);
+
+        // Migrating twice leaves the schema as it is
+        drop(storage);
+        let storage = SqliteStorage::open(&database.0).unwrap();
+        assert_eq!(storage.accounts().unwrap(), vec![account("Alice")]);
+    }
+}
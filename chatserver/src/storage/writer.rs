@@ -0,0 +1,139 @@
+use super::{Account, RoomRecord, Storage};
+use anyhow::{anyhow, Result};
+use common::message::ChatMessage;
+use log::error;
+use std::sync::mpsc;
+use std::thread::{self, JoinHandle};
+
+enum Write {
+    Room(RoomRecord),
+    Account(Account),
+    Message { room: String, message: ChatMessage },
+    LastMessageId(u64),
+    Flush,
+}
+
+// Owns the storage once the server is running and writes to it on a
+// thread of its own, so that sessions never wait for the disk. Writes
+// are done in the order they are sent, failures are only logged
+pub struct StorageWriter {
+    tx: mpsc::Sender<Write>,
+    thread: JoinHandle<Box<dyn Storage>>,
+}
+
+impl StorageWriter {
+    pub fn spawn(mut storage: Box<dyn Storage>) -> Self {
+        let (tx, rx) = mpsc::channel::<Write>();
+
+        let thread = thread::spawn(move || {
+            for write in rx {
+                let (result, what) = match write {
+                    Write::Room(room) => (storage.add_room(&room), "room"),
+                    Write::Account(account) => (storage.add_account(&account), "account"),
+                    Write::Message { room, message } => {
+                        (storage.add_message(&room, &message), "message")
+                    }
+                    Write::LastMessageId(id) => (storage.set_last_message_id(id), "message id"),
+                    Write::Flush => (storage.flush(), "state"),
+                };
+
+                if let Err(e) = result {
+                    error!("[-] Failed to store {what}: {e}");
+                }
+            }
+
+            // Nothing is sent anymore, whatever is left gets written out
+            if let Err(e) = storage.flush() {
+                error!("[-] Failed to store state: {e}");
+            }
+
+            storage
+        });
+
+        StorageWriter { tx, thread }
+    }
+
+    fn send(&self, write: Write) {
+        // The thread only stops once the writer is closed
+        let _ = self.tx.send(write);
+    }
+
+    pub fn add_room(&self, room: RoomRecord) {
+        self.send(Write::Room(room));
+    }
+
+    pub fn add_account(&self, account: Account) {
+        self.send(Write::Account(account));
+    }
+
+    pub fn add_message(&self, room: &str, message: ChatMessage) {
+        self.send(Write::Message {
+            room: room.to_string(),
+            message,
+        });
+    }
+
+    pub fn set_last_message_id(&self, id: u64) {
+        self.send(Write::LastMessageId(id));
+    }
+
+    pub fn flush(&self) {
+        self.send(Write::Flush);
+    }
+
+    // Waits for every write sent so far to be done and flushed,
+    // handing the storage back
+    pub async fn close(self) -> Result<Box<dyn Storage>> {
+        let StorageWriter { tx, thread } = self;
+        drop(tx);
+
+        tokio::task::spawn_blocking(move || thread.join())
+            .await?
+            .map_err(|_| anyhow!("Storage thread panicked"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+    use chrono::{TimeZone, Utc};
+    use common::message::Author;
+
+    fn message(id: u64) -> ChatMessage {
+        ChatMessage {
+            id,
+            author: Author {
+                id: 1,
+                username: String::from("alice"),
+            },
+            text: format!("message {id}"),
+            timestamp: Utc.timestamp_opt(1_700_000_000 + id as i64, 0).unwrap(),
+        }
+    }
+
+    #[tokio::test]
+    async fn close_waits_for_every_write() {
+        let writer = StorageWriter::spawn(Box::new(MemoryStorage::new()));
+
+        writer.add_room(RoomRecord {
+            name: String::from("main"),
+            created_by: None,
+            created_at: Utc.timestamp_opt(1_700_000_000, 0).unwrap(),
+        });
+        for id in 1..=100 {
+            writer.add_message("main", message(id));
+        }
+        writer.set_last_message_id(100);
+        writer.flush();
+
+        let storage = writer.close().await.unwrap();
+
+        assert_eq!(storage.rooms().unwrap().len(), 1);
+        assert_eq!(storage.last_message_id().unwrap(), Some(100));
+        assert_eq!(
+            storage.history("main", 2).unwrap(),
+            vec![message(99), message(100)]
+        );
+    }
+}
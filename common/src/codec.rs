@@ -0,0 +1,54 @@
+use anyhow::{anyhow, Result};
+use serde::{de::DeserializeOwned, Serialize};
+use std::str::FromStr;
+
+// Encodings understood on the wire. Postcard is compact and used by
+// default, JSON is meant for debugging and quick scripts
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Codec {
+    Postcard,
+    Json,
+}
+
+impl FromStr for Codec {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "postcard" => Ok(Codec::Postcard),
+            "json" => Ok(Codec::Json),
+            _ => Err(anyhow!("Unknown codec \"{s}\" (expected postcard or json)")),
+        }
+    }
+}
+
+// Single frame as carried by a transport. Binary frames hold
+// postcard, text frames hold JSON
+#[derive(Clone, Debug, PartialEq)]
+pub enum Frame {
+    Binary(Vec<u8>),
+    Text(String),
+}
+
+impl Frame {
+    pub fn encode<T: Serialize>(value: &T, codec: Codec) -> Result<Self> {
+        match codec {
+            Codec::Postcard => Ok(Frame::Binary(postcard::to_stdvec(value)?)),
+            Codec::Json => Ok(Frame::Text(serde_json::to_string(value)?)),
+        }
+    }
+
+    pub fn decode<T: DeserializeOwned>(&self) -> Result<T> {
+        match self {
+            Frame::Binary(bytes) => Ok(postcard::from_bytes(bytes)?),
+            Frame::Text(text) => Ok(serde_json::from_str(text)?),
+        }
+    }
+
+    pub fn codec(&self) -> Codec {
+        match self {
+            Frame::Binary(_) => Codec::Postcard,
+            Frame::Text(_) => Codec::Json,
+        }
+    }
+}
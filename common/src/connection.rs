@@ -1,2 +1,503 @@
+use crate::codec::{Codec, Frame};
+use crate::message::Message;
+use crate::tls::{TlsAcceptor, TlsConnector};
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use futures_util::{SinkExt, StreamExt};
+use rustls::pki_types::{CertificateDer, ServerName};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+    sync::mpsc::{self},
+};
+use tokio_tungstenite::{tungstenite, WebSocketStream};
 
+// Largest frame accepted by the length-prefixed TCP transport
+pub const MAX_FRAME_SIZE: usize = 1024 * 1024;
 
+// Frame kinds of the TCP transport. The kind byte leaves room
+// for control frames next to the data frames
+const FRAME_BINARY: u8 = 0;
+const FRAME_TEXT: u8 = 1;
+const FRAME_PING: u8 = 2;
+const FRAME_PONG: u8 = 3;
+
+// Moves frames between two peers. Implementations only deal with
+// whole frames, encoding is handled by the provided methods
+#[async_trait]
+pub trait Transport: Send {
+    async fn send_frame(&mut self, frame: Frame) -> Result<()>;
+
+    // Next frame sent by the peer, None once the connection is closed.
+    // Has to be cancel safe as it is polled inside select loops
+    async fn recv_frame(&mut self) -> Option<Result<Frame>>;
+
+    async fn close(&mut self) -> Result<()>;
+
+    // Asks the peer to answer, keeping the connection alive and
+    // measuring the round trip once the answer is received
+    async fn send_ping(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    // Time since anything was last received from the peer
+    fn idle_time(&self) -> Duration {
+        Duration::ZERO
+    }
+
+    // Round trip of the last answered ping
+    fn round_trip(&self) -> Option<Duration> {
+        None
+    }
+
+    async fn send(&mut self, message: &Message, codec: Codec) -> Result<()> {
+        self.send_frame(Frame::encode(message, codec)?).await
+    }
+
+    // Frames are decoded with the codec they were sent with
+    async fn recv(&mut self) -> Option<Result<Message>> {
+        let frame = self.recv_frame().await?;
+
+        Some(frame.and_then(|frame| frame.decode()))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TransportKind {
+    WebSocket,
+    Tcp,
+}
+
+impl FromStr for TransportKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "ws" | "websocket" => Ok(TransportKind::WebSocket),
+            "tcp" => Ok(TransportKind::Tcp),
+            _ => Err(anyhow!("Unknown transport \"{s}\" (expected ws or tcp)")),
+        }
+    }
+}
+
+// Opens a connection to a server listening with the given transport
+pub async fn connect(addr: &str, kind: TransportKind) -> Result<Box<dyn Transport>> {
+    let stream = TcpStream::connect(addr).await?;
+
+    connect_over(stream, format!("ws://{addr}"), kind).await
+}
+
+// Same as `connect` for servers expecting TLS. The certificate the
+// server presented is returned along with the transport
+pub async fn connect_tls(
+    addr: &str,
+    kind: TransportKind,
+    connector: &TlsConnector,
+) -> Result<(Box<dyn Transport>, CertificateDer<'static>)> {
+    // Certificates are issued for the host, without the port
+    let host = addr
+        .rsplit_once(':')
+        .map_or(addr, |(host, _)| host)
+        .trim_start_matches('[')
+        .trim_end_matches(']');
+    let server_name = ServerName::try_from(host.to_string())
+        .map_err(|_| anyhow!("Invalid server name \"{host}\""))?;
+
+    let stream = TcpStream::connect(addr).await?;
+    let stream = connector.connect(server_name, stream).await?;
+
+    let certificate = stream
+        .get_ref()
+        .1
+        .peer_certificates()
+        .and_then(|certificates| certificates.first())
+        .map(|certificate| certificate.clone().into_owned())
+        .ok_or_else(|| anyhow!("Server presented no certificate"))?;
+
+    let transport = connect_over(stream, format!("wss://{addr}"), kind).await?;
+
+    Ok((transport, certificate))
+}
+
+async fn connect_over<S>(stream: S, url: String, kind: TransportKind) -> Result<Box<dyn Transport>>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    match kind {
+        TransportKind::WebSocket => {
+            let (ws_stream, _) = tokio_tungstenite::client_async(url, stream).await?;
+
+            Ok(Box::new(WebSocketTransport::new(ws_stream)))
+        }
+        TransportKind::Tcp => Ok(Box::new(TcpTransport::new(stream))),
+    }
+}
+
+// Sets up the server side of a freshly accepted connection
+pub async fn accept(stream: TcpStream, kind: TransportKind) -> Result<Box<dyn Transport>> {
+    accept_over(stream, kind).await
+}
+
+// Same as `accept` for connections starting with a TLS handshake
+pub async fn accept_tls(
+    stream: TcpStream,
+    kind: TransportKind,
+    acceptor: &TlsAcceptor,
+) -> Result<Box<dyn Transport>> {
+    let stream = acceptor.accept(stream).await?;
+
+    accept_over(stream, kind).await
+}
+
+async fn accept_over<S>(stream: S, kind: TransportKind) -> Result<Box<dyn Transport>>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    match kind {
+        TransportKind::WebSocket => {
+            let ws_stream = tokio_tungstenite::accept_async(stream).await?;
+
+            Ok(Box::new(WebSocketTransport::new(ws_stream)))
+        }
+        TransportKind::Tcp => Ok(Box::new(TcpTransport::new(stream))),
+    }
+}
+
+// Liveness of the peer. Pings carry the time they were sent at,
+// which the peer sends back untouched in its pong
+struct Heartbeat {
+    epoch: Instant,
+    last_heard: Instant,
+    round_trip: Option<Duration>,
+}
+
+impl Heartbeat {
+    fn new() -> Self {
+        let now = Instant::now();
+
+        Heartbeat {
+            epoch: now,
+            last_heard: now,
+            round_trip: None,
+        }
+    }
+
+    fn heard(&mut self) {
+        self.last_heard = Instant::now();
+    }
+
+    fn ping_payload(&self) -> Vec<u8> {
+        (self.epoch.elapsed().as_micros() as u64)
+            .to_be_bytes()
+            .to_vec()
+    }
+
+    // Pongs which don't carry one of our payloads are ignored
+    fn pong(&mut self, payload: &[u8]) {
+        if let Ok(sent) = payload.try_into().map(u64::from_be_bytes) {
+            let sent = Duration::from_micros(sent);
+
+            self.round_trip = self.epoch.elapsed().checked_sub(sent);
+        }
+    }
+}
+
+// Binary frames are carried as binary WebSocket messages
+// and text frames as text messages
+pub struct WebSocketTransport<S> {
+    ws_stream: WebSocketStream<S>,
+    heartbeat: Heartbeat,
+}
+
+impl<S> WebSocketTransport<S> {
+    pub fn new(ws_stream: WebSocketStream<S>) -> Self {
+        WebSocketTransport {
+            ws_stream,
+            heartbeat: Heartbeat::new(),
+        }
+    }
+}
+
+#[async_trait]
+impl<S> Transport for WebSocketTransport<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    async fn send_frame(&mut self, frame: Frame) -> Result<()> {
+        let message = match frame {
+            Frame::Binary(data) => tungstenite::Message::Binary(data.into()),
+            Frame::Text(text) => tungstenite::Message::Text(text.into()),
+        };
+
+        self.ws_stream.send(message).await?;
+
+        Ok(())
+    }
+
+    async fn recv_frame(&mut self) -> Option<Result<Frame>> {
+        loop {
+            let message = self.ws_stream.next().await?;
+            if message.is_ok() {
+                self.heartbeat.heard();
+            }
+
+            match message {
+                Ok(tungstenite::Message::Binary(data)) => {
+                    return Some(Ok(Frame::Binary(data.into())))
+                }
+                Ok(tungstenite::Message::Text(text)) => {
+                    return Some(Ok(Frame::Text(text.as_str().to_string())))
+                }
+                Ok(tungstenite::Message::Close(_)) => return None,
+                Ok(tungstenite::Message::Pong(payload)) => self.heartbeat.pong(&payload),
+                // Pings are answered by tungstenite itself
+                Ok(_) => continue,
+                Err(e) => return Some(Err(e.into())),
+            }
+        }
+    }
+
+    async fn close(&mut self) -> Result<()> {
+        self.ws_stream.close(None).await?;
+
+        Ok(())
+    }
+
+    async fn send_ping(&mut self) -> Result<()> {
+        let payload = self.heartbeat.ping_payload();
+        self.ws_stream
+            .send(tungstenite::Message::Ping(payload.into()))
+            .await?;
+
+        Ok(())
+    }
+
+    fn idle_time(&self) -> Duration {
+        self.heartbeat.last_heard.elapsed()
+    }
+
+    fn round_trip(&self) -> Option<Duration> {
+        self.heartbeat.round_trip
+    }
+}
+
+// Frames are written as a big endian u32 length, a kind byte
+// and the payload. Pings are answered with a pong carrying the
+// same payload
+pub struct TcpTransport<S = TcpStream> {
+    stream: S,
+    buffer: Vec<u8>,
+    // Bytes not written yet, pongs are queued here while receiving
+    pending: Vec<u8>,
+    heartbeat: Heartbeat,
+    // Set once the stream can no longer be read from,
+    // after a malformed frame there is no way to resync
+    broken: bool,
+}
+
+impl<S> TcpTransport<S> {
+    pub fn new(stream: S) -> Self {
+        TcpTransport {
+            stream,
+            buffer: Vec::new(),
+            pending: Vec::new(),
+            heartbeat: Heartbeat::new(),
+            broken: false,
+        }
+    }
+
+    fn queue_frame(&mut self, kind: u8, payload: &[u8]) -> Result<()> {
+        if payload.len() > MAX_FRAME_SIZE {
+            return Err(anyhow!("Frame of {} bytes exceeds limit", payload.len()));
+        }
+
+        self.pending
+            .extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        self.pending.push(kind);
+        self.pending.extend_from_slice(payload);
+
+        Ok(())
+    }
+
+    // Takes the first complete frame out of the read buffer
+    fn parse_frame(&mut self) -> Option<Result<(u8, Vec<u8>)>> {
+        if self.buffer.len() < 5 {
+            return None;
+        }
+
+        let length = u32::from_be_bytes(self.buffer[..4].try_into().unwrap()) as usize;
+        if length > MAX_FRAME_SIZE {
+            return Some(Err(anyhow!("Frame of {length} bytes exceeds limit")));
+        }
+
+        if self.buffer.len() < 5 + length {
+            return None;
+        }
+
+        let kind = self.buffer[4];
+        let payload = self.buffer[5..5 + length].to_vec();
+        self.buffer.drain(..5 + length);
+
+        Some(Ok((kind, payload)))
+    }
+}
+
+impl<S> TcpTransport<S>
+where
+    S: AsyncWrite + Unpin,
+{
+    // Only drops what was actually written, so that being
+    // cancelled halfway never leaves a partial frame behind
+    async fn flush_pending(&mut self) -> std::io::Result<()> {
+        while !self.pending.is_empty() {
+            let n = self.stream.write(&self.pending).await?;
+            if n == 0 {
+                return Err(std::io::ErrorKind::WriteZero.into());
+            }
+
+            self.pending.drain(..n);
+        }
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<S> Transport for TcpTransport<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    async fn send_frame(&mut self, frame: Frame) -> Result<()> {
+        match frame {
+            Frame::Binary(data) => self.queue_frame(FRAME_BINARY, &data)?,
+            Frame::Text(text) => self.queue_frame(FRAME_TEXT, text.as_bytes())?,
+        }
+
+        self.flush_pending().await?;
+
+        Ok(())
+    }
+
+    async fn recv_frame(&mut self) -> Option<Result<Frame>> {
+        if self.broken {
+            return None;
+        }
+
+        loop {
+            // Pongs queued while receiving go out before waiting for more
+            if let Err(e) = self.flush_pending().await {
+                self.broken = true;
+                return Some(Err(e.into()));
+            }
+
+            match self.parse_frame() {
+                Some(Ok((FRAME_BINARY, payload))) => return Some(Ok(Frame::Binary(payload))),
+                Some(Ok((FRAME_TEXT, payload))) => {
+                    return Some(
+                        String::from_utf8(payload)
+                            .map(Frame::Text)
+                            .map_err(Into::into),
+                    )
+                }
+                Some(Ok((FRAME_PING, payload))) => {
+                    if let Err(e) = self.queue_frame(FRAME_PONG, &payload) {
+                        return Some(Err(e));
+                    }
+                    continue;
+                }
+                Some(Ok((FRAME_PONG, payload))) => {
+                    self.heartbeat.pong(&payload);
+                    continue;
+                }
+                Some(Ok((kind, _))) => return Some(Err(anyhow!("Unknown frame kind {kind}"))),
+                Some(Err(e)) => {
+                    self.broken = true;
+                    return Some(Err(e));
+                }
+                None => {}
+            }
+
+            // Reading into a local chunk keeps this cancel safe, partial
+            // frames stay in the buffer until the rest arrives
+            let mut chunk = [0u8; 4096];
+            match self.stream.read(&mut chunk).await {
+                Ok(0) => return None,
+                Ok(n) => {
+                    self.heartbeat.heard();
+                    self.buffer.extend_from_slice(&chunk[..n]);
+                }
+                Err(e) => {
+                    self.broken = true;
+                    return Some(Err(e.into()));
+                }
+            }
+        }
+    }
+
+    async fn close(&mut self) -> Result<()> {
+        self.flush_pending().await?;
+        self.stream.shutdown().await?;
+
+        Ok(())
+    }
+
+    async fn send_ping(&mut self) -> Result<()> {
+        let payload = self.heartbeat.ping_payload();
+        self.queue_frame(FRAME_PING, &payload)?;
+        self.flush_pending().await?;
+
+        Ok(())
+    }
+
+    fn idle_time(&self) -> Duration {
+        self.heartbeat.last_heard.elapsed()
+    }
+
+    fn round_trip(&self) -> Option<Duration> {
+        self.heartbeat.round_trip
+    }
+}
+
+// Pair of connected in-memory transports, used for testing
+pub struct MemoryTransport {
+    tx: Option<mpsc::UnboundedSender<Frame>>,
+    rx: mpsc::UnboundedReceiver<Frame>,
+}
+
+pub fn memory_pair() -> (MemoryTransport, MemoryTransport) {
+    let (a_tx, b_rx) = mpsc::unbounded_channel::<Frame>();
+    let (b_tx, a_rx) = mpsc::unbounded_channel::<Frame>();
+
+    (
+        MemoryTransport {
+            tx: Some(a_tx),
+            rx: a_rx,
+        },
+        MemoryTransport {
+            tx: Some(b_tx),
+            rx: b_rx,
+        },
+    )
+}
+
+#[async_trait]
+impl Transport for MemoryTransport {
+    async fn send_frame(&mut self, frame: Frame) -> Result<()> {
+        match &self.tx {
+            Some(tx) => tx.send(frame).map_err(|_| anyhow!("Peer dropped")),
+            None => Err(anyhow!("Transport closed")),
+        }
+    }
+
+    async fn recv_frame(&mut self) -> Option<Result<Frame>> {
+        self.rx.recv().await.map(Ok)
+    }
+
+    async fn close(&mut self) -> Result<()> {
+        self.tx = None;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,99 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+// Reasons a request can fail, sent back in `MessageType::Failed`.
+// Codes are part of the wire format: new ones are only ever
+// appended and existing ones are never reordered or removed
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum ErrorCode {
+    NameTaken,
+    NoSuchRoom,
+    NotInRoom,
+    AlreadyInRoom,
+    RoomExists,
+    NoSuchUser,
+    NotRegistered,
+    RateLimited,
+    Internal,
+    InvalidName,
+    NameTooLong,
+    InvalidMessage,
+    MessageTooLong,
+    InvalidCredentials,
+    NameReserved,
+    GuestsDisabled,
+    InvalidPassword,
+    NameLocked,
+    InvalidToken,
+    AlreadyRegistered,
+    RegisterTimeout,
+    SessionClosing,
+    UnexpectedMessage,
+}
+
+impl ErrorCode {
+    // Fatal errors leave the session unusable, the client is
+    // expected to drop the connection when receiving one
+    pub fn is_fatal(&self) -> bool {
+        match self {
+            ErrorCode::Internal | ErrorCode::RegisterTimeout => true,
+            ErrorCode::NameTaken
+            | ErrorCode::NoSuchRoom
+            | ErrorCode::NotInRoom
+            | ErrorCode::AlreadyInRoom
+            | ErrorCode::RoomExists
+            | ErrorCode::NoSuchUser
+            | ErrorCode::NotRegistered
+            | ErrorCode::RateLimited
+            | ErrorCode::InvalidName
+            | ErrorCode::NameTooLong
+            | ErrorCode::InvalidMessage
+            | ErrorCode::MessageTooLong
+            | ErrorCode::InvalidCredentials
+            | ErrorCode::NameReserved
+            | ErrorCode::GuestsDisabled
+            | ErrorCode::InvalidPassword
+            | ErrorCode::NameLocked
+            | ErrorCode::InvalidToken
+            | ErrorCode::AlreadyRegistered
+            | ErrorCode::SessionClosing
+            | ErrorCode::UnexpectedMessage => false,
+        }
+    }
+
+    pub fn description(&self) -> &'static str {
+        match self {
+            ErrorCode::NameTaken => "Username already exists",
+            ErrorCode::NoSuchRoom => "No such room",
+            ErrorCode::NotInRoom => "Not part of room",
+            ErrorCode::AlreadyInRoom => "Already part of room",
+            ErrorCode::RoomExists => "Room already exists",
+            ErrorCode::NoSuchUser => "No such user",
+            ErrorCode::NotRegistered => "Not registered",
+            ErrorCode::RateLimited => "Too many requests",
+            ErrorCode::Internal => "Internal server error",
+            ErrorCode::InvalidName => "Name is empty or contains characters that are not allowed",
+            ErrorCode::NameTooLong => "Name is too long",
+            ErrorCode::InvalidMessage => "Message is empty or contains control characters",
+            ErrorCode::MessageTooLong => "Message is too long",
+            ErrorCode::InvalidCredentials => "Wrong username or password",
+            ErrorCode::NameReserved => "Name belongs to an account, log in to use it",
+            ErrorCode::GuestsDisabled => "Server only accepts logged in users",
+            ErrorCode::InvalidPassword => "Password is too short or too long",
+            ErrorCode::NameLocked => "Name is bound to the logged in account",
+            ErrorCode::InvalidToken => "Session expired or does not exist",
+            ErrorCode::AlreadyRegistered => "Already registered",
+            ErrorCode::RegisterTimeout => "Took too long to register",
+            ErrorCode::SessionClosing => "Session is closing",
+            ErrorCode::UnexpectedMessage => "Not a request the server handles",
+        }
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.description())
+    }
+}
+
+impl std::error::Error for ErrorCode {}
@@ -0,0 +1,134 @@
+use anyhow::Result;
+use postcard::{from_bytes, to_stdvec};
+use serde::{Deserialize, Serialize};
+
+// Version of the `Message` wire format. Has to be bumped whenever
+// `MessageHeader` or `MessageType` change in a way that older peers
+// can not decode (including adding or reordering variants)
+pub const PROTOCOL_VERSION: u16 = 17;
+
+// Oldest protocol version this build is still able to talk to
+pub const MIN_PROTOCOL_VERSION: u16 = 17;
+
+// Optional features which are only used once both sides have
+// announced support for them during the handshake
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Capability {
+    History,
+    Typing,
+    Attachments,
+    Resume,
+}
+
+impl Capability {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Capability::History => "history",
+            Capability::Typing => "typing",
+            Capability::Attachments => "attachments",
+            Capability::Resume => "resume",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "history" => Some(Capability::History),
+            "typing" => Some(Capability::Typing),
+            "attachments" => Some(Capability::Attachments),
+            "resume" => Some(Capability::Resume),
+            _ => None,
+        }
+    }
+}
+
+// Hello and HelloReply are the first frames exchanged on a new
+// connection, before any `Message`. Their layout must never change
+// so that peers of any version can always read them. Capabilities
+// travel as names so unknown ones can simply be ignored
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Hello {
+    pub protocol_version: u16,
+    pub capabilities: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub enum HelloReply {
+    Accepted {
+        protocol_version: u16,
+        capabilities: Vec<String>,
+    },
+    Rejected {
+        protocol_version: u16,
+        reason: String,
+    },
+}
+
+impl Hello {
+    pub fn new(capabilities: &[Capability]) -> Self {
+        Hello {
+            protocol_version: PROTOCOL_VERSION,
+            capabilities: capability_names(capabilities),
+        }
+    }
+
+    pub fn from_bytes(bytes: Vec<u8>) -> Result<Self> {
+        let hello = from_bytes(&bytes)?;
+
+        Ok(hello)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        to_stdvec(self).unwrap()
+    }
+}
+
+impl HelloReply {
+    // Answers a client hello with the capabilities both sides share,
+    // or a rejection if the protocol versions are incompatible
+    pub fn answer(hello: &Hello, supported: &[Capability]) -> Self {
+        if !is_compatible(hello.protocol_version) {
+            return HelloReply::Rejected {
+                protocol_version: PROTOCOL_VERSION,
+                reason: format!(
+                    "Unsupported protocol version {} (supported: {MIN_PROTOCOL_VERSION} to {PROTOCOL_VERSION})",
+                    hello.protocol_version
+                ),
+            };
+        }
+
+        HelloReply::Accepted {
+            protocol_version: PROTOCOL_VERSION,
+            capabilities: capability_names(&negotiate(supported, &hello.capabilities)),
+        }
+    }
+
+    pub fn from_bytes(bytes: Vec<u8>) -> Result<Self> {
+        let reply = from_bytes(&bytes)?;
+
+        Ok(reply)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        to_stdvec(self).unwrap()
+    }
+}
+
+pub fn is_compatible(protocol_version: u16) -> bool {
+    (MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&protocol_version)
+}
+
+// Capabilities supported locally which the peer also announced
+pub fn negotiate(supported: &[Capability], announced: &[String]) -> Vec<Capability> {
+    supported
+        .iter()
+        .filter(|capability| announced.iter().any(|name| name == capability.name()))
+        .copied()
+        .collect()
+}
+
+pub fn capability_names(capabilities: &[Capability]) -> Vec<String> {
+    capabilities
+        .iter()
+        .map(|capability| capability.name().to_string())
+        .collect()
+}
@@ -0,0 +1,76 @@
+use crate::message::ChatMessage;
+use std::collections::VecDeque;
+
+// Bounded backlog of the most recent chat messages of a room.
+// Messages are kept in the order they were sent, once full the
+// oldest message is dropped for every new one
+#[derive(Clone, Debug)]
+pub struct History {
+    messages: VecDeque<ChatMessage>,
+    capacity: usize,
+}
+
+impl History {
+    pub fn new(capacity: usize) -> Self {
+        History {
+            messages: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    pub fn push(&mut self, message: ChatMessage) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        if self.messages.len() == self.capacity {
+            self.messages.pop_front();
+        }
+
+        self.messages.push_back(message);
+    }
+
+    // Up to `limit` of the newest messages, oldest first
+    pub fn latest(&self, limit: usize) -> Vec<ChatMessage> {
+        self.page(None, limit).0
+    }
+
+    // Page of up to `limit` messages sent before the message with the
+    // given id (or the newest ones if None), oldest first. The flag
+    // tells whether even older messages are left
+    pub fn page(&self, before: Option<u64>, limit: usize) -> (Vec<ChatMessage>, bool) {
+        let end = match before {
+            Some(id) => self.messages.partition_point(|message| message.id < id),
+            None => self.messages.len(),
+        };
+        let start = end.saturating_sub(limit);
+
+        (
+            self.messages.range(start..end).cloned().collect(),
+            start > 0,
+        )
+    }
+
+    // Every message sent after the message with the given id
+    // (or all of them if None), oldest first
+    pub fn since(&self, after: Option<u64>) -> Vec<ChatMessage> {
+        let start = match after {
+            Some(id) => self.messages.partition_point(|message| message.id <= id),
+            None => 0,
+        };
+
+        self.messages.range(start..).cloned().collect()
+    }
+
+    pub fn last_id(&self) -> Option<u64> {
+        self.messages.back().map(|message| message.id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.messages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+}
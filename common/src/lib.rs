@@ -1,3 +1,9 @@
+pub mod codec;
 pub mod connection;
+pub mod error;
+pub mod handshake;
+pub mod history;
 pub mod message;
 pub mod message_queue;
+pub mod tls;
+pub mod validation;
@@ -1,133 +1,276 @@
-use anyhow::{anyhow, Result};
+use crate::error::ErrorCode;
+use anyhow::Result;
+use chrono::{DateTime, Utc};
 use postcard::{from_bytes, to_stdvec};
 use serde::{Deserialize, Serialize};
 
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
-pub enum MessageType {
-    Register,
-    Registered,
-    Join,
-    Joined,
-    Leave,
-    LeftRoom,
-    List,
-    ChangeName,
-    ChangedName,
-    Create,
-    CreatedRoom,
-    PrivMsg,
-    IncomingMsg,
-    OutgoingMsg,
-    SendTo,
-    MessagedRoom,
-    RoomMessage,
-    UserRooms,
-    AllRooms,
+pub enum ListOption {
     Users,
-    Failed,
+    Rooms,
+    AllRooms,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
-pub struct MessageHeader {
-    pub message_type: MessageType,
-    pub sender_id: u64,
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Author {
+    pub id: u64,
+    pub username: String,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
-pub struct MessageBody {
-    pub arg: Option<String>,
-    pub content: Option<String>,
+// Whether a member is connected, or away while the server
+// holds on to their session for resuming
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Presence {
+    Online,
+    Away,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
-pub struct Message {
-    pub header: MessageHeader,
-    pub body: MessageBody,
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Member {
+    pub username: String,
+    pub presence: Presence,
 }
 
-fn check_message(message: &Message) -> Result<()> {
-    match message.header.message_type {
-        MessageType::Join
-        | MessageType::ChangeName
-        | MessageType::Leave
-        | MessageType::List
-        | MessageType::Create
-        | MessageType::Register
-        | MessageType::Joined
-        | MessageType::LeftRoom
-        | MessageType::CreatedRoom => {
-            if message.body.arg.is_none() {
-                return Err(anyhow!("Argument required"));
-            }
-
-            if message.body.content.is_some() {
-                return Err(anyhow!("Unecessary content provided"));
-            }
-        }
+// Chat message as stamped by the server. The id is unique
+// per server and can be used to deduplicate messages
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ChatMessage {
+    pub id: u64,
+    pub timestamp: DateTime<Utc>,
+    pub author: Author,
+    pub text: String,
+}
 
-        MessageType::SendTo
-        | MessageType::PrivMsg
-        | MessageType::RoomMessage
-        | MessageType::Registered
-        | MessageType::Failed
-        | MessageType::ChangedName
-        | MessageType::MessagedRoom
-        | MessageType::OutgoingMsg => {
-            if message.body.arg.is_none() {
-                return Err(anyhow!("Argument required"));
-            }
-
-            if message.body.content.is_none() {
-                return Err(anyhow!("Content required"));
-            }
+impl ChatMessage {
+    pub fn new(id: u64, author: Author, text: String) -> Self {
+        ChatMessage {
+            id,
+            timestamp: Utc::now(),
+            author,
+            text,
         }
+    }
+}
+
+// Every message type carries its own payload so that
+// neither side has to parse ad-hoc strings
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum MessageType {
+    Register {
+        username: String,
+    },
+    Registered {
+        id: u64,
+        username: String,
+    },
+    Join {
+        room: String,
+    },
+    // History holds the latest messages of the room, only
+    // filled in when the history capability was negotiated.
+    // Members include the session which joined
+    Joined {
+        room: String,
+        history: Vec<ChatMessage>,
+        members: Vec<Member>,
+    },
+    Leave {
+        room: String,
+    },
+    LeftRoom {
+        room: String,
+    },
+    List(ListOption),
+    ChangeName {
+        username: String,
+    },
+    ChangedName {
+        new_username: String,
+        old_username: String,
+    },
+    Create {
+        room: String,
+    },
+    CreatedRoom {
+        room: String,
+    },
+    PrivMsg {
+        to: String,
+        text: String,
+    },
+    IncomingMsg(ChatMessage),
+    // Copy of a private message sent by the client, with the name
+    // of the receiver spelled the way they registered it
+    OutgoingMsg {
+        to: String,
+        message: ChatMessage,
+    },
+    SendTo {
+        room: String,
+        text: String,
+    },
+    MessagedRoom {
+        room: String,
+        id: u64,
+    },
+    RoomMessage {
+        room: String,
+        message: ChatMessage,
+    },
+    UserRooms(Vec<String>),
+    AllRooms(Vec<String>),
+    Users(Vec<String>),
+    Failed {
+        command: String,
+        code: ErrorCode,
+    },
+    // Asks for up to `limit` messages sent to the room before the
+    // message with id `before`, or the latest ones if None
+    FetchHistory {
+        room: String,
+        before: Option<u64>,
+        limit: u32,
+    },
+    // Page of room history, oldest first. `more` is set when
+    // the server still has older messages
+    History {
+        room: String,
+        messages: Vec<ChatMessage>,
+        more: bool,
+    },
+    // Sent when the session fell behind on a room and `count`
+    // messages were skipped. Whatever the room history still holds
+    // of them is included if the history capability was negotiated,
+    // along with the members, replacing those known so far
+    MissedMessages {
+        room: String,
+        count: u64,
+        messages: Vec<ChatMessage>,
+        members: Vec<Member>,
+    },
+    // Creates a password protected account and logs in to it.
+    // Both are answered with `Registered` like a guest registration
+    CreateAccount {
+        username: String,
+        password: String,
+    },
+    Login {
+        username: String,
+        password: String,
+    },
+    // Handed out after registering when the resume capability was
+    // negotiated. Reconnecting with it within the grace period gives
+    // back the same session, with the messages sent in the meantime
+    SessionToken {
+        token: String,
+    },
+    Resume {
+        token: String,
+    },
+    Resumed {
+        id: u64,
+        username: String,
+        rooms: Vec<String>,
+    },
+    Members {
+        room: String,
+    },
+    RoomMembers {
+        room: String,
+        members: Vec<Member>,
+    },
+    // Changes to the members of a room, sent to the other members
+    MemberJoined {
+        room: String,
+        member: Member,
+    },
+    // `disconnected` is set when the member left by
+    // closing its session rather than the room
+    MemberLeft {
+        room: String,
+        username: String,
+        disconnected: bool,
+    },
+    // The member known as `username` was renamed or changed presence
+    MemberChanged {
+        room: String,
+        username: String,
+        member: Member,
+    },
+    // Message of the day, sent once connected if the server has one
+    Motd {
+        text: String,
+    },
+    // Last message before the server closes the connection. Clients
+    // should wait `reconnect_after` seconds before coming back
+    ShuttingDown {
+        reason: String,
+        reconnect_after: u64,
+    },
+    // Sent by a client leaving for good, so that the server drops
+    // its session right away instead of keeping it for resuming
+    Quit,
+}
 
-        MessageType::IncomingMsg
-        | MessageType::AllRooms
-        | MessageType::UserRooms
-        | MessageType::Users => {
-            if message.body.arg.is_some() {
-                return Err(anyhow!("Uncessary argument provided"));
-            }
-
-            if message.body.content.is_none() {
-                return Err(anyhow!("Content required"));
-            }
+impl MessageType {
+    // Chat message carried by the message, if any
+    pub fn chat_message(&self) -> Option<&ChatMessage> {
+        match self {
+            MessageType::RoomMessage { message, .. }
+            | MessageType::IncomingMsg(message)
+            | MessageType::OutgoingMsg { message, .. } => Some(message),
+            _ => None,
         }
     }
+}
 
-    Ok(())
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct MessageHeader {
+    pub sender_id: u64,
+    // Chosen by the client for requests and echoed back by the
+    // server in the matching reply. None for unsolicited messages
+    pub request_id: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Message {
+    pub header: MessageHeader,
+    pub message_type: MessageType,
 }
 
 impl Message {
-    pub fn build(
-        message_type: MessageType,
-        sender_id: u64,
-        arg: Option<String>,
-        content: Option<String>,
-    ) -> Result<Self> {
-        let message = Message {
+    pub fn build(message_type: MessageType, sender_id: u64) -> Self {
+        Message {
             header: MessageHeader {
-                message_type,
                 sender_id,
+                request_id: None,
             },
-            body: MessageBody { arg, content },
-        };
-
-        check_message(&message)?;
+            message_type,
+        }
+    }
 
-        Ok(message)
+    pub fn with_request_id(mut self, request_id: Option<u64>) -> Self {
+        self.header.request_id = request_id;
+        self
     }
 
     pub fn from_bytes(bytes: Vec<u8>) -> Result<Self> {
         let message = from_bytes(&bytes)?;
 
-        check_message(&message)?;
-
         Ok(message)
     }
 
     pub fn to_bytes(&self) -> Vec<u8> {
         to_stdvec(self).unwrap()
     }
+
+    pub fn from_json(json: &str) -> Result<Self> {
+        let message = serde_json::from_str(json)?;
+
+        Ok(message)
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
 }
@@ -0,0 +1,89 @@
+use crate::error::ErrorCode;
+use unicode_normalization::UnicodeNormalization;
+
+// Upper bound on passwords, in bytes, keeping hashing cheap
+pub const MAX_PASSWORD_LENGTH: usize = 1024;
+
+// Limits applied by the server to names and message bodies
+#[derive(Clone, Debug, PartialEq)]
+pub struct Limits {
+    // Maximum length of user and room names, in characters
+    pub max_name_length: usize,
+    // Maximum length of a message body, in characters
+    pub max_message_length: usize,
+    // Characters allowed in names besides letters and digits
+    pub name_symbols: String,
+    // Minimum length of account passwords, in characters
+    pub min_password_length: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_name_length: 32,
+            max_message_length: 2000,
+            name_symbols: String::from("-_."),
+            min_password_length: 8,
+        }
+    }
+}
+
+impl Limits {
+    // Checks a user or room name, returning it in normalized form
+    pub fn validate_name(&self, name: &str) -> Result<String, ErrorCode> {
+        let name = name.nfc().collect::<String>();
+
+        if name.is_empty() {
+            return Err(ErrorCode::InvalidName);
+        }
+
+        if name.chars().count() > self.max_name_length {
+            return Err(ErrorCode::NameTooLong);
+        }
+
+        let allowed = |c: char| c.is_alphanumeric() || self.name_symbols.contains(c);
+        if !name.chars().all(allowed) {
+            return Err(ErrorCode::InvalidName);
+        }
+
+        Ok(name)
+    }
+
+    // Checks the body of a room or private message. Newlines and
+    // tabs are the only control characters let through
+    pub fn validate_text(&self, text: &str) -> Result<(), ErrorCode> {
+        if text.trim().is_empty() {
+            return Err(ErrorCode::InvalidMessage);
+        }
+
+        if text.chars().count() > self.max_message_length {
+            return Err(ErrorCode::MessageTooLong);
+        }
+
+        if text
+            .chars()
+            .any(|c| c.is_control() && c != '\n' && c != '\t')
+        {
+            return Err(ErrorCode::InvalidMessage);
+        }
+
+        Ok(())
+    }
+
+    // Checks the password given when creating an account
+    pub fn validate_password(&self, password: &str) -> Result<(), ErrorCode> {
+        if password.chars().count() < self.min_password_length
+            || password.len() > MAX_PASSWORD_LENGTH
+        {
+            return Err(ErrorCode::InvalidPassword);
+        }
+
+        Ok(())
+    }
+}
+
+// Key names are compared by, so that "Alice" and "alice"
+// (or differently composed forms of the same name) collide
+pub fn name_key(name: &str) -> String {
+    name.nfc().collect::<String>().to_lowercase()
+}
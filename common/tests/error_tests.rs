@@ -0,0 +1,63 @@
+use common::error::ErrorCode;
+use common::message::{Message, MessageType};
+
+#[test]
+fn failed_layout() {
+    let message = Message::build(
+        MessageType::Failed {
+            command: String::from("join"),
+            code: ErrorCode::NotInRoom,
+        },
+        0,
+    );
+
+    let bytes = message.to_bytes();
+    assert_eq!(bytes, vec![0, 0, 20, 4, 106, 111, 105, 110, 2]);
+
+    let message_new = Message::from_bytes(bytes).unwrap();
+    assert_eq!(message_new, message);
+}
+
+#[test]
+fn stable_codes() {
+    // Codes are encoded by position, reordering them breaks older peers
+    let codes = [
+        ErrorCode::NameTaken,
+        ErrorCode::NoSuchRoom,
+        ErrorCode::NotInRoom,
+        ErrorCode::AlreadyInRoom,
+        ErrorCode::RoomExists,
+        ErrorCode::NoSuchUser,
+        ErrorCode::NotRegistered,
+        ErrorCode::RateLimited,
+        ErrorCode::Internal,
+        ErrorCode::InvalidName,
+        ErrorCode::NameTooLong,
+        ErrorCode::InvalidMessage,
+        ErrorCode::MessageTooLong,
+        ErrorCode::InvalidCredentials,
+        ErrorCode::NameReserved,
+        ErrorCode::GuestsDisabled,
+        ErrorCode::InvalidPassword,
+        ErrorCode::NameLocked,
+        ErrorCode::InvalidToken,
+        ErrorCode::AlreadyRegistered,
+        ErrorCode::RegisterTimeout,
+        ErrorCode::SessionClosing,
+        ErrorCode::UnexpectedMessage,
+    ];
+
+    for (index, code) in codes.iter().enumerate() {
+        assert_eq!(postcard::to_stdvec(code).unwrap(), vec![index as u8]);
+    }
+}
+
+#[test]
+fn fatal_codes() {
+    assert!(ErrorCode::Internal.is_fatal());
+    assert!(ErrorCode::RegisterTimeout.is_fatal());
+    assert!(!ErrorCode::NameTaken.is_fatal());
+    assert!(!ErrorCode::NotInRoom.is_fatal());
+    // Sessions closing because of a shutdown still get told why
+    assert!(!ErrorCode::SessionClosing.is_fatal());
+}
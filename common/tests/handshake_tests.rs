@@ -0,0 +1,37 @@
+use common::handshake::{negotiate, Capability, Hello, HelloReply, PROTOCOL_VERSION};
+
+#[test]
+fn hello_layout() {
+    let hello = Hello {
+        protocol_version: 1,
+        capabilities: vec![String::from("history")],
+    };
+
+    // The hello layout is frozen, this must never change
+    assert_eq!(
+        hello.to_bytes(),
+        vec![1, 1, 7, 104, 105, 115, 116, 111, 114, 121]
+    );
+}
+
+#[test]
+fn reject_incompatible_version() {
+    let hello = Hello {
+        protocol_version: PROTOCOL_VERSION + 1,
+        capabilities: Vec::new(),
+    };
+
+    let reply = HelloReply::answer(&hello, &[]);
+    let reply = HelloReply::from_bytes(reply.to_bytes()).unwrap();
+
+    assert!(matches!(reply, HelloReply::Rejected { .. }));
+}
+
+#[test]
+fn negotiate_shared_capabilities() {
+    let announced = vec![String::from("history"), String::from("unknown")];
+
+    let shared = negotiate(&[Capability::History, Capability::Typing], &announced);
+
+    assert_eq!(shared, vec![Capability::History]);
+}
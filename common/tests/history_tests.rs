@@ -0,0 +1,90 @@
+use common::history::History;
+use common::message::{Author, ChatMessage};
+
+fn message(id: u64) -> ChatMessage {
+    let author = Author {
+        id: 1,
+        username: String::from("alice"),
+    };
+
+    ChatMessage::new(id, author, format!("message {id}"))
+}
+
+fn ids(messages: &[ChatMessage]) -> Vec<u64> {
+    messages.iter().map(|message| message.id).collect()
+}
+
+fn filled(capacity: usize, count: u64) -> History {
+    let mut history = History::new(capacity);
+    for id in 1..=count {
+        history.push(message(id));
+    }
+
+    history
+}
+
+#[test]
+fn bounded() {
+    let history = filled(3, 5);
+
+    assert_eq!(history.len(), 3);
+    assert_eq!(ids(&history.latest(10)), vec![3, 4, 5]);
+}
+
+#[test]
+fn disabled() {
+    let history = filled(0, 5);
+
+    assert!(history.is_empty());
+    assert!(history.latest(10).is_empty());
+}
+
+#[test]
+fn latest() {
+    let history = filled(10, 5);
+
+    assert_eq!(ids(&history.latest(2)), vec![4, 5]);
+    assert!(history.latest(0).is_empty());
+}
+
+#[test]
+fn pages() {
+    let history = filled(10, 7);
+
+    let (page, more) = history.page(None, 3);
+    assert_eq!(ids(&page), vec![5, 6, 7]);
+    assert!(more);
+
+    let (page, more) = history.page(Some(5), 3);
+    assert_eq!(ids(&page), vec![2, 3, 4]);
+    assert!(more);
+
+    let (page, more) = history.page(Some(2), 3);
+    assert_eq!(ids(&page), vec![1]);
+    assert!(!more);
+
+    let (page, more) = history.page(Some(1), 3);
+    assert!(page.is_empty());
+    assert!(!more);
+}
+
+#[test]
+fn page_before_dropped_message() {
+    // Messages older than the buffer are gone, paging stops there
+    let history = filled(3, 10);
+
+    let (page, more) = history.page(Some(4), 5);
+    assert!(page.is_empty());
+    assert!(!more);
+}
+
+#[test]
+fn since() {
+    let history = filled(10, 5);
+
+    assert_eq!(ids(&history.since(Some(3))), vec![4, 5]);
+    assert_eq!(ids(&history.since(None)), vec![1, 2, 3, 4, 5]);
+    assert!(history.since(Some(5)).is_empty());
+    assert_eq!(history.last_id(), Some(5));
+    assert_eq!(History::new(3).last_id(), None);
+}
@@ -1,41 +1,144 @@
-use common::message::{Message, MessageBody, MessageHeader, MessageType};
+use common::message::{Author, ChatMessage, Member, Message, MessageHeader, MessageType, Presence};
 
 #[test]
 fn serialize() {
     let header = MessageHeader {
-        message_type: MessageType::Join,
         sender_id: 1,
+        request_id: None,
     };
 
-    let body = MessageBody {
-        arg: Some(String::from("main")),
-        content: None,
+    let message_type = MessageType::Join {
+        room: String::from("main"),
     };
 
-    let message = Message { header, body };
+    let message = Message {
+        header,
+        message_type,
+    };
     let bytes = message.to_bytes();
-    assert_eq!(
-        bytes,
-        vec![2, 3, 74, 111, 110, 1, 1, 4, 109, 97, 105, 110, 0]
-    );
+    assert_eq!(bytes, vec![1, 0, 2, 4, 109, 97, 105, 110]);
 }
 
 #[test]
 fn deserialize() {
-    let bytes = vec![2, 3, 74, 111, 110, 1, 1, 4, 109, 97, 105, 110, 0];
+    let bytes = vec![1, 0, 2, 4, 109, 97, 105, 110];
 
     let header = MessageHeader {
-        message_type: MessageType::Join,
         sender_id: 1,
+        request_id: None,
     };
 
-    let body = MessageBody {
-        arg: Some(String::from("main")),
-        content: None,
+    let message_type = MessageType::Join {
+        room: String::from("main"),
     };
 
-    let message_orig = Message { header, body };
+    let message_orig = Message {
+        header,
+        message_type,
+    };
     let message_new = Message::from_bytes(bytes).unwrap();
 
     assert_eq!(message_new, message_orig);
 }
+
+#[test]
+fn request_id() {
+    let message = Message::build(
+        MessageType::Join {
+            room: String::from("main"),
+        },
+        1,
+    )
+    .with_request_id(Some(7));
+
+    let bytes = message.to_bytes();
+    assert_eq!(bytes, vec![1, 1, 7, 2, 4, 109, 97, 105, 110]);
+
+    let message_new = Message::from_bytes(bytes).unwrap();
+    assert_eq!(message_new.header.request_id, Some(7));
+}
+
+#[test]
+fn listing_with_commas() {
+    let rooms = vec![String::from("main"), String::from("a,b")];
+    let message = Message::build(MessageType::AllRooms(rooms.clone()), 0);
+
+    let message_new = Message::from_bytes(message.to_bytes()).unwrap();
+
+    assert_eq!(message_new.message_type, MessageType::AllRooms(rooms));
+}
+
+#[test]
+fn chat_message() {
+    let author = Author {
+        id: 3,
+        username: String::from("alice"),
+    };
+    let chat_message = ChatMessage::new(42, author, String::from("hello"));
+
+    let message = Message::build(
+        MessageType::RoomMessage {
+            room: String::from("main"),
+            message: chat_message.clone(),
+        },
+        3,
+    );
+
+    let message_new = Message::from_bytes(message.to_bytes()).unwrap();
+
+    assert_eq!(
+        message_new.message_type,
+        MessageType::RoomMessage {
+            room: String::from("main"),
+            message: chat_message,
+        }
+    );
+}
+
+#[test]
+fn member_changes() {
+    let member = Member {
+        username: String::from("bob"),
+        presence: Presence::Away,
+    };
+
+    let message = Message::build(
+        MessageType::MemberChanged {
+            room: String::from("main"),
+            username: String::from("bob"),
+            member: member.clone(),
+        },
+        3,
+    );
+
+    let message_new = Message::from_bytes(message.to_bytes()).unwrap();
+
+    assert_eq!(
+        message_new.message_type,
+        MessageType::MemberChanged {
+            room: String::from("main"),
+            username: String::from("bob"),
+            member,
+        }
+    );
+}
+
+#[test]
+fn json() {
+    let message = Message::build(
+        MessageType::Join {
+            room: String::from("main"),
+        },
+        1,
+    )
+    .with_request_id(Some(7));
+
+    let json = message.to_json();
+    assert_eq!(
+        json,
+        r#"{"header":{"sender_id":1,"request_id":7},"message_type":{"Join":{"room":"main"}}}"#
+    );
+
+    let message_new = Message::from_json(&json).unwrap();
+    assert_eq!(message_new, message);
+}
@@ -0,0 +1,147 @@
+use common::codec::Codec;
+use common::connection::{self, Transport, TransportKind};
+use common::message::{Message, MessageType};
+use common::tls::{self, TlsAcceptor, TlsConnector};
+use rcgen::{BasicConstraints, Certificate, CertificateParams, IsCa, KeyPair};
+use std::path::PathBuf;
+use tokio::net::TcpListener;
+
+fn join(room: &str) -> Message {
+    Message::build(
+        MessageType::Join {
+            room: String::from(room),
+        },
+        1,
+    )
+}
+
+fn write_file(name: &str, contents: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("tls-tests-{0}-{name}", std::process::id()));
+    std::fs::write(&path, contents).unwrap();
+
+    path
+}
+
+// Authority along with its key, written to `{name}-ca.pem`
+fn authority(name: &str) -> (Certificate, KeyPair, PathBuf) {
+    let key = KeyPair::generate().unwrap();
+    let mut params = CertificateParams::new(Vec::new()).unwrap();
+    params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    let certificate = params.self_signed(&key).unwrap();
+    let path = write_file(&format!("{name}-ca.pem"), &certificate.pem());
+
+    (certificate, key, path)
+}
+
+// Server certificate for localhost signed by the authority
+fn server_acceptor(name: &str, ca: &Certificate, ca_key: &KeyPair) -> (TlsAcceptor, Certificate) {
+    let key = KeyPair::generate().unwrap();
+    let certificate = CertificateParams::new(vec![String::from("localhost")])
+        .unwrap()
+        .signed_by(&key, ca, ca_key)
+        .unwrap();
+
+    let cert_path = write_file(&format!("{name}-cert.pem"), &certificate.pem());
+    let key_path = write_file(&format!("{name}-key.pem"), &key.serialize_pem());
+
+    (tls::acceptor(&cert_path, &key_path).unwrap(), certificate)
+}
+
+async fn loopback(
+    kind: TransportKind,
+    acceptor: TlsAcceptor,
+    connector: &TlsConnector,
+) -> anyhow::Result<(Box<dyn Transport>, Box<dyn Transport>, Vec<u8>)> {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+
+    let server = tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        connection::accept_tls(stream, kind, &acceptor).await
+    });
+
+    let (client, certificate) =
+        connection::connect_tls(&format!("localhost:{port}"), kind, connector).await?;
+    let server = server.await.unwrap()?;
+
+    Ok((client, server, certificate.to_vec()))
+}
+
+async fn roundtrip(kind: TransportKind, name: &str) {
+    let (ca, ca_key, ca_path) = authority(name);
+    let (acceptor, _) = server_acceptor(name, &ca, &ca_key);
+    let connector = tls::connector(&ca_path).unwrap();
+
+    let (mut client, mut server, _) = loopback(kind, acceptor, &connector).await.unwrap();
+
+    client.send(&join("main"), Codec::Postcard).await.unwrap();
+    assert_eq!(server.recv().await.unwrap().unwrap(), join("main"));
+
+    server.send(&join("reply"), Codec::Json).await.unwrap();
+    assert_eq!(client.recv().await.unwrap().unwrap(), join("reply"));
+
+    client.close().await.unwrap();
+    assert!(server.recv().await.is_none());
+}
+
+#[tokio::test]
+async fn tls_tcp_roundtrip() {
+    roundtrip(TransportKind::Tcp, "tcp").await;
+}
+
+#[tokio::test]
+async fn tls_websocket_roundtrip() {
+    roundtrip(TransportKind::WebSocket, "ws").await;
+}
+
+#[tokio::test]
+async fn unknown_authority_is_rejected() {
+    let (ca, ca_key, _) = authority("unknown");
+    let (acceptor, _) = server_acceptor("unknown", &ca, &ca_key);
+    let (_, _, other_ca_path) = authority("other");
+    let connector = tls::connector(&other_ca_path).unwrap();
+
+    assert!(loopback(TransportKind::Tcp, acceptor, &connector)
+        .await
+        .is_err());
+}
+
+#[tokio::test]
+async fn unverified_connection_returns_certificate() {
+    let (ca, ca_key, _) = authority("unverified");
+    let (acceptor, certificate) = server_acceptor("unverified", &ca, &ca_key);
+    let connector = tls::unverified_connector().unwrap();
+
+    let (_, _, presented) = loopback(TransportKind::WebSocket, acceptor, &connector)
+        .await
+        .unwrap();
+
+    assert_eq!(presented, certificate.der().to_vec());
+}
+
+#[test]
+fn fingerprint_format() {
+    let (ca, _, _) = authority("fingerprint");
+    let fingerprint = tls::fingerprint(ca.der());
+
+    assert!(fingerprint.starts_with("SHA256:"));
+    // 32 bytes as pairs of hex digits separated by colons
+    assert_eq!(fingerprint.len(), "SHA256:".len() + 32 * 3 - 1);
+    assert!(fingerprint["SHA256:".len()..]
+        .split(':')
+        .all(|byte| byte.len() == 2 && byte.chars().all(|c| c.is_ascii_hexdigit())));
+}
+
+#[test]
+fn mismatched_key_is_rejected() {
+    let (ca, ca_key, _) = authority("mismatch");
+    let (_, certificate) = server_acceptor("mismatch", &ca, &ca_key);
+
+    let cert_path = write_file("mismatch-cert.pem", &certificate.pem());
+    let key_path = write_file(
+        "mismatch-other-key.pem",
+        &KeyPair::generate().unwrap().serialize_pem(),
+    );
+
+    assert!(tls::acceptor(&cert_path, &key_path).is_err());
+}
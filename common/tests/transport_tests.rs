@@ -0,0 +1,176 @@
+use common::codec::{Codec, Frame};
+use common::connection::{
+    self, memory_pair, TcpTransport, Transport, TransportKind, MAX_FRAME_SIZE,
+};
+use common::message::{Message, MessageType};
+use tokio::{io::AsyncWriteExt, net::TcpListener};
+
+fn join(room: &str) -> Message {
+    Message::build(
+        MessageType::Join {
+            room: String::from(room),
+        },
+        1,
+    )
+}
+
+async fn roundtrip(mut a: Box<dyn Transport>, mut b: Box<dyn Transport>, codec: Codec) {
+    a.send(&join("main"), codec).await.unwrap();
+    a.send(&join("other"), codec).await.unwrap();
+
+    assert_eq!(b.recv().await.unwrap().unwrap(), join("main"));
+    assert_eq!(b.recv().await.unwrap().unwrap(), join("other"));
+
+    b.send(&join("reply"), codec).await.unwrap();
+    assert_eq!(a.recv().await.unwrap().unwrap(), join("reply"));
+
+    a.close().await.unwrap();
+    assert!(b.recv().await.is_none());
+}
+
+async fn loopback(kind: TransportKind) -> (Box<dyn Transport>, Box<dyn Transport>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+
+    let server = tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        connection::accept(stream, kind).await.unwrap()
+    });
+
+    let client = connection::connect(&addr, kind).await.unwrap();
+    let server = server.await.unwrap();
+
+    (client, server)
+}
+
+#[tokio::test]
+async fn memory_roundtrip() {
+    let (a, b) = memory_pair();
+
+    roundtrip(Box::new(a), Box::new(b), Codec::Postcard).await;
+}
+
+#[tokio::test]
+async fn memory_roundtrip_json() {
+    let (a, b) = memory_pair();
+
+    roundtrip(Box::new(a), Box::new(b), Codec::Json).await;
+}
+
+#[tokio::test]
+async fn tcp_roundtrip() {
+    let (client, server) = loopback(TransportKind::Tcp).await;
+
+    roundtrip(client, server, Codec::Postcard).await;
+}
+
+#[tokio::test]
+async fn tcp_roundtrip_json() {
+    let (client, server) = loopback(TransportKind::Tcp).await;
+
+    roundtrip(client, server, Codec::Json).await;
+}
+
+#[tokio::test]
+async fn websocket_roundtrip() {
+    let (client, server) = loopback(TransportKind::WebSocket).await;
+
+    roundtrip(client, server, Codec::Postcard).await;
+}
+
+#[tokio::test]
+async fn websocket_roundtrip_json() {
+    let (client, server) = loopback(TransportKind::WebSocket).await;
+
+    roundtrip(client, server, Codec::Json).await;
+}
+
+#[tokio::test]
+async fn frames_keep_their_codec() {
+    let (mut client, mut server) = loopback(TransportKind::WebSocket).await;
+
+    client
+        .send_frame(Frame::encode(&join("main"), Codec::Json).unwrap())
+        .await
+        .unwrap();
+
+    let frame = server.recv_frame().await.unwrap().unwrap();
+    assert_eq!(frame.codec(), Codec::Json);
+    assert_eq!(frame.decode::<Message>().unwrap(), join("main"));
+}
+
+#[tokio::test]
+async fn tcp_split_frames() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let writer = tokio::spawn(async move {
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let payload = join("main").to_bytes();
+
+        // Write the frame one byte at a time
+        let mut bytes = (payload.len() as u32).to_be_bytes().to_vec();
+        bytes.push(0);
+        bytes.extend_from_slice(&payload);
+        for byte in bytes {
+            stream.write_all(&[byte]).await.unwrap();
+            stream.flush().await.unwrap();
+        }
+    });
+
+    let (stream, _) = listener.accept().await.unwrap();
+    let mut transport = TcpTransport::new(stream);
+
+    assert_eq!(transport.recv().await.unwrap().unwrap(), join("main"));
+    writer.await.unwrap();
+}
+
+#[tokio::test]
+async fn tcp_oversized_frame() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let writer = tokio::spawn(async move {
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let length = (MAX_FRAME_SIZE as u32 + 1).to_be_bytes();
+        stream.write_all(&length).await.unwrap();
+        stream.write_all(&[0]).await.unwrap();
+        stream
+    });
+
+    let (stream, _) = listener.accept().await.unwrap();
+    let mut transport = TcpTransport::new(stream);
+
+    assert!(transport.recv_frame().await.unwrap().is_err());
+    assert!(transport.recv_frame().await.is_none());
+    drop(writer.await.unwrap());
+}
+
+async fn ping(mut client: Box<dyn Transport>, mut server: Box<dyn Transport>) {
+    assert!(client.round_trip().is_none());
+
+    client.send_ping().await.unwrap();
+    client.send(&join("main"), Codec::Postcard).await.unwrap();
+
+    // The pong goes out along with the reply
+    assert_eq!(server.recv().await.unwrap().unwrap(), join("main"));
+    server.send(&join("reply"), Codec::Postcard).await.unwrap();
+
+    assert_eq!(client.recv().await.unwrap().unwrap(), join("reply"));
+    assert!(client.round_trip().is_some());
+    assert!(client.idle_time() < std::time::Duration::from_secs(1));
+}
+
+#[tokio::test]
+async fn tcp_ping() {
+    let (client, server) = loopback(TransportKind::Tcp).await;
+
+    ping(client, server).await;
+}
+
+#[tokio::test]
+async fn websocket_ping() {
+    let (client, server) = loopback(TransportKind::WebSocket).await;
+
+    ping(client, server).await;
+}
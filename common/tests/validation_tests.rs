@@ -0,0 +1,109 @@
+use common::error::ErrorCode;
+use common::validation::{name_key, Limits, MAX_PASSWORD_LENGTH};
+
+#[test]
+fn valid_names() {
+    let limits = Limits::default();
+
+    assert_eq!(limits.validate_name("alice"), Ok(String::from("alice")));
+    assert_eq!(limits.validate_name("Bob_2.0"), Ok(String::from("Bob_2.0")));
+    assert_eq!(limits.validate_name("zoë"), Ok(String::from("zoë")));
+}
+
+#[test]
+fn invalid_names() {
+    let limits = Limits::default();
+
+    assert_eq!(limits.validate_name(""), Err(ErrorCode::InvalidName));
+    assert_eq!(
+        limits.validate_name("two words"),
+        Err(ErrorCode::InvalidName)
+    );
+    assert_eq!(
+        limits.validate_name("tab\there"),
+        Err(ErrorCode::InvalidName)
+    );
+    assert_eq!(limits.validate_name("a,b"), Err(ErrorCode::InvalidName));
+    assert_eq!(
+        limits.validate_name(&"a".repeat(33)),
+        Err(ErrorCode::NameTooLong)
+    );
+}
+
+#[test]
+fn configured_limits() {
+    let limits = Limits {
+        max_name_length: 4,
+        max_message_length: 5,
+        name_symbols: String::from("#"),
+        min_password_length: 2,
+    };
+
+    assert!(limits.validate_name("#rs").is_ok());
+    assert_eq!(limits.validate_name("a-b"), Err(ErrorCode::InvalidName));
+    assert_eq!(limits.validate_name("abcde"), Err(ErrorCode::NameTooLong));
+
+    assert!(limits.validate_text("hello").is_ok());
+    assert_eq!(
+        limits.validate_text("hello!"),
+        Err(ErrorCode::MessageTooLong)
+    );
+
+    assert!(limits.validate_password("pw").is_ok());
+    assert_eq!(
+        limits.validate_password("p"),
+        Err(ErrorCode::InvalidPassword)
+    );
+}
+
+#[test]
+fn normalized_names() {
+    let limits = Limits::default();
+
+    // "e" followed by a combining acute accent is composed into "é"
+    let decomposed = "re\u{301}sume\u{301}";
+    assert_eq!(
+        limits.validate_name(decomposed),
+        Ok(String::from("r\u{e9}sum\u{e9}"))
+    );
+    assert_eq!(name_key(decomposed), name_key("R\u{c9}SUM\u{c9}"));
+}
+
+#[test]
+fn case_insensitive_keys() {
+    assert_eq!(name_key("Alice"), name_key("alice"));
+    assert_ne!(name_key("alice"), name_key("alicia"));
+}
+
+#[test]
+fn message_bodies() {
+    let limits = Limits::default();
+
+    assert!(limits.validate_text("hello there").is_ok());
+    assert!(limits.validate_text("two\nlines").is_ok());
+    assert_eq!(limits.validate_text(""), Err(ErrorCode::InvalidMessage));
+    assert_eq!(limits.validate_text("   "), Err(ErrorCode::InvalidMessage));
+    assert_eq!(
+        limits.validate_text("bell\u{7}"),
+        Err(ErrorCode::InvalidMessage)
+    );
+    assert_eq!(
+        limits.validate_text(&"x".repeat(2001)),
+        Err(ErrorCode::MessageTooLong)
+    );
+}
+
+#[test]
+fn passwords() {
+    let limits = Limits::default();
+
+    assert!(limits.validate_password("correct horse").is_ok());
+    assert_eq!(
+        limits.validate_password("short"),
+        Err(ErrorCode::InvalidPassword)
+    );
+    assert_eq!(
+        limits.validate_password(&"p".repeat(MAX_PASSWORD_LENGTH + 1)),
+        Err(ErrorCode::InvalidPassword)
+    );
+}